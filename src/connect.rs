@@ -0,0 +1,283 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `ConnectOptions` retries connection of a client with backoff.
+
+use std::collections::hash_map::RandomState;
+use std::ffi::{OsStr, OsString};
+use std::hash::{BuildHasher, Hasher};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{duration_deadline, PipeClient, PipeError};
+
+/// Options which can be used to configure how a `PipeClient` connects to a server.
+///
+/// Client makes an attempt to connect and, if all instances of the pipe are busy (or the pipe
+/// doesn't exist yet and `wait_for_pipe` is set), sleeps and tries again. Delay between attempts
+/// starts with `initial_backoff` and doubles up to `max_backoff`. With jitter enabled random
+/// delay between a half and the whole of it is used, so clients don't retry in lockstep.
+///
+/// Connection fails with the error of the last attempt once retries are exhausted or the
+/// deadline is reached.
+///
+/// Builder defaults:
+///
+/// - **retries** - unlimited
+/// - **backoff** - from 10ms up to 1s
+/// - **jitter** - true
+/// - **timeout**/**deadline** - none
+/// - **wait_for_pipe** - false
+///
+/// ```
+/// # use named_pipe::{ConnectOptions, PipeOptions};
+/// # use std::time::Duration;
+/// std::thread::spawn(|| {
+///     std::thread::sleep(Duration::from_millis(50));
+///     PipeOptions::new("mem://connect_options_doc")
+///         .single()
+///         .unwrap()
+///         .wait()
+///         .unwrap();
+/// });
+///
+/// let client = ConnectOptions::new("mem://connect_options_doc")
+///     .wait_for_pipe(true)
+///     .timeout(Duration::from_secs(5))
+///     .connect()
+///     .unwrap();
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct ConnectOptions {
+    name: OsString,
+    retries: Option<u32>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    wait_for_pipe: bool,
+}
+
+impl ConnectOptions {
+    pub fn new<T: AsRef<OsStr>>(name: T) -> ConnectOptions {
+        ConnectOptions {
+            name: name.as_ref().into(),
+            retries: None,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            jitter: true,
+            timeout: None,
+            deadline: None,
+            wait_for_pipe: false,
+        }
+    }
+
+    /// Number of attempts after the first one, `None` stands for unlimited. Defaults to `None`.
+    pub fn retries(&mut self, val: Option<u32>) -> &mut ConnectOptions {
+        self.retries = val;
+        self
+    }
+
+    /// Delay before the first retry and the limit it doubles up to. Defaults to 10ms and 1s.
+    pub fn backoff(&mut self, initial: Duration, max: Duration) -> &mut ConnectOptions {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Randomize delays between attempts. Defaults to `true`.
+    pub fn jitter(&mut self, val: bool) -> &mut ConnectOptions {
+        self.jitter = val;
+        self
+    }
+
+    /// Gives up `val` after `connect` is called. Defaults to none.
+    pub fn timeout(&mut self, val: Duration) -> &mut ConnectOptions {
+        self.timeout = Some(val);
+        self
+    }
+
+    /// Gives up at `val`. If `timeout` is set too, the earlier one applies. Defaults to none.
+    pub fn deadline(&mut self, val: Instant) -> &mut ConnectOptions {
+        self.deadline = Some(val);
+        self
+    }
+
+    /// Retry if the pipe doesn't exist (`ERROR_FILE_NOT_FOUND` or `ENOENT`), e.g. because the
+    /// server isn't started yet. Defaults to `false`.
+    pub fn wait_for_pipe(&mut self, val: bool) -> &mut ConnectOptions {
+        self.wait_for_pipe = val;
+        self
+    }
+
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// Connects to the pipe with this options.
+    pub fn connect(&self) -> Result<PipeClient, PipeError> {
+        self.connect_with(&mut Attempts::new(self))
+    }
+
+    /// Connects to the pipe counting retries in `attempts`.
+    pub(crate) fn connect_with(&self, attempts: &mut Attempts) -> Result<PipeClient, PipeError> {
+        loop {
+            let err = match PipeClient::open(&self.name, Some(Instant::now())) {
+                Ok(client) => return Ok(client),
+                Err(err @ PipeError::Busy(_)) => err,
+                Err(err @ PipeError::NotFound(_)) if self.wait_for_pipe => err,
+                Err(err) => return Err(err),
+            };
+            if !attempts.next() {
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Retries and backoff of `ConnectOptions` from the first attempt on.
+#[derive(Debug)]
+pub(crate) struct Attempts {
+    retries: Option<u32>,
+    max_backoff: Duration,
+    jitter: bool,
+    deadline: Option<Instant>,
+    backoff: Duration,
+    retried: u32,
+}
+
+impl Attempts {
+    pub(crate) fn new(options: &ConnectOptions) -> Attempts {
+        Attempts {
+            retries: options.retries,
+            max_backoff: options.max_backoff,
+            jitter: options.jitter,
+            deadline: match (options.deadline, duration_deadline(options.timeout)) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            backoff: options.initial_backoff,
+            retried: 0,
+        }
+    }
+
+    /// Sleeps before the next attempt. Returns `false` if retries are exhausted or the deadline
+    /// is reached.
+    pub(crate) fn next(&mut self) -> bool {
+        if self.retries.is_some_and(|max| self.retried >= max) {
+            return false;
+        }
+        let mut delay = self.delay();
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_millis(0) {
+                return false;
+            }
+            delay = delay.min(remaining);
+        }
+        thread::sleep(delay);
+        self.retried += 1;
+        self.backoff = (self.backoff * 2).min(self.max_backoff);
+        true
+    }
+
+    /// Returns `backoff` or, if jitter is enabled, random delay between its half and itself.
+    fn delay(&self) -> Duration {
+        if !self.jitter {
+            return self.backoff;
+        }
+        // Hasher keys are random, so it's a cheap source of randomness.
+        let random = RandomState::new().build_hasher().finish();
+        let half = self.backoff / 2;
+        half + half.mul_f64((random >> 11) as f64 / (1u64 << 53) as f64)
+    }
+}
+
+#[test]
+fn test_connect_options() {
+    use crate::PipeOptions;
+    use std::io::{Read, Write};
+
+    for &name in &[
+        r"\\.\pipe\test_connect_options",
+        "mem://test_memory_connect_options",
+    ] {
+        let err = ConnectOptions::new(name).connect().unwrap_err();
+        assert!(matches!(err, PipeError::NotFound(_)));
+
+        let start = Instant::now();
+        let err = ConnectOptions::new(name)
+            .wait_for_pipe(true)
+            .timeout(Duration::from_millis(50))
+            .connect()
+            .unwrap_err();
+        assert!(matches!(err, PipeError::NotFound(_)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let err = ConnectOptions::new(name)
+            .wait_for_pipe(true)
+            .retries(Some(3))
+            .backoff(Duration::from_millis(1), Duration::from_millis(1))
+            .connect()
+            .unwrap_err();
+        assert!(matches!(err, PipeError::NotFound(_)));
+
+        // Server is started late.
+        let server_name = name.to_string();
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let mut server = PipeOptions::new(server_name)
+                .single()
+                .unwrap()
+                .wait()
+                .unwrap();
+            server.write_all(b"late").unwrap();
+        });
+        let mut client = ConnectOptions::new(name)
+            .wait_for_pipe(true)
+            .timeout(Duration::from_secs(10))
+            .connect()
+            .unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"late");
+        server.join().unwrap();
+    }
+}
+
+#[test]
+fn test_connect_options_busy() {
+    use crate::PipeOptions;
+
+    for &name in &[
+        r"\\.\pipe\test_connect_options_busy",
+        "mem://test_memory_connect_options_busy",
+    ] {
+        let server = PipeOptions::new(name).single().unwrap();
+        let _client = PipeClient::connect(name).unwrap();
+        let err = ConnectOptions::new(name)
+            .retries(Some(2))
+            .jitter(false)
+            .connect()
+            .unwrap_err();
+        assert!(matches!(err, PipeError::Busy(_)));
+
+        // Instance freed between attempts is taken.
+        let second = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            PipeOptions::new(name).first(false).single()
+        });
+        let client = ConnectOptions::new(name)
+            .timeout(Duration::from_secs(10))
+            .connect();
+        let second = second.join().unwrap().unwrap();
+        client.unwrap();
+        drop((server, second));
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use crate::sys;
+
+/// Operation that failed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Operation {
+    /// Parsing of a pipe name.
+    Parse,
+    /// Creation of a pipe instance.
+    Create,
+    /// Server waiting for a client.
+    Accept,
+    /// Client connecting to a server.
+    Connect,
+    Disconnect,
+    /// Duplication of a pipe handle (`try_clone`).
+    Duplicate,
+    Read,
+    /// Looking at data without reading it (`peek`, `bytes_available`).
+    Peek,
+    Write,
+    Flush,
+    /// Waiting for multiple operations.
+    Wait,
+    Cancel,
+}
+
+impl Operation {
+    fn description(&self) -> &'static str {
+        match *self {
+            Operation::Parse => "parse name of",
+            Operation::Create => "create",
+            Operation::Accept => "accept a client on",
+            Operation::Connect => "connect to",
+            Operation::Disconnect => "disconnect",
+            Operation::Duplicate => "duplicate handle of",
+            Operation::Read => "read from",
+            Operation::Peek => "peek into",
+            Operation::Write => "write to",
+            Operation::Flush => "flush",
+            Operation::Wait => "wait on",
+            Operation::Cancel => "cancel operation on",
+        }
+    }
+}
+
+/// Pipe name, failed operation and the underlying error.
+#[derive(Debug)]
+pub struct ErrorContext {
+    name: Option<Arc<OsString>>,
+    operation: Operation,
+    source: io::Error,
+}
+
+impl ErrorContext {
+    /// Name of the pipe if it is known.
+    pub fn name(&self) -> Option<&OsStr> {
+        self.name.as_ref().map(|name| name.as_os_str())
+    }
+
+    pub fn operation(&self) -> Operation {
+        self.operation
+    }
+
+    /// Underlying error as returned by the OS (or by the emulation).
+    pub fn source(&self) -> &io::Error {
+        &self.source
+    }
+}
+
+/// Error of a pipe operation.
+///
+/// Functions of this crate that return `io::Error` (e.g. `io::Read` and `io::Write` impls)
+/// convert `PipeError` into it. Errors reported by the OS are converted with
+/// `io::Error::from_raw_os_error`, so `io::Error::raw_os_error` gives the OS error code, but the
+/// pipe name and the operation are lost. Other errors wrap `PipeError`, so it's available via
+/// `io::Error::get_ref`:
+///
+/// ```
+/// # use named_pipe::{PipeClient, PipeError};
+/// let err = PipeClient::connect("mem://no_such_pipe").unwrap_err();
+/// assert!(matches!(err, PipeError::NotFound(_)));
+///
+/// let err = std::io::Error::from(err);
+/// let err = err.get_ref().and_then(|err| err.downcast_ref::<PipeError>());
+/// assert!(matches!(err, Some(PipeError::NotFound(_))));
+/// ```
+#[derive(Debug)]
+pub enum PipeError {
+    /// All pipe instances are busy.
+    Busy(ErrorContext),
+    /// Other end of the pipe is closed or disconnected.
+    Disconnected(ErrorContext),
+    TimedOut(ErrorContext),
+    /// Pipe instance already exists (see `PipeOptions::first`).
+    AlreadyExists(ErrorContext),
+    NotFound(ErrorContext),
+    AccessDenied(ErrorContext),
+    InvalidName(ErrorContext),
+    /// Operation was cancelled.
+    Cancelled(ErrorContext),
+    /// Any other error.
+    Os(ErrorContext),
+}
+
+impl PipeError {
+    /// Classifies `source` error. If it already wraps a `PipeError`, only its context is updated.
+    pub(crate) fn new(
+        source: io::Error,
+        name: Option<Arc<OsString>>,
+        operation: Operation,
+    ) -> PipeError {
+        if source.get_ref().is_some_and(|err| err.is::<PipeError>()) {
+            let mut err = *source
+                .into_inner()
+                .unwrap()
+                .downcast::<PipeError>()
+                .unwrap();
+            let context = err.context_mut();
+            if name.is_some() {
+                context.name = name;
+            }
+            context.operation = operation;
+            return err;
+        }
+
+        let variant = if sys::is_cancelled(&source) {
+            PipeError::Cancelled
+        } else if sys::is_disconnected(&source) {
+            PipeError::Disconnected
+        } else if sys::is_busy(&source) {
+            PipeError::Busy
+        } else {
+            match source.kind() {
+                io::ErrorKind::ResourceBusy => PipeError::Busy,
+                io::ErrorKind::TimedOut => PipeError::TimedOut,
+                io::ErrorKind::AlreadyExists => PipeError::AlreadyExists,
+                io::ErrorKind::NotFound => PipeError::NotFound,
+                io::ErrorKind::PermissionDenied => PipeError::AccessDenied,
+                _ => PipeError::Os,
+            }
+        };
+        variant(ErrorContext {
+            name,
+            operation,
+            source,
+        })
+    }
+
+    pub(crate) fn invalid_name(name: &OsStr, reason: &str) -> PipeError {
+        PipeError::InvalidName(ErrorContext {
+            name: Some(Arc::new(name.into())),
+            operation: Operation::Parse,
+            source: io::Error::new(io::ErrorKind::InvalidInput, reason),
+        })
+    }
+
+    pub fn context(&self) -> &ErrorContext {
+        match *self {
+            PipeError::Busy(ref context)
+            | PipeError::Disconnected(ref context)
+            | PipeError::TimedOut(ref context)
+            | PipeError::AlreadyExists(ref context)
+            | PipeError::NotFound(ref context)
+            | PipeError::AccessDenied(ref context)
+            | PipeError::InvalidName(ref context)
+            | PipeError::Cancelled(ref context)
+            | PipeError::Os(ref context) => context,
+        }
+    }
+
+    fn context_mut(&mut self) -> &mut ErrorContext {
+        match *self {
+            PipeError::Busy(ref mut context)
+            | PipeError::Disconnected(ref mut context)
+            | PipeError::TimedOut(ref mut context)
+            | PipeError::AlreadyExists(ref mut context)
+            | PipeError::NotFound(ref mut context)
+            | PipeError::AccessDenied(ref mut context)
+            | PipeError::InvalidName(ref mut context)
+            | PipeError::Cancelled(ref mut context)
+            | PipeError::Os(ref mut context) => context,
+        }
+    }
+
+    /// Name of the pipe if it is known.
+    pub fn name(&self) -> Option<&OsStr> {
+        self.context().name()
+    }
+
+    pub fn operation(&self) -> Operation {
+        self.context().operation()
+    }
+
+    /// OS error code of the underlying error, if any.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.context().source().raw_os_error()
+    }
+
+    /// Kind of the `io::Error` this error is converted into. Errors reported by the OS have the
+    /// kind of their error code.
+    pub fn kind(&self) -> io::ErrorKind {
+        if self.raw_os_error().is_some() {
+            return self.context().source().kind();
+        }
+        match *self {
+            PipeError::Busy(_) => io::ErrorKind::ResourceBusy,
+            PipeError::Disconnected(_) => io::ErrorKind::BrokenPipe,
+            PipeError::TimedOut(_) => io::ErrorKind::TimedOut,
+            PipeError::AlreadyExists(_) => io::ErrorKind::AlreadyExists,
+            PipeError::NotFound(_) => io::ErrorKind::NotFound,
+            PipeError::AccessDenied(_) => io::ErrorKind::PermissionDenied,
+            PipeError::InvalidName(_) => io::ErrorKind::InvalidInput,
+            PipeError::Cancelled(_) => io::ErrorKind::Other,
+            PipeError::Os(ref context) => context.source.kind(),
+        }
+    }
+}
+
+impl fmt::Display for PipeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let context = self.context();
+        write!(f, "failed to {} pipe", context.operation.description())?;
+        if let Some(name) = context.name() {
+            write!(f, " {:?}", name)?;
+        }
+        write!(f, ": {}", context.source)
+    }
+}
+
+impl Error for PipeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.context().source)
+    }
+}
+
+/// Keeps the OS error code of errors reported by the OS at the cost of their context (pipe name
+/// and operation). Other errors are wrapped with their context.
+impl From<PipeError> for io::Error {
+    fn from(err: PipeError) -> io::Error {
+        match err.raw_os_error() {
+            Some(code) => io::Error::from_raw_os_error(code),
+            None => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+#[test]
+fn test_pipe_error() {
+    let source = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+    let name = Arc::new(OsString::from("mem://test_pipe_error"));
+    let err = PipeError::new(source, Some(name), Operation::Read);
+    assert!(matches!(err, PipeError::TimedOut(_)));
+    assert_eq!(err.name(), Some(OsStr::new("mem://test_pipe_error")));
+    assert_eq!(
+        err.to_string(),
+        r#"failed to read from pipe "mem://test_pipe_error": timed out"#
+    );
+
+    // Context of a wrapped error is updated.
+    let err = io::Error::from(err);
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    let err = PipeError::new(err, None, Operation::Write);
+    assert!(matches!(err, PipeError::TimedOut(_)));
+    assert_eq!(err.operation(), Operation::Write);
+    assert_eq!(err.name(), Some(OsStr::new("mem://test_pipe_error")));
+
+    // OS error code is kept (2 is ENOENT and ERROR_FILE_NOT_FOUND).
+    let err = PipeError::new(io::Error::from_raw_os_error(2), None, Operation::Read);
+    assert!(matches!(err, PipeError::NotFound(_)));
+    let err = io::Error::from(err);
+    assert_eq!(err.raw_os_error(), Some(2));
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
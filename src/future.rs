@@ -0,0 +1,255 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `Future` support.
+//!
+//! `ConnectingServer`, `ReadHandle` and `WriteHandle` implement `IntoFuture`, so they could be
+//! awaited in any async runtime. Task is woken once the operation is finished (by a background
+//! thread on Unix and by the system thread pool on Windows), so no runtime thread is blocked.
+//! Read and write timeouts are not applied to futures.
+//!
+//! Dropping a future of a pending read or write cancels the operation the same way dropping
+//! `ReadHandle` or `WriteHandle` does.
+
+use std::future::{Future, IntoFuture};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::{
+    sys, ConnectingServer, Operation, OwnedIoResult, PipeError, PipeIo, PipeServer, ReadHandle,
+    WriteHandle,
+};
+
+/// Registers the task of `cx` to be woken once the operation of `io` is finished.
+fn register(
+    io: &dyn PipeIo,
+    registration: &mut Option<sys::Registration>,
+    cx: &mut Context<'_>,
+) -> Result<(), PipeError> {
+    // Previous registration must be dropped first to not to wake outdated task.
+    *registration = None;
+    let handles = io.io_handles();
+    let new_registration =
+        sys::register_waker(handles.pipe_handle, handles.ovl, cx.waker().clone())
+            .map_err(|err| PipeError::new(err, Some(handles.name.clone()), Operation::Wait))?;
+    *registration = Some(new_registration);
+    Ok(())
+}
+
+/// Future of a pipe instance waiting for a client. Resolves to connected `PipeServer`.
+#[derive(Debug)]
+pub struct AcceptFuture {
+    // Must be dropped before the operation.
+    registration: Option<sys::Registration>,
+    server: Option<ConnectingServer>,
+}
+
+impl Future for AcceptFuture {
+    type Output = Result<PipeServer, PipeError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let server = this
+            .server
+            .as_mut()
+            .expect("AcceptFuture polled after completion");
+        match server.try_wait() {
+            Ok(false) => match register(server, &mut this.registration, cx) {
+                Ok(_) => Poll::Pending,
+                Err(err) => Poll::Ready(Err(err)),
+            },
+            Ok(true) => {
+                this.registration = None;
+                Poll::Ready(this.server.take().unwrap().wait())
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+impl IntoFuture for ConnectingServer {
+    type Output = Result<PipeServer, PipeError>;
+    type IntoFuture = AcceptFuture;
+
+    fn into_future(self) -> AcceptFuture {
+        AcceptFuture {
+            registration: None,
+            server: Some(self),
+        }
+    }
+}
+
+/// Future of a pending read. Resolves to the same value as `ReadHandle::wait`.
+#[derive(Debug)]
+pub struct ReadFuture<'a, T: PipeIo> {
+    // Must be dropped before the operation.
+    registration: Option<sys::Registration>,
+    handle: Option<ReadHandle<'a, T>>,
+}
+
+impl<'a, T: PipeIo + Unpin> Future for ReadFuture<'a, T> {
+    type Output = OwnedIoResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let handle = this
+            .handle
+            .as_mut()
+            .expect("ReadFuture polled after completion");
+        match handle.try_wait() {
+            Ok(None) => match register(handle, &mut this.registration, cx) {
+                Ok(_) => Poll::Pending,
+                Err(err) => Poll::Ready(Err(err)),
+            },
+            Ok(Some(_)) => {
+                this.registration = None;
+                Poll::Ready(this.handle.take().unwrap().wait())
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+impl<'a, T: PipeIo + Unpin> IntoFuture for ReadHandle<'a, T> {
+    type Output = OwnedIoResult<T>;
+    type IntoFuture = ReadFuture<'a, T>;
+
+    fn into_future(self) -> ReadFuture<'a, T> {
+        ReadFuture {
+            registration: None,
+            handle: Some(self),
+        }
+    }
+}
+
+/// Future of a pending write. Resolves to the same value as `WriteHandle::wait`.
+#[derive(Debug)]
+pub struct WriteFuture<'a, T: PipeIo> {
+    // Must be dropped before the operation.
+    registration: Option<sys::Registration>,
+    handle: Option<WriteHandle<'a, T>>,
+}
+
+impl<'a, T: PipeIo + Unpin> Future for WriteFuture<'a, T> {
+    type Output = OwnedIoResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let handle = this
+            .handle
+            .as_mut()
+            .expect("WriteFuture polled after completion");
+        match handle.try_wait() {
+            Ok(None) => match register(handle, &mut this.registration, cx) {
+                Ok(_) => Poll::Pending,
+                Err(err) => Poll::Ready(Err(err)),
+            },
+            Ok(Some(_)) => {
+                this.registration = None;
+                Poll::Ready(this.handle.take().unwrap().wait())
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+impl<'a, T: PipeIo + Unpin> IntoFuture for WriteHandle<'a, T> {
+    type Output = OwnedIoResult<T>;
+    type IntoFuture = WriteFuture<'a, T>;
+
+    fn into_future(self) -> WriteFuture<'a, T> {
+        WriteFuture {
+            registration: None,
+            handle: Some(self),
+        }
+    }
+}
+
+#[cfg(test)]
+fn block_on<F: Future>(future: F) -> F::Output {
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+    use std::thread::{self, Thread};
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+fn test_futures_on(name: &str) {
+    use crate::{PipeClient, PipeOptions};
+    use std::io::{Read, Write};
+    use std::task::Waker;
+    use std::thread;
+    use std::time::Duration;
+
+    let connecting = PipeOptions::new(name).single().unwrap();
+    let client_name = name.to_string();
+    let client = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        PipeClient::connect(client_name).unwrap()
+    });
+    let server = block_on(connecting.into_future()).unwrap();
+    let client = client.join().unwrap();
+
+    let mut read = client.read_async_owned(vec![0; 4]).unwrap().into_future();
+    let mut cx = Context::from_waker(Waker::noop());
+    assert!(Pin::new(&mut read).poll(&mut cx).is_pending());
+
+    let writer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        let write = server.write_async_owned(b"ping".to_vec()).unwrap();
+        block_on(write.into_future()).unwrap()
+    });
+    let (n, owned) = block_on(read).unwrap();
+    assert_eq!(n, 4);
+    let (client, buf) = owned.unwrap();
+    assert_eq!(buf, b"ping");
+    let (n, owned) = writer.join().unwrap();
+    assert_eq!(n, 4);
+    let mut server = owned.unwrap().0;
+
+    // Dropped future cancels the read.
+    let mut client = client;
+    let mut buf = [0; 4];
+    let mut read = unsafe { client.read_async(&mut buf).unwrap() }.into_future();
+    assert!(Pin::new(&mut read).poll(&mut cx).is_pending());
+    drop(read);
+
+    client.write_all(b"ping").unwrap();
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+    server.write_all(b"pong").unwrap();
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"pong");
+}
+
+#[test]
+fn test_futures() {
+    test_futures_on(r"\\.\pipe\test_futures");
+}
+
+#[test]
+fn test_memory_futures() {
+    test_futures_on("mem://test_memory_futures");
+}
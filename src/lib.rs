@@ -8,10 +8,16 @@
 
 //! Named-Pipe is a wrapper for overlapped (asyncronous) IO of Windows's named pipes.
 //!
+//! On Linux the same API is backed by Unix domain sockets, so code that depends on this crate
+//! builds and runs there too. Pipe names of the form `\\.\pipe\<name>` are mapped to a socket
+//! in `$XDG_RUNTIME_DIR` or in the temporary directory (see [`PipeName`](struct.PipeName.html)),
+//! any other name is treated as a socket path (a Linux-only extension, such names are invalid
+//! on Windows). Socket paths longer than 107 bytes are rejected.
+//!
 //! # Description
 //!
-//! You can use `wait` or `wait_all` to *select(2)*-like wait for multiple pending IO operations
-//! (which is read/write from/to `PipeServer`/`PipeClient` or waiting for new client).
+//! You can use `wait`, `wait_all` or `WaitSet` to *select(2)*-like wait for multiple pending IO
+//! operations (which is read/write from/to `PipeServer`/`PipeClient` or waiting for new client).
 //!
 //! Or you can use `ConnectingServer::wait` or `io::Read` and `io::Write` implementaions for
 //! `PipeServer` and `PipeClient` for syncronous communication.
@@ -22,117 +28,72 @@
 //! # Usage
 //!
 //! To create new pipe instance use [`PipeOptions`](struct.PipeOptions.html) structure.
+//! [`PipeListener`](struct.PipeListener.html) keeps instances waiting for clients and accepts
+//! them like `std::net::TcpListener`. Threads blocked on pipes could be woken with a
+//! [`ShutdownHandle`](struct.ShutdownHandle.html).
 //!
 //! To connect to a pipe server use [`PipeClient`](struct.PipeClient.html) structure.
-
-use winapi::{
-    ctypes::*,
-    shared::{minwindef::*, ntdef::HANDLE, winerror::*},
-    um::{
-        errhandlingapi::*, fileapi::*, handleapi::*, ioapiset::*, minwinbase::*, namedpipeapi::*,
-        synchapi::*, winbase::*, winnt::*,
-    },
-};
+//! [`ConnectOptions`](struct.ConnectOptions.html) retries connection with backoff, e.g. until
+//! the server is started, and [`ReconnectingClient`](struct.ReconnectingClient.html) reconnects
+//! after the server is restarted.
+//!
+//! Connected `PipeServer` and `PipeClient` could be split into
+//! [`PipeReadHalf`](struct.PipeReadHalf.html) and [`PipeWriteHalf`](struct.PipeWriteHalf.html) to
+//! read and write at the same time.
+//!
+//! Pipes named `mem://<name>` live in memory of the current process, see the
+//! [`memory`](memory/index.html) module.
+//!
+//! With `tokio` feature enabled the [`tokio`](tokio/index.html) module provides pipes that
+//! implement tokio's `AsyncRead` and `AsyncWrite`. With `mio` feature enabled `PipeServer`,
+//! `PipeClient` and `ConnectingServer` implement `mio::event::Source`.
+//!
+//! Failures are reported as [`PipeError`](enum.PipeError.html) which carries pipe name and the
+//! failed operation. `io::Read` and `io::Write` implementations convert it into `io::Error`
+//! keeping the OS error code, if any.
+//!
+//! Once the other side is closed or disconnected and everything it wrote is read, reads return
+//! end of stream: `io::Read` gives `Ok(0)` and `ReadHandle::wait` zero bytes read
+//! (`read_message` is the exception, it fails with `PipeError::Disconnected` to tell the end of
+//! stream from an empty message). Writes, including `WriteHandle::wait`, fail with
+//! `PipeError::Disconnected`. `is_connected` tells if the end of stream is reached without
+//! reading.
+
+mod connect;
+mod error;
+mod future;
+mod listener;
+pub mod memory;
+mod name;
+mod queue;
+mod reconnect;
+mod shutdown;
+#[cfg(feature = "mio")]
+mod source;
+mod split;
+mod sys;
+#[cfg(feature = "tokio")]
+pub mod tokio;
 
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::io;
-use std::marker::PhantomData;
-use std::mem;
-use std::os::windows::ffi::OsStrExt;
-use std::ptr;
-use std::sync::Arc;
-use std::time::Duration;
-
-#[derive(Debug)]
-struct Handle {
-    value: HANDLE,
-}
-
-impl Drop for Handle {
-    fn drop(&mut self) {
-        let _ = unsafe { CloseHandle(self.value) };
-    }
-}
-
-unsafe impl Sync for Handle {}
-unsafe impl Send for Handle {}
-
-#[derive(Debug)]
-struct Event {
-    handle: Handle,
-}
-
-impl Event {
-    fn new() -> io::Result<Event> {
-        let handle = unsafe { CreateEventW(ptr::null_mut(), 1, 0, ptr::null()) };
-        if handle != ptr::null_mut() {
-            Ok(Event {
-                handle: Handle { value: handle },
-            })
-        } else {
-            Err(io::Error::last_os_error())
-        }
-    }
-
-    fn reset(&self) -> io::Result<()> {
-        let result = unsafe { ResetEvent(self.handle.value) };
-        if result != 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
-        }
-    }
-
-    fn set(&self) -> io::Result<()> {
-        let result = unsafe { SetEvent(self.handle.value) };
-        if result != 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
-        }
-    }
-}
-
-struct Overlapped {
-    ovl: Box<OVERLAPPED>,
-    event: Event,
-}
-
-impl fmt::Debug for Overlapped {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Overlapped")
-            .field("ovl", &"OVERLAPPED")
-            .field("event", &self.event)
-            .finish()
-    }
-}
-
-unsafe impl Send for Overlapped {}
-unsafe impl Sync for Overlapped {}
-
-impl Overlapped {
-    fn new() -> io::Result<Overlapped> {
-        let event = Event::new()?;
-        let mut ovl: Box<OVERLAPPED> = Box::new(unsafe { mem::zeroed() });
-        ovl.hEvent = event.handle.value;
-        Ok(Overlapped {
-            ovl: ovl,
-            event: event,
-        })
-    }
-
-    fn clear(&mut self) -> io::Result<()> {
-        self.event.reset()?;
-        self.ovl = Box::new(unsafe { mem::zeroed() });
-        self.ovl.hEvent = self.event.handle.value;
-        Ok(())
-    }
-
-    fn get_mut(&mut self) -> &mut OVERLAPPED {
-        &mut self.ovl
-    }
-}
+use std::iter::FromIterator;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::sys::{Handle, Overlapped, INFINITE};
+
+pub use crate::connect::ConnectOptions;
+pub use crate::error::{ErrorContext, Operation, PipeError};
+pub use crate::future::{AcceptFuture, ReadFuture, WriteFuture};
+pub use crate::listener::{Incoming, PipeListener};
+pub use crate::name::PipeName;
+pub use crate::queue::{Completion, IoQueue, Pipe};
+pub use crate::reconnect::ReconnectingClient;
+pub use crate::shutdown::ShutdownHandle;
+pub use crate::split::{PipeReadHalf, PipeWriteHalf, ReuniteError, SplitPipe};
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum OpenMode {
@@ -144,14 +105,24 @@ pub enum OpenMode {
     Duplex,
 }
 
-impl OpenMode {
-    fn val(&self) -> u32 {
-        match self {
-            &OpenMode::Read => PIPE_ACCESS_INBOUND,
-            &OpenMode::Write => PIPE_ACCESS_OUTBOUND,
-            &OpenMode::Duplex => PIPE_ACCESS_DUPLEX,
-        }
-    }
+/// Type of a pipe. Client reads in the mode chosen by the server.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum PipeMode {
+    /// Data is a stream of bytes
+    Byte,
+    /// Data is a stream of messages, every write is a single message. On Unix it's emulated
+    /// with `SOCK_SEQPACKET` sockets where empty messages are indistinguishable from end of stream.
+    Message,
+}
+
+/// Result of a read from a message-mode pipe.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum MessageRead {
+    /// Rest of the message was read. Contains number of bytes read.
+    Complete(usize),
+    /// Buffer is too small, so rest of the message is left for the following reads. Contains
+    /// number of bytes read.
+    Partial(usize),
 }
 
 /// Options and flags which can be used to configure how a pipe is created.
@@ -161,56 +132,32 @@ impl OpenMode {
 /// Builder defaults:
 ///
 /// - **open_mode** - `Duplex`
+/// - **pipe_mode** - `Byte`
 /// - **in_buffer** - 65536
 /// - **out_buffer** - 65536
 /// - **first** - true
+/// - **shutdown_handle** - none
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub struct PipeOptions {
-    name: Arc<Vec<u16>>,
+    name: Arc<OsString>,
     open_mode: OpenMode,
+    pipe_mode: PipeMode,
     out_buffer: u32,
     in_buffer: u32,
     first: bool,
+    shutdown: Option<ShutdownHandle>,
 }
 
 impl PipeOptions {
-    fn create_named_pipe(&self, first: bool) -> io::Result<Handle> {
-        let handle = unsafe {
-            CreateNamedPipeW(
-                self.name.as_ptr(),
-                self.open_mode.val()
-                    | FILE_FLAG_OVERLAPPED
-                    | if first {
-                        FILE_FLAG_FIRST_PIPE_INSTANCE
-                    } else {
-                        0
-                    },
-                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT,
-                PIPE_UNLIMITED_INSTANCES,
-                self.out_buffer,
-                self.in_buffer,
-                0,
-                ptr::null_mut(),
-            )
-        };
-
-        if handle != INVALID_HANDLE_VALUE {
-            Ok(Handle { value: handle })
-        } else {
-            Err(io::Error::last_os_error())
-        }
-    }
-
     pub fn new<T: AsRef<OsStr>>(name: T) -> PipeOptions {
-        let mut full_name: OsString = name.as_ref().into();
-        full_name.push("\x00");
-        let full_name = full_name.encode_wide().collect::<Vec<u16>>();
         PipeOptions {
-            name: Arc::new(full_name),
+            name: Arc::new(name.as_ref().into()),
             open_mode: OpenMode::Duplex,
+            pipe_mode: PipeMode::Byte,
             out_buffer: 65536,
             in_buffer: 65536,
             first: true,
+            shutdown: None,
         }
     }
 
@@ -226,6 +173,12 @@ impl PipeOptions {
         self
     }
 
+    /// Pipe mode for pipe instance. Defaults to `Byte`.
+    pub fn pipe_mode(&mut self, val: PipeMode) -> &mut PipeOptions {
+        self.pipe_mode = val;
+        self
+    }
+
     /// Input buffer size for pipe instance. Defaults to 65536
     pub fn in_buffer(&mut self, val: u32) -> &mut PipeOptions {
         self.in_buffer = val;
@@ -238,8 +191,14 @@ impl PipeOptions {
         self
     }
 
+    /// Attaches `handle` to pipe instances, see [`ShutdownHandle`](struct.ShutdownHandle.html).
+    pub fn shutdown_handle(&mut self, handle: &ShutdownHandle) -> &mut PipeOptions {
+        self.shutdown = Some(handle.clone());
+        self
+    }
+
     /// Creates single instance of pipe with this options.
-    pub fn single(&self) -> io::Result<ConnectingServer> {
+    pub fn single(&self) -> Result<ConnectingServer, PipeError> {
         let mut pipes = self.multiple(1)?;
         match pipes.pop() {
             Some(pipe) => Ok(pipe),
@@ -248,21 +207,26 @@ impl PipeOptions {
     }
 
     /// Creates multiple instances of pipe with this options.
-    pub fn multiple(&self, num: u32) -> io::Result<Vec<ConnectingServer>> {
+    pub fn multiple(&self, num: u32) -> Result<Vec<ConnectingServer>, PipeError> {
         if num == 0 {
             return Ok(Vec::new());
         }
         let mut out = Vec::with_capacity(num as usize);
         let mut first = self.first;
         for _ in 0..num {
-            let handle = self.create_named_pipe(first)?;
+            let handle = sys::create_named_pipe(self, first)
+                .map_err(pipe_error(&self.name, Operation::Create))?;
             first = false;
-            let mut ovl = Overlapped::new()?;
-            let pending = connect_named_pipe(&handle, &mut ovl)?;
+            let mut ovl =
+                Overlapped::new(&handle).map_err(pipe_error(&self.name, Operation::Create))?;
+            let pending = sys::connect_named_pipe(&handle, &mut ovl)
+                .map_err(pipe_error(&self.name, Operation::Accept))?;
             out.push(ConnectingServer {
-                handle: handle,
-                ovl: ovl,
-                pending: pending,
+                name: self.name.clone(),
+                handle,
+                ovl,
+                pending,
+                shutdown: self.shutdown.clone(),
             });
         }
         Ok(out)
@@ -273,14 +237,16 @@ impl PipeOptions {
 /// (fn.wait_all.html) functions.
 #[derive(Debug)]
 pub struct ConnectingServer {
+    name: Arc<OsString>,
     handle: Handle,
     ovl: Overlapped,
     pending: bool,
+    shutdown: Option<ShutdownHandle>,
 }
 
 impl ConnectingServer {
     /// Waites for client infinitely.
-    pub fn wait(self) -> io::Result<PipeServer> {
+    pub fn wait(self) -> Result<PipeServer, PipeError> {
         match self.wait_ms(INFINITE)? {
             Ok(pipe_server) => Ok(pipe_server),
             Err(_) => unreachable!(),
@@ -288,63 +254,148 @@ impl ConnectingServer {
     }
 
     /// Waites for client. Note that `timeout` 0xFFFFFFFF stands for infinite waiting.
-    pub fn wait_ms(mut self, timeout: u32) -> io::Result<Result<PipeServer, ConnectingServer>> {
-        if self.pending {
-            match wait_for_single_obj(&mut self, timeout)? {
-                Some(_) => {
-                    let mut dummy = 0;
-                    get_ovl_result(&mut self, &mut dummy)?;
-                    self.pending = false;
-                }
-                None => return Ok(Err(self)),
-            }
+    pub fn wait_ms(
+        mut self,
+        timeout: u32,
+    ) -> Result<Result<PipeServer, ConnectingServer>, PipeError> {
+        let error = pipe_error(&self.name, Operation::Accept);
+        if !self.poll(timeout).map_err(&error)? {
+            return Ok(Err(self));
         }
         let ConnectingServer {
-            handle, mut ovl, ..
+            name,
+            handle,
+            mut ovl,
+            shutdown,
+            ..
         } = self;
-        ovl.clear()?;
+        let handle = sys::finish_connect(handle, &mut ovl).map_err(&error)?;
+        ovl.clear().map_err(&error)?;
         Ok(Ok(PipeServer {
+            name,
             handle: Some(handle),
             ovl: Some(ovl),
             read_timeout: None,
             write_timeout: None,
+            shutdown,
+            clones: Arc::new(AtomicUsize::new(1)),
         }))
     }
+
+    /// Waits for client for `timeout` or infinitely if it's `None`. Returns back
+    /// `ConnectingServer` on timeout.
+    pub fn wait_timeout(
+        self,
+        timeout: Option<Duration>,
+    ) -> Result<Result<PipeServer, ConnectingServer>, PipeError> {
+        match duration_deadline(timeout) {
+            Some(deadline) => self.wait_deadline(deadline),
+            None => self.wait_ms(INFINITE),
+        }
+    }
+
+    /// Waits for client until `deadline`. Returns back `ConnectingServer` on timeout.
+    pub fn wait_deadline(
+        mut self,
+        deadline: Instant,
+    ) -> Result<Result<PipeServer, ConnectingServer>, PipeError> {
+        loop {
+            // Waits longer than `u32` milliseconds are split.
+            let timeout = sys::remaining_ms(deadline);
+            match self.wait_ms(timeout)? {
+                Err(server) if timeout != 0 => self = server,
+                result => return Ok(result),
+            }
+        }
+    }
+
+    /// Checks whether a client is connected without blocking. If it returns `true`, then `wait`
+    /// will return `PipeServer` immediately.
+    pub fn try_wait(&mut self) -> Result<bool, PipeError> {
+        self.poll(0)
+            .map_err(pipe_error(&self.name, Operation::Accept))
+    }
+
+    fn poll(&mut self, timeout: u32) -> io::Result<bool> {
+        if self.pending {
+            if wait_for_single_obj(self, timeout)? {
+                get_ovl_result(self)?;
+                self.pending = false;
+            } else {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
 }
 
 /// Pipe server connected to a client.
 #[derive(Debug)]
 pub struct PipeServer {
+    name: Arc<OsString>,
     handle: Option<Handle>,
     ovl: Option<Overlapped>,
     read_timeout: Option<u32>,
     write_timeout: Option<u32>,
+    shutdown: Option<ShutdownHandle>,
+    /// Number of clones, so only the last one disconnects the client.
+    clones: Arc<AtomicUsize>,
 }
 
 impl PipeServer {
     /// This function will flush buffers and disconnect server from client. Then will start waiting
     /// for a new client.
-    pub fn disconnect(mut self) -> io::Result<ConnectingServer> {
+    ///
+    /// Fails if other clones of the server (see `try_clone`) are alive.
+    pub fn disconnect(mut self) -> Result<ConnectingServer, PipeError> {
+        let error = pipe_error(&self.name, Operation::Disconnect);
+        if self
+            .clones
+            .compare_exchange(1, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(error(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pipe is used by its clones",
+            )));
+        }
         let handle = self.handle.take().unwrap();
         let mut ovl = self.ovl.take().unwrap();
-        let mut result = unsafe { FlushFileBuffers(handle.value) };
-
-        if result != 0 {
-            result = unsafe { DisconnectNamedPipe(handle.value) };
-            if result != 0 {
-                ovl.clear()?;
-                let pending = connect_named_pipe(&handle, &mut ovl)?;
-                Ok(ConnectingServer {
-                    handle: handle,
-                    ovl: ovl,
-                    pending: pending,
-                })
-            } else {
-                Err(io::Error::last_os_error())
-            }
-        } else {
-            Err(io::Error::last_os_error())
-        }
+        sys::flush_file_buffers(&handle).map_err(&error)?;
+        sys::disconnect_named_pipe(&handle).map_err(&error)?;
+        let handle = sys::listening_handle(handle);
+        ovl.clear().map_err(&error)?;
+        let pending = sys::connect_named_pipe(&handle, &mut ovl)
+            .map_err(pipe_error(&self.name, Operation::Accept))?;
+        Ok(ConnectingServer {
+            name: self.name.clone(),
+            handle,
+            ovl,
+            pending,
+            shutdown: self.shutdown.take(),
+        })
+    }
+
+    /// Creates a new handle to the same connection, like `TcpStream::try_clone`. Clone has its
+    /// own pending operation state, so one clone could read while another one writes. Timeouts
+    /// and the shutdown handle are copied.
+    ///
+    /// Client is disconnected when the last clone is dropped. `disconnect` fails while there are
+    /// other clones.
+    pub fn try_clone(&self) -> Result<PipeServer, PipeError> {
+        let error = pipe_error(&self.name, Operation::Duplicate);
+        let handle = sys::duplicate_handle(self.handle.as_ref().unwrap()).map_err(&error)?;
+        let ovl = Overlapped::new(&handle).map_err(&error)?;
+        self.clones.fetch_add(1, Ordering::Relaxed);
+        Ok(PipeServer {
+            name: self.name.clone(),
+            handle: Some(handle),
+            ovl: Some(ovl),
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            shutdown: self.shutdown.clone(),
+            clones: self.clones.clone(),
+        })
     }
 
     /// Initializes asyncronous read opeation.
@@ -360,8 +411,64 @@ impl PipeServer {
     }
 
     /// Initializes asyncronous read operation and takes ownership of buffer and server.
-    pub fn read_async_owned(self, buf: Vec<u8>) -> io::Result<ReadHandle<'static, Self>> {
-        init_read_owned(self, buf)
+    pub fn read_async_owned(self, buf: Vec<u8>) -> Result<ReadHandle<'static, Self>, PipeError> {
+        let error = pipe_error(&self.name, Operation::Read);
+        init_read_owned(self, buf).map_err(error)
+    }
+
+    /// Reads from a message-mode pipe, respecting read timeout.
+    ///
+    /// Returns `MessageRead::Partial` if `buf` is too small to hold the rest of the current
+    /// message, the following reads will return the rest. Reads from byte-mode pipes are always
+    /// `Complete`. Returns `ErrorKind::BrokenPipe` error if the other side is closed.
+    pub fn read_message_part(&mut self, buf: &mut [u8]) -> Result<MessageRead, PipeError> {
+        read_message_part(self, buf).map_err(pipe_error(&self.name, Operation::Read))
+    }
+
+    /// Reads whole message from a message-mode pipe. See `read_message_part`.
+    pub fn read_message(&mut self) -> Result<Vec<u8>, PipeError> {
+        read_message(self).map_err(pipe_error(&self.name, Operation::Read))
+    }
+
+    /// Copies data from the pipe into `buf` without removing it, returns number of bytes copied.
+    ///
+    /// Doesn't wait for data, so returns `Ok(0)` if the pipe is empty. Message-mode pipes are
+    /// peeked into the current message only. Returns `ErrorKind::BrokenPipe` error if the pipe is
+    /// empty and the other side is closed.
+    pub fn peek(&self, buf: &mut [u8]) -> Result<usize, PipeError> {
+        self.peek_info(buf).map(|info| info.bytes_read as usize)
+    }
+
+    /// Number of bytes that can be read without waiting. See `peek`.
+    pub fn bytes_available(&self) -> Result<usize, PipeError> {
+        self.peek_info(&mut [])
+            .map(|info| info.bytes_available as usize)
+    }
+
+    /// Number of bytes left in the current message of a message-mode pipe, i.e. the size of the
+    /// message `read_message` would return without waiting. Always `0` for byte-mode pipes. See
+    /// `peek`.
+    pub fn message_bytes_left(&self) -> Result<usize, PipeError> {
+        self.peek_info(&mut [])
+            .map(|info| info.message_bytes_left as usize)
+    }
+
+    /// Returns `false` once reads return end of stream, i.e. if the other side is closed or
+    /// disconnected and everything it wrote is read. Doesn't wait for data. See `peek`.
+    pub fn is_connected(&self) -> Result<bool, PipeError> {
+        match self.peek_info(&mut []) {
+            Ok(_) => Ok(true),
+            Err(PipeError::Disconnected(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn peek_info(&self, buf: &mut [u8]) -> Result<sys::PeekInfo, PipeError> {
+        match (&self.handle, &self.ovl) {
+            (Some(handle), Some(ovl)) => sys::peek_named_pipe(handle, ovl, buf)
+                .map_err(pipe_error(&self.name, Operation::Peek)),
+            _ => unreachable!(),
+        }
     }
 
     /// Initializes asyncronous write operation.
@@ -377,8 +484,19 @@ impl PipeServer {
     }
 
     /// Initializes asyncronous write operation and takes ownership of buffer and server.
-    pub fn write_async_owned(self, buf: Vec<u8>) -> io::Result<WriteHandle<'static, Self>> {
-        init_write_owned(self, buf)
+    pub fn write_async_owned(self, buf: Vec<u8>) -> Result<WriteHandle<'static, Self>, PipeError> {
+        let error = pipe_error(&self.name, Operation::Write);
+        init_write_owned(self, buf).map_err(error)
+    }
+
+    /// Initializes asyncronous write of `bufs` gathered into a single buffer (given back by
+    /// `wait`) and takes ownership of server.
+    pub fn write_vectored_async_owned(
+        self,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Result<WriteHandle<'static, Self>, PipeError> {
+        let error = pipe_error(&self.name, Operation::Write);
+        init_write_owned(self, gather(bufs)).map_err(error)
     }
 
     /// Allows you to set read timeout in milliseconds.
@@ -419,62 +537,53 @@ impl PipeServer {
 
     pub fn get_read_timeout(&self) -> Option<Duration> {
         self.read_timeout
-            .clone()
             .map(|millis| Duration::from_millis(millis as u64))
     }
 
     pub fn get_write_timeout(&self) -> Option<Duration> {
         self.write_timeout
-            .clone()
             .map(|millis| Duration::from_millis(millis as u64))
     }
 
     fn get_read_timeout_ms(&self) -> Option<u32> {
-        self.read_timeout.clone()
+        self.read_timeout
     }
 
     fn get_write_timeout_ms(&self) -> Option<u32> {
-        self.write_timeout.clone()
+        self.write_timeout
     }
 }
 
 impl io::Read for PipeServer {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let read_handle = unsafe { self.read_async(buf) };
-        let result = read_handle
-            .and_then(|read_handle| read_handle.wait())
-            .map(|x| x.0);
-        match result {
-            Ok(x) => Ok(x),
-            Err(err) => {
-                if err.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) {
-                    Ok(0)
-                } else {
-                    Err(err)
-                }
-            }
-        }
+        wait_read(read_handle).map_err(|err| pipe_error(&self.name, Operation::Read)(err).into())
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        read_vectored(self, bufs).map_err(|err| pipe_error(&self.name, Operation::Read)(err).into())
     }
 }
 
 impl io::Write for PipeServer {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let error = pipe_error(&self.name, Operation::Write);
         let write_handle = unsafe { self.write_async(buf) };
-        write_handle
-            .and_then(|write_handle| write_handle.wait())
-            .map(|x| x.0)
+        let result = write_handle
+            .map_err(error)
+            .and_then(|write_handle| write_handle.wait());
+        Ok(result?.0)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        write_vectored(self, bufs)
+            .map_err(|err| pipe_error(&self.name, Operation::Write)(err).into())
     }
 
     fn flush(&mut self) -> io::Result<()> {
         match self.handle {
-            Some(ref handle) => {
-                let result = unsafe { FlushFileBuffers(handle.value) };
-                if result != 0 {
-                    Ok(())
-                } else {
-                    Err(io::Error::last_os_error())
-                }
-            }
+            Some(ref handle) => sys::flush_file_buffers(handle)
+                .map_err(|err| pipe_error(&self.name, Operation::Flush)(err).into()),
             None => unreachable!(),
         }
     }
@@ -482,9 +591,13 @@ impl io::Write for PipeServer {
 
 impl Drop for PipeServer {
     fn drop(&mut self) {
+        // Handle is taken by `disconnect`.
         if let Some(ref handle) = self.handle {
-            let _ = unsafe { FlushFileBuffers(handle.value) };
-            let _ = unsafe { DisconnectNamedPipe(handle.value) };
+            if self.clones.fetch_sub(1, Ordering::AcqRel) > 1 {
+                return;
+            }
+            let _ = sys::flush_file_buffers(handle);
+            let _ = sys::disconnect_named_pipe(handle);
         }
     }
 }
@@ -492,105 +605,80 @@ impl Drop for PipeServer {
 /// Pipe client connected to a server.
 #[derive(Debug)]
 pub struct PipeClient {
+    name: Arc<OsString>,
     handle: Handle,
     ovl: Overlapped,
     read_timeout: Option<u32>,
     write_timeout: Option<u32>,
+    shutdown: Option<ShutdownHandle>,
 }
 
 impl PipeClient {
-    fn create_file(name: &Vec<u16>, mode: DWORD) -> io::Result<Handle> {
-        loop {
-            let handle = unsafe {
-                CreateFileW(
-                    name.as_ptr(),
-                    mode,
-                    0,
-                    ptr::null_mut(),
-                    OPEN_EXISTING,
-                    FILE_FLAG_OVERLAPPED,
-                    ptr::null_mut(),
-                )
-            };
-
-            if handle != INVALID_HANDLE_VALUE {
-                return Ok(Handle { value: handle });
-            }
-
-            match unsafe { GetLastError() } {
-                ERROR_PIPE_BUSY => {
-                    unsafe { WaitNamedPipeW(name.as_ptr(), 0) };
-                }
-                ERROR_ACCESS_DENIED => match mode {
-                    mode if mode == (GENERIC_READ | GENERIC_WRITE) => {
-                        return PipeClient::create_file(name, GENERIC_READ | FILE_WRITE_ATTRIBUTES);
-                    }
-                    mode if mode == (GENERIC_READ | FILE_WRITE_ATTRIBUTES) => {
-                        return PipeClient::create_file(name, GENERIC_WRITE | FILE_READ_ATTRIBUTES);
-                    }
-                    _ => {
-                        return Err(io::Error::last_os_error());
-                    }
-                },
-                _ => {
-                    return Err(io::Error::last_os_error());
-                }
-            }
-        }
-    }
-
     /// Will wait for server infinitely.
-    pub fn connect<T: AsRef<OsStr>>(name: T) -> io::Result<PipeClient> {
-        PipeClient::connect_ms(name, 0xFFFFFFFF)
+    pub fn connect<T: AsRef<OsStr>>(name: T) -> Result<PipeClient, PipeError> {
+        PipeClient::connect_ms(name, INFINITE)
     }
 
     /// Will wait for server. Note that `timeout` 0xFFFFFFFF stands for infinite waiting.
-    pub fn connect_ms<T: AsRef<OsStr>>(name: T, timeout: u32) -> io::Result<PipeClient> {
-        let mut full_name: OsString = name.as_ref().into();
-        full_name.push("\x00");
-        let full_name = full_name.encode_wide().collect::<Vec<u16>>();
-        let mut waited = false;
-        loop {
-            match PipeClient::create_file(&full_name, GENERIC_READ | GENERIC_WRITE) {
-                Ok(handle) => {
-                    let result = unsafe {
-                        let mut mode = PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT;
-                        SetNamedPipeHandleState(
-                            handle.value,
-                            &mut mode,
-                            ptr::null_mut(),
-                            ptr::null_mut(),
-                        )
-                    };
-
-                    if result != 0 {
-                        return Ok(PipeClient {
-                            handle: handle,
-                            ovl: Overlapped::new()?,
-                            read_timeout: None,
-                            write_timeout: None,
-                        });
-                    } else {
-                        return Err(io::Error::last_os_error());
-                    }
-                }
-                Err(err) => {
-                    if err.raw_os_error().unwrap() == ERROR_PIPE_BUSY as i32 {
-                        if !waited {
-                            waited = true;
-                            let result = unsafe { WaitNamedPipeW(full_name.as_ptr(), timeout) };
-                            if result == 0 {
-                                return Err(err);
-                            }
-                        } else {
-                            return Err(err);
-                        }
-                    } else {
-                        return Err(err);
-                    }
-                }
-            }
-        }
+    pub fn connect_ms<T: AsRef<OsStr>>(name: T, timeout: u32) -> Result<PipeClient, PipeError> {
+        let deadline = match timeout {
+            INFINITE => None,
+            timeout => Some(Instant::now() + Duration::from_millis(timeout as u64)),
+        };
+        PipeClient::open(name.as_ref(), deadline)
+    }
+
+    /// Will wait for server for `timeout` or infinitely if it's `None`. Time spent waiting for
+    /// a busy pipe is counted too.
+    pub fn connect_timeout<T: AsRef<OsStr>>(
+        name: T,
+        timeout: Option<Duration>,
+    ) -> Result<PipeClient, PipeError> {
+        PipeClient::open(name.as_ref(), duration_deadline(timeout))
+    }
+
+    /// Will wait for server until `deadline`.
+    pub fn connect_deadline<T: AsRef<OsStr>>(
+        name: T,
+        deadline: Instant,
+    ) -> Result<PipeClient, PipeError> {
+        PipeClient::open(name.as_ref(), Some(deadline))
+    }
+
+    fn open(name: &OsStr, deadline: Option<Instant>) -> Result<PipeClient, PipeError> {
+        let name = Arc::new(name.into());
+        let error = pipe_error(&name, Operation::Connect);
+        let handle = sys::open_pipe(&name, deadline).map_err(&error)?;
+        Ok(PipeClient {
+            ovl: Overlapped::new(&handle).map_err(&error)?,
+            name,
+            handle,
+            read_timeout: None,
+            write_timeout: None,
+            shutdown: None,
+        })
+    }
+
+    /// Attaches `handle` to the client or detaches it if `None`, see
+    /// [`ShutdownHandle`](struct.ShutdownHandle.html).
+    pub fn set_shutdown_handle(&mut self, handle: Option<ShutdownHandle>) {
+        self.shutdown = handle;
+    }
+
+    /// Creates a new handle to the same connection, like `TcpStream::try_clone`. Clone has its
+    /// own pending operation state, so one clone could read while another one writes. Timeouts
+    /// and the shutdown handle are copied.
+    pub fn try_clone(&self) -> Result<PipeClient, PipeError> {
+        let error = pipe_error(&self.name, Operation::Duplicate);
+        let handle = sys::duplicate_handle(&self.handle).map_err(&error)?;
+        Ok(PipeClient {
+            name: self.name.clone(),
+            ovl: Overlapped::new(&handle).map_err(&error)?,
+            handle,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            shutdown: self.shutdown.clone(),
+        })
     }
 
     /// Initializes asyncronous read operation.
@@ -606,8 +694,61 @@ impl PipeClient {
     }
 
     /// Initializes asyncronous read operation and takes ownership of buffer and client.
-    pub fn read_async_owned(self, buf: Vec<u8>) -> io::Result<ReadHandle<'static, Self>> {
-        init_read_owned(self, buf)
+    pub fn read_async_owned(self, buf: Vec<u8>) -> Result<ReadHandle<'static, Self>, PipeError> {
+        let error = pipe_error(&self.name, Operation::Read);
+        init_read_owned(self, buf).map_err(error)
+    }
+
+    /// Reads from a message-mode pipe, respecting read timeout.
+    ///
+    /// Returns `MessageRead::Partial` if `buf` is too small to hold the rest of the current
+    /// message, the following reads will return the rest. Reads from byte-mode pipes are always
+    /// `Complete`. Returns `ErrorKind::BrokenPipe` error if the other side is closed.
+    pub fn read_message_part(&mut self, buf: &mut [u8]) -> Result<MessageRead, PipeError> {
+        read_message_part(self, buf).map_err(pipe_error(&self.name, Operation::Read))
+    }
+
+    /// Reads whole message from a message-mode pipe. See `read_message_part`.
+    pub fn read_message(&mut self) -> Result<Vec<u8>, PipeError> {
+        read_message(self).map_err(pipe_error(&self.name, Operation::Read))
+    }
+
+    /// Copies data from the pipe into `buf` without removing it, returns number of bytes copied.
+    ///
+    /// Doesn't wait for data, so returns `Ok(0)` if the pipe is empty. Message-mode pipes are
+    /// peeked into the current message only. Returns `ErrorKind::BrokenPipe` error if the pipe is
+    /// empty and the other side is closed.
+    pub fn peek(&self, buf: &mut [u8]) -> Result<usize, PipeError> {
+        self.peek_info(buf).map(|info| info.bytes_read as usize)
+    }
+
+    /// Number of bytes that can be read without waiting. See `peek`.
+    pub fn bytes_available(&self) -> Result<usize, PipeError> {
+        self.peek_info(&mut [])
+            .map(|info| info.bytes_available as usize)
+    }
+
+    /// Number of bytes left in the current message of a message-mode pipe, i.e. the size of the
+    /// message `read_message` would return without waiting. Always `0` for byte-mode pipes. See
+    /// `peek`.
+    pub fn message_bytes_left(&self) -> Result<usize, PipeError> {
+        self.peek_info(&mut [])
+            .map(|info| info.message_bytes_left as usize)
+    }
+
+    /// Returns `false` once reads return end of stream, i.e. if the other side is closed or
+    /// disconnected and everything it wrote is read. Doesn't wait for data. See `peek`.
+    pub fn is_connected(&self) -> Result<bool, PipeError> {
+        match self.peek_info(&mut []) {
+            Ok(_) => Ok(true),
+            Err(PipeError::Disconnected(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn peek_info(&self, buf: &mut [u8]) -> Result<sys::PeekInfo, PipeError> {
+        sys::peek_named_pipe(&self.handle, &self.ovl, buf)
+            .map_err(pipe_error(&self.name, Operation::Peek))
     }
 
     /// Initializes asyncronous write operation.
@@ -623,8 +764,19 @@ impl PipeClient {
     }
 
     /// Initializes asyncronous write operation and takes ownership of buffer and client.
-    pub fn write_async_owned(self, buf: Vec<u8>) -> io::Result<WriteHandle<'static, Self>> {
-        init_write_owned(self, buf)
+    pub fn write_async_owned(self, buf: Vec<u8>) -> Result<WriteHandle<'static, Self>, PipeError> {
+        let error = pipe_error(&self.name, Operation::Write);
+        init_write_owned(self, buf).map_err(error)
+    }
+
+    /// Initializes asyncronous write of `bufs` gathered into a single buffer (given back by
+    /// `wait`) and takes ownership of client.
+    pub fn write_vectored_async_owned(
+        self,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Result<WriteHandle<'static, Self>, PipeError> {
+        let error = pipe_error(&self.name, Operation::Write);
+        init_write_owned(self, gather(bufs)).map_err(error)
     }
 
     /// Allows you to set read timeout in milliseconds.
@@ -665,22 +817,20 @@ impl PipeClient {
 
     pub fn get_read_timeout(&self) -> Option<Duration> {
         self.read_timeout
-            .clone()
             .map(|millis| Duration::from_millis(millis as u64))
     }
 
     pub fn get_write_timeout(&self) -> Option<Duration> {
         self.write_timeout
-            .clone()
             .map(|millis| Duration::from_millis(millis as u64))
     }
 
     fn get_read_timeout_ms(&self) -> Option<u32> {
-        self.read_timeout.clone()
+        self.read_timeout
     }
 
     fn get_write_timeout_ms(&self) -> Option<u32> {
-        self.write_timeout.clone()
+        self.write_timeout
     }
 }
 
@@ -689,42 +839,48 @@ unsafe impl Send for PipeClient {}
 impl io::Read for PipeClient {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let read_handle = unsafe { self.read_async(buf) };
-        read_handle
-            .and_then(|read_handle| read_handle.wait())
-            .map(|x| x.0)
+        wait_read(read_handle).map_err(|err| pipe_error(&self.name, Operation::Read)(err).into())
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        read_vectored(self, bufs).map_err(|err| pipe_error(&self.name, Operation::Read)(err).into())
     }
 }
 
 impl io::Write for PipeClient {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let error = pipe_error(&self.name, Operation::Write);
         let write_handle = unsafe { self.write_async(buf) };
-        write_handle
-            .and_then(|write_handle| write_handle.wait())
-            .map(|x| x.0)
+        let result = write_handle
+            .map_err(error)
+            .and_then(|write_handle| write_handle.wait());
+        Ok(result?.0)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        write_vectored(self, bufs)
+            .map_err(|err| pipe_error(&self.name, Operation::Write)(err).into())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        let result = unsafe { FlushFileBuffers(self.handle.value) };
-        if result != 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
-        }
+        sys::flush_file_buffers(&self.handle)
+            .map_err(|err| pipe_error(&self.name, Operation::Flush)(err).into())
     }
 }
 
 #[derive(Debug)]
 pub struct PipeIoObj<'a> {
-    handle: HANDLE,
+    handle: sys::RawHandle,
     ovl: &'a mut Overlapped,
+    shutdown: Option<&'a sys::Shutdown>,
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
 pub struct PipeIoHandles<'a> {
-    pipe_handle: HANDLE,
-    event_handle: HANDLE,
-    _phantom: PhantomData<&'a ()>,
+    name: &'a Arc<OsString>,
+    pipe_handle: sys::RawHandle,
+    ovl: &'a Overlapped,
+    shutdown: Option<&'a sys::Shutdown>,
 }
 
 /// This trait used for genericity.
@@ -738,7 +894,7 @@ pub trait PipeIo {
 impl PipeIo for PipeServer {
     fn io_obj<'a>(&'a mut self) -> PipeIoObj<'a> {
         let raw_handle = match self.handle {
-            Some(ref handle) => handle.value,
+            Some(ref handle) => handle.raw(),
             None => unreachable!(),
         };
         let ovl = match self.ovl {
@@ -747,23 +903,25 @@ impl PipeIo for PipeServer {
         };
         PipeIoObj {
             handle: raw_handle,
-            ovl: ovl,
+            ovl,
+            shutdown: self.shutdown.as_ref().map(ShutdownHandle::inner),
         }
     }
 
     fn io_handles<'a>(&'a self) -> PipeIoHandles<'a> {
         let pipe_handle = match self.handle {
-            Some(ref handle) => handle.value,
+            Some(ref handle) => handle.raw(),
             None => unreachable!(),
         };
-        let event_handle = match self.ovl {
-            Some(ref ovl) => ovl.ovl.hEvent,
+        let ovl = match self.ovl {
+            Some(ref ovl) => ovl,
             None => unreachable!(),
         };
         PipeIoHandles {
-            pipe_handle: pipe_handle,
-            event_handle: event_handle,
-            _phantom: PhantomData,
+            name: &self.name,
+            pipe_handle,
+            ovl,
+            shutdown: self.shutdown.as_ref().map(ShutdownHandle::inner),
         }
     }
 
@@ -779,16 +937,18 @@ impl PipeIo for PipeServer {
 impl PipeIo for PipeClient {
     fn io_obj<'a>(&'a mut self) -> PipeIoObj<'a> {
         PipeIoObj {
-            handle: self.handle.value,
+            handle: self.handle.raw(),
             ovl: &mut self.ovl,
+            shutdown: self.shutdown.as_ref().map(ShutdownHandle::inner),
         }
     }
 
     fn io_handles<'a>(&'a self) -> PipeIoHandles<'a> {
         PipeIoHandles {
-            pipe_handle: self.handle.value,
-            event_handle: self.ovl.ovl.hEvent,
-            _phantom: PhantomData,
+            name: &self.name,
+            pipe_handle: self.handle.raw(),
+            ovl: &self.ovl,
+            shutdown: self.shutdown.as_ref().map(ShutdownHandle::inner),
         }
     }
 
@@ -803,117 +963,55 @@ impl PipeIo for PipeClient {
 
 impl<'a, T: PipeIo> PipeIo for ReadHandle<'a, T> {
     fn io_obj<'b>(&'b mut self) -> PipeIoObj<'b> {
-        match self.io {
-            Some(ref mut io) => return io.io_obj(),
-            _ => (),
-        }
-        match self.io_ref {
-            Some(ref mut io) => return io.io_obj(),
-            _ => (),
-        }
-        unreachable!();
+        self.io_mut().io_obj()
     }
 
     fn io_handles<'b>(&'b self) -> PipeIoHandles<'b> {
-        match self.io {
-            Some(ref io) => return io.io_handles(),
-            _ => (),
-        }
-        match self.io_ref {
-            Some(ref io) => return io.io_handles(),
-            _ => (),
-        }
-        unreachable!();
+        self.io().io_handles()
     }
 
     fn get_read_timeout(&self) -> Option<u32> {
-        match self.io {
-            Some(ref io) => return io.get_read_timeout(),
-            _ => (),
-        }
-        match self.io_ref {
-            Some(ref io) => return io.get_read_timeout(),
-            _ => (),
-        }
-        unreachable!();
+        self.io().get_read_timeout()
     }
 
     fn get_write_timeout(&self) -> Option<u32> {
-        match self.io {
-            Some(ref io) => return io.get_write_timeout(),
-            _ => (),
-        }
-        match self.io_ref {
-            Some(ref io) => return io.get_write_timeout(),
-            _ => (),
-        }
-        unreachable!();
+        self.io().get_write_timeout()
     }
 }
 
 impl<'a, T: PipeIo> PipeIo for WriteHandle<'a, T> {
     fn io_obj<'b>(&'b mut self) -> PipeIoObj<'b> {
-        match self.io {
-            Some(ref mut io) => return io.io_obj(),
-            _ => (),
-        }
-        match self.io_ref {
-            Some(ref mut io) => return io.io_obj(),
-            _ => (),
-        }
-        unreachable!();
+        self.io_mut().io_obj()
     }
 
     fn io_handles<'b>(&'b self) -> PipeIoHandles<'b> {
-        match self.io {
-            Some(ref io) => return io.io_handles(),
-            _ => (),
-        }
-        match self.io_ref {
-            Some(ref io) => return io.io_handles(),
-            _ => (),
-        }
-        unreachable!();
+        self.io().io_handles()
     }
 
     fn get_read_timeout(&self) -> Option<u32> {
-        match self.io {
-            Some(ref io) => return io.get_read_timeout(),
-            _ => (),
-        }
-        match self.io_ref {
-            Some(ref io) => return io.get_read_timeout(),
-            _ => (),
-        }
-        unreachable!();
+        self.io().get_read_timeout()
     }
 
     fn get_write_timeout(&self) -> Option<u32> {
-        match self.io {
-            Some(ref io) => return io.get_write_timeout(),
-            _ => (),
-        }
-        match self.io_ref {
-            Some(ref io) => return io.get_write_timeout(),
-            _ => (),
-        }
-        unreachable!();
+        self.io().get_write_timeout()
     }
 }
 
 impl PipeIo for ConnectingServer {
     fn io_obj<'a>(&'a mut self) -> PipeIoObj<'a> {
         PipeIoObj {
-            handle: self.handle.value,
+            handle: self.handle.raw(),
             ovl: &mut self.ovl,
+            shutdown: self.shutdown.as_ref().map(ShutdownHandle::inner),
         }
     }
 
     fn io_handles<'a>(&'a self) -> PipeIoHandles<'a> {
         PipeIoHandles {
-            pipe_handle: self.handle.value,
-            event_handle: self.ovl.ovl.hEvent,
-            _phantom: PhantomData,
+            name: &self.name,
+            pipe_handle: self.handle.raw(),
+            ovl: &self.ovl,
+            shutdown: self.shutdown.as_ref().map(ShutdownHandle::inner),
         }
     }
 
@@ -926,12 +1024,18 @@ impl PipeIo for ConnectingServer {
     }
 }
 
+/// Result of a finished IO operation: number of bytes transferred and owned data (pipe and buffer)
+/// if operation was created by `read_async_owned`/`write_async_owned`.
+pub type OwnedIoResult<T> = Result<(usize, Option<(T, Vec<u8>)>), PipeError>;
+
 /// Pending read operation. Can be used with [`wait`](fn.wait.html) and [`wait_all`]
 /// (fn.wait_all.html) functions.
 pub struct ReadHandle<'a, T: PipeIo> {
     io: Option<T>,
     io_ref: Option<&'a mut dyn PipeIo>,
     bytes_read: u32,
+    len: u32,
+    more_data: bool,
     pending: bool,
     buffer: Option<Vec<u8>>,
 }
@@ -944,6 +1048,7 @@ impl<'a, T: fmt::Debug + PipeIo> fmt::Debug for ReadHandle<'a, T> {
                 .field("io", &self.io)
                 .field("io_ref", &io.io_handles())
                 .field("bytes_read", &self.bytes_read)
+                .field("more_data", &self.more_data)
                 .field("pending", &self.pending)
                 .field("buffer", &self.buffer)
                 .finish(),
@@ -952,6 +1057,7 @@ impl<'a, T: fmt::Debug + PipeIo> fmt::Debug for ReadHandle<'a, T> {
                 .field("io", &self.io)
                 .field("io_ref", &"None")
                 .field("bytes_read", &self.bytes_read)
+                .field("more_data", &self.more_data)
                 .field("pending", &self.pending)
                 .field("buffer", &self.buffer)
                 .finish(),
@@ -961,79 +1067,123 @@ impl<'a, T: fmt::Debug + PipeIo> fmt::Debug for ReadHandle<'a, T> {
 
 impl<'a, T: PipeIo> Drop for ReadHandle<'a, T> {
     fn drop(&mut self) {
-        let timeout = self.get_read_timeout().unwrap_or(INFINITE);
-        if self.pending && timeout > 0 {
-            let result = unsafe {
-                let io_obj = self.io_obj();
-                CancelIoEx(io_obj.handle, &mut *io_obj.ovl.ovl)
-            };
-            if result == FALSE {
-                let error = io::Error::last_os_error();
-                match error.raw_os_error().unwrap() as u32 {
-                    ERROR_IO_PENDING => (/* OK */),
-                    _ => panic!("CANCEL ERROR {:?}", error),
-                }
+        if self.pending {
+            if let Err(err) = cancel_io(self) {
+                let name = self.io().io_handles().name;
+                report_drop_error(PipeError::new(err, Some(name.clone()), Operation::Cancel));
             }
         }
     }
 }
 
 impl<'a, T: PipeIo> ReadHandle<'a, T> {
+    fn io(&self) -> &dyn PipeIo {
+        match (&self.io, &self.io_ref) {
+            (Some(io), _) => io,
+            (None, Some(io)) => &**io,
+            (None, None) => unreachable!(),
+        }
+    }
+
+    fn io_mut(&mut self) -> &mut dyn PipeIo {
+        match (&mut self.io, &mut self.io_ref) {
+            (Some(io), _) => io,
+            (None, Some(io)) => &mut **io,
+            (None, None) => unreachable!(),
+        }
+    }
+
     fn wait_impl(&mut self) -> io::Result<()> {
+        let timeout = self.get_read_timeout().unwrap_or(INFINITE);
+        if self.poll(timeout)? {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out while reading from pipe",
+            ))
+        }
+    }
+
+    /// Returns `false` if operation is still pending after `timeout`.
+    fn poll(&mut self, timeout: u32) -> io::Result<bool> {
         if self.pending {
-            let timeout = self.get_read_timeout().unwrap_or(INFINITE);
-            match wait_for_single_obj(self, timeout)? {
-                Some(_) => {
-                    let mut count = 0;
-                    self.pending = false;
-                    match get_ovl_result(self, &mut count)? {
-                        0 => Err(io::Error::last_os_error()),
-                        _ => {
-                            self.bytes_read = count;
-                            Ok(())
-                        }
+            if wait_for_single_obj(self, timeout)? {
+                self.pending = false;
+                match get_ovl_result(self) {
+                    Ok(bytes_read) => self.bytes_read = bytes_read,
+                    Err(ref err) if sys::is_more_data(err) => {
+                        self.bytes_read = self.len;
+                        self.more_data = true;
                     }
+                    Err(err) => return Err(err),
                 }
-                None => Err(io::Error::new(
-                    io::ErrorKind::TimedOut,
-                    "timed out while reading from pipe",
-                )),
+            } else {
+                return Ok(false);
             }
-        } else {
-            Ok(())
+        }
+        Ok(true)
+    }
+
+    /// Checks for completion without blocking.
+    ///
+    /// Returns `Some(<bytes_read>)` if operation is finished, then `wait` will return
+    /// immediately giving back owned data.
+    pub fn try_wait(&mut self) -> Result<Option<usize>, PipeError> {
+        let error = pipe_error(self.io().io_handles().name, Operation::Read);
+        match self.poll(0) {
+            Ok(true) => Ok(Some(self.bytes_read as usize)),
+            Ok(false) => Ok(None),
+            Err(ref err) if sys::is_disconnected(err) => Ok(Some(self.bytes_read as usize)),
+            Err(err) => Err(error(err)),
         }
     }
     /// Will wait for completion infinitely, or until read_timeout reached if read_timeout has been set.
     ///
     /// Returns (<bytes_read>, <owned_data>). Owned data is `Some((T, Vec<u8>))` if `ReadHandle`
-    /// was created as a result of `T::read_async_owned`.
-    pub fn wait(mut self) -> io::Result<(usize, Option<(T, Vec<u8>)>)> {
-        let result = self.wait_impl();
-        let output = {
-            let io = self.io.take();
-            let bytes_read = self.bytes_read;
-            let buffer = self.buffer.take();
-            if let Some(buf) = buffer {
-                if let Some(io) = io {
-                    Ok((bytes_read as usize, Some((io, buf))))
-                } else {
-                    unreachable!()
-                }
-            } else {
-                Ok((bytes_read as usize, None))
-            }
-        };
-        match result {
-            Ok(_) => output,
+    /// was created as a result of `T::read_async_owned`. End of stream is reported as zero
+    /// `bytes_read`.
+    pub fn wait(mut self) -> OwnedIoResult<T> {
+        let error = pipe_error(self.io().io_handles().name, Operation::Read);
+        match self.wait_impl() {
+            Ok(_) => Ok(self.output()),
             Err(err) => {
-                if err.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) {
-                    output
+                if sys::is_disconnected(&err) {
+                    Ok(self.output())
                 } else {
-                    Err(err)
+                    Err(error(err))
                 }
             }
         }
     }
+
+    /// Cancels pending read and waits for the cancellation to complete.
+    ///
+    /// Returns (<bytes_read>, <owned_data>) as `wait` does, where `bytes_read` is the number of
+    /// bytes that were read into the buffer before the cancellation.
+    pub fn cancel(mut self) -> OwnedIoResult<T> {
+        if self.pending {
+            let error = pipe_error(self.io().io_handles().name, Operation::Cancel);
+            self.pending = false;
+            self.bytes_read = cancel_io(&mut self).map_err(error)?;
+        }
+        Ok(self.output())
+    }
+
+    fn output(&mut self) -> (usize, Option<(T, Vec<u8>)>) {
+        let io = self.io.take();
+        let bytes_read = self.bytes_read;
+        let buffer = self.buffer.take();
+        if let Some(buf) = buffer {
+            if let Some(io) = io {
+                (bytes_read as usize, Some((io, buf)))
+            } else {
+                unreachable!()
+            }
+        } else {
+            (bytes_read as usize, None)
+        }
+    }
 }
 
 /// Pending write operation. Can be used with [`wait`](fn.wait.html) and [`wait_all`]
@@ -1074,85 +1224,115 @@ impl<'a, T: fmt::Debug + PipeIo> fmt::Debug for WriteHandle<'a, T> {
 
 impl<'a, T: PipeIo> Drop for WriteHandle<'a, T> {
     fn drop(&mut self) {
-        let timeout = self.get_write_timeout().unwrap_or(INFINITE);
-        if self.pending && timeout > 0 {
-            let result = unsafe {
-                let io_obj = self.io_obj();
-                CancelIoEx(io_obj.handle, &mut *io_obj.ovl.ovl)
-            };
-            if result == FALSE {
-                let error = io::Error::last_os_error();
-                match error.raw_os_error().unwrap() as u32 {
-                    ERROR_IO_PENDING => (/* OK */),
-                    _ => panic!("CANCEL ERROR {:?}", error),
-                }
+        if self.pending {
+            if let Err(err) = cancel_io(self) {
+                let name = self.io().io_handles().name;
+                report_drop_error(PipeError::new(err, Some(name.clone()), Operation::Cancel));
             }
         }
     }
 }
 
 impl<'a, T: PipeIo> WriteHandle<'a, T> {
+    fn io(&self) -> &dyn PipeIo {
+        match (&self.io, &self.io_ref) {
+            (Some(io), _) => io,
+            (None, Some(io)) => &**io,
+            (None, None) => unreachable!(),
+        }
+    }
+
+    fn io_mut(&mut self) -> &mut dyn PipeIo {
+        match (&mut self.io, &mut self.io_ref) {
+            (Some(io), _) => io,
+            (None, Some(io)) => &mut **io,
+            (None, None) => unreachable!(),
+        }
+    }
+
     fn wait_impl(&mut self) -> io::Result<()> {
+        let timeout = self.get_write_timeout().unwrap_or(INFINITE);
+        if self.poll(timeout)? {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out while writing into pipe",
+            ))
+        }
+    }
+
+    /// Returns `false` if operation is still pending after `timeout`.
+    fn poll(&mut self, timeout: u32) -> io::Result<bool> {
         if self.pending {
-            let timeout = self.get_write_timeout().unwrap_or(INFINITE);
-            match wait_for_single_obj(self, timeout)? {
-                Some(_) => {
-                    let mut bytes_written = 0;
-                    self.pending = false;
-                    match get_ovl_result(self, &mut bytes_written)? {
-                        x if x as u32 == self.num_bytes => {
-                            self.bytes_written = bytes_written;
-                            Ok(())
-                        }
-                        _ => Err(io::Error::last_os_error()),
+            if wait_for_single_obj(self, timeout)? {
+                self.pending = false;
+                match get_ovl_result(self)? {
+                    x if x == self.num_bytes => self.bytes_written = x,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "failed to write whole buffer into pipe",
+                        ))
                     }
                 }
-                None => Err(io::Error::new(
-                    io::ErrorKind::TimedOut,
-                    "timed out while writing into pipe",
-                )),
+            } else {
+                return Ok(false);
             }
-        } else {
-            Ok(())
+        }
+        Ok(true)
+    }
+
+    /// Checks for completion without blocking.
+    ///
+    /// Returns `Some(<bytes_written>)` if operation is finished, then `wait` will return
+    /// immediately giving back owned data.
+    pub fn try_wait(&mut self) -> Result<Option<usize>, PipeError> {
+        let error = pipe_error(self.io().io_handles().name, Operation::Write);
+        match self.poll(0) {
+            Ok(true) => Ok(Some(self.bytes_written as usize)),
+            Ok(false) => Ok(None),
+            Err(err) => Err(error(err)),
         }
     }
 
     /// Will wait for completion infinitely, or until write_timeout reached if write_timeout has been set.
     ///
     /// Returns (<bytes_read>, <owned_data>). Owned data is `Some((T, Vec<u8>))` if `WriteHandle`
-    /// was created as a result of `T::write_async_owned`.
-    pub fn wait(mut self) -> io::Result<(usize, Option<(T, Vec<u8>)>)> {
-        self.wait_impl()?;
+    /// was created as a result of `T::write_async_owned`. Fails with `PipeError::Disconnected`
+    /// (`ErrorKind::BrokenPipe`) if the other side is closed.
+    pub fn wait(mut self) -> OwnedIoResult<T> {
+        let error = pipe_error(self.io().io_handles().name, Operation::Write);
+        self.wait_impl().map_err(error)?;
+        Ok(self.output())
+    }
+
+    /// Cancels pending write and waits for the cancellation to complete.
+    ///
+    /// Returns (<bytes_written>, <owned_data>) as `wait` does, where `bytes_written` is the number
+    /// of bytes that were written into the pipe before the cancellation.
+    pub fn cancel(mut self) -> OwnedIoResult<T> {
+        if self.pending {
+            let error = pipe_error(self.io().io_handles().name, Operation::Cancel);
+            self.pending = false;
+            self.bytes_written = cancel_io(&mut self).map_err(error)?;
+        }
+        Ok(self.output())
+    }
+
+    fn output(&mut self) -> (usize, Option<(T, Vec<u8>)>) {
         let io = self.io.take();
         let bytes_written = self.bytes_written;
         let buffer = self.buffer.take();
         if let Some(buf) = buffer {
             if let Some(io) = io {
-                Ok((bytes_written as usize, Some((io, buf))))
+                (bytes_written as usize, Some((io, buf)))
             } else {
                 unreachable!()
             }
         } else {
-            Ok((bytes_written as usize, None))
-        }
-    }
-}
-
-/// Returns `Ok(true)` if connection is pending or `Ok(false)` if pipe is connected.
-fn connect_named_pipe(handle: &Handle, ovl: &mut Overlapped) -> io::Result<bool> {
-    let result = unsafe { ConnectNamedPipe(handle.value, ovl.get_mut()) };
-    if result == TRUE {
-        // Overlapped ConnectNamedPipe should return FALSE
-        return Err(io::Error::last_os_error());
-    } else {
-        let err = io::Error::last_os_error();
-        let mut pending = false;
-        match err.raw_os_error().unwrap() as u32 {
-            ERROR_IO_PENDING => pending = true,
-            ERROR_PIPE_CONNECTED => ovl.event.set()?,
-            _ => return Err(err),
+            (bytes_written as usize, None)
         }
-        Ok(pending)
     }
 }
 
@@ -1160,75 +1340,91 @@ fn init_read<'a, 'b: 'a, T>(this: &'a mut T, buf: &'b mut [u8]) -> io::Result<Re
 where
     T: PipeIo,
 {
-    let mut bytes_read = 0;
-    let result = unsafe {
-        let io_obj = this.io_obj();
-        ReadFile(
-            io_obj.handle,
-            buf.as_mut_ptr() as *mut c_void,
-            buf.len() as u32,
-            &mut bytes_read,
-            &mut *io_obj.ovl.ovl,
-        )
+    let len = buf.len().min(0xFFFFFFFF) as u32;
+    let result = unsafe { start_read(this, buf.as_mut_ptr(), len) }?;
+    Ok(ReadHandle {
+        io: None,
+        io_ref: Some(this),
+        bytes_read: result.map_or(0, |x| x.0),
+        len,
+        more_data: result.is_some_and(|x| x.1),
+        pending: result.is_none(),
+        buffer: None,
+    })
+}
+
+fn init_read_owned<T: PipeIo>(mut this: T, mut buf: Vec<u8>) -> io::Result<ReadHandle<'static, T>> {
+    let len = buf.len().min(0xFFFFFFFF) as u32;
+    let result = match unsafe { start_read(&mut this, buf.as_mut_ptr(), len) } {
+        // End of stream, so `wait` gives back the pipe and the buffer.
+        Err(ref err) if sys::is_disconnected(err) => Some((0, false)),
+        result => result?,
     };
+    Ok(ReadHandle {
+        io: Some(this),
+        io_ref: None,
+        bytes_read: result.map_or(0, |x| x.0),
+        len,
+        more_data: result.is_some_and(|x| x.1),
+        pending: result.is_none(),
+        buffer: Some(buf),
+    })
+}
 
-    if result != 0 && bytes_read != 0 {
-        Ok(ReadHandle {
-            io: None,
-            io_ref: Some(this),
-            bytes_read: bytes_read,
-            pending: false,
-            buffer: None,
-        })
-    } else {
-        let err = io::Error::last_os_error();
-        if result == 0 && err.raw_os_error().unwrap() == ERROR_IO_PENDING as i32 {
-            Ok(ReadHandle {
-                io: None,
-                io_ref: Some(this),
-                bytes_read: 0,
-                pending: true,
-                buffer: None,
-            })
-        } else {
-            Err(err)
-        }
+/// Returns `Ok(Some((bytes_read, more_data)))` if read was completed synchronously or `Ok(None)`
+/// if pending. `more_data` is `true` if message didn't fit into the buffer.
+///
+/// # Unsafety
+/// `buf` must stay valid until operation is completed or cancelled.
+unsafe fn start_read<T: PipeIo>(
+    this: &mut T,
+    buf: *mut u8,
+    len: u32,
+) -> io::Result<Option<(u32, bool)>> {
+    let io_obj = this.io_obj();
+    match sys::read_file(io_obj.handle, buf, len, io_obj.ovl) {
+        Ok(result) => Ok(result.map(|bytes_read| (bytes_read, false))),
+        Err(ref err) if sys::is_more_data(err) => Ok(Some((len, true))),
+        Err(err) => Err(err),
     }
 }
 
-fn init_read_owned<T: PipeIo>(mut this: T, mut buf: Vec<u8>) -> io::Result<ReadHandle<'static, T>> {
-    let mut bytes_read = 0;
-    let result = unsafe {
-        let io_obj = this.io_obj();
-        ReadFile(
-            io_obj.handle,
-            buf.as_mut_ptr() as *mut c_void,
-            buf.len() as u32,
-            &mut bytes_read,
-            &mut *io_obj.ovl.ovl,
-        )
-    };
+/// Waits for `read_handle` respecting read timeout. Returns `Ok(0)` at the end of stream, i.e.
+/// if the other side is closed or disconnected and everything it wrote is read.
+fn wait_read<T: PipeIo>(read_handle: io::Result<ReadHandle<'_, T>>) -> io::Result<usize> {
+    let result = read_handle.and_then(|mut read_handle| {
+        read_handle.wait_impl()?;
+        Ok(read_handle.bytes_read as usize)
+    });
+    match result {
+        Err(ref err) if sys::is_disconnected(err) => Ok(0),
+        result => result,
+    }
+}
 
-    if result != 0 && bytes_read != 0 {
-        Ok(ReadHandle {
-            io: Some(this),
-            io_ref: None,
-            bytes_read: bytes_read,
-            pending: false,
-            buffer: Some(buf),
-        })
+fn read_message_part<T: PipeIo>(this: &mut T, buf: &mut [u8]) -> io::Result<MessageRead> {
+    let mut read_handle = init_read(this, buf)?;
+    read_handle.wait_impl()?;
+    let bytes_read = read_handle.bytes_read as usize;
+    if read_handle.more_data {
+        Ok(MessageRead::Partial(bytes_read))
     } else {
-        let err = io::Error::last_os_error();
-        if result == 0 && err.raw_os_error().unwrap() == ERROR_IO_PENDING as i32 {
-            Ok(ReadHandle {
-                io: Some(this),
-                io_ref: None,
-                bytes_read: 0,
-                pending: true,
-                buffer: Some(buf),
-            })
-        } else {
-            Err(err)
+        Ok(MessageRead::Complete(bytes_read))
+    }
+}
+
+fn read_message<T: PipeIo>(this: &mut T) -> io::Result<Vec<u8>> {
+    const CHUNK_SIZE: usize = 4096;
+    let mut message = Vec::new();
+    loop {
+        let start = message.len();
+        message.resize(start + CHUNK_SIZE, 0);
+        match read_message_part(this, &mut message[start..])? {
+            MessageRead::Complete(bytes_read) => {
+                message.truncate(start + bytes_read);
+                return Ok(message);
+            }
+            MessageRead::Partial(bytes_read) => message.truncate(start + bytes_read),
         }
     }
 }
@@ -1238,171 +1434,407 @@ where
     T: PipeIo,
 {
     assert!(buf.len() <= 0xFFFFFFFF);
-    let mut bytes_written = 0;
-    let result = unsafe {
-        let io_obj = this.io_obj();
-        WriteFile(
-            io_obj.handle,
-            buf.as_ptr() as *mut c_void,
-            buf.len() as u32,
-            &mut bytes_written,
-            &mut *io_obj.ovl.ovl,
-        )
-    };
+    let result = unsafe { start_write(this, buf.as_ptr(), buf.len()) }?;
+    Ok(WriteHandle {
+        io: None,
+        io_ref: Some(this),
+        buffer: None,
+        bytes_written: result.unwrap_or(0),
+        num_bytes: buf.len() as u32,
+        pending: result.is_none(),
+    })
+}
 
-    if result != 0 && bytes_written == buf.len() as u32 {
-        Ok(WriteHandle {
+fn init_write_owned<T: PipeIo>(mut this: T, buf: Vec<u8>) -> io::Result<WriteHandle<'static, T>> {
+    assert!(buf.len() <= 0xFFFFFFFF);
+    let result = unsafe { start_write(&mut this, buf.as_ptr(), buf.len()) }?;
+    Ok(WriteHandle {
+        io_ref: None,
+        io: Some(this),
+        num_bytes: buf.len() as u32,
+        buffer: Some(buf),
+        bytes_written: result.unwrap_or(0),
+        pending: result.is_none(),
+    })
+}
+
+/// Returns `Ok(Some(bytes_written))` if whole buffer was written synchronously or `Ok(None)`
+/// if write is pending.
+///
+/// # Unsafety
+/// `buf` must stay valid until operation is completed or cancelled.
+unsafe fn start_write<T: PipeIo>(
+    this: &mut T,
+    buf: *const u8,
+    len: usize,
+) -> io::Result<Option<u32>> {
+    let io_obj = this.io_obj();
+    match sys::write_file(io_obj.handle, buf, len as u32, io_obj.ovl)? {
+        Some(bytes_written) if bytes_written == len as u32 => Ok(Some(bytes_written)),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "failed to write whole buffer into pipe",
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Reads into `bufs` with a single read: natively if pipe supports it, otherwise into a temporary
+/// buffer that is scattered afterwards, so a message isn't split between reads. Returns `Ok(0)`
+/// at the end of stream, see `read`.
+fn read_vectored<T: PipeIo>(this: &mut T, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+    let count = bufs.len().min(sys::IOV_MAX);
+    let bufs = &mut bufs[..count];
+    let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+    let len = len.min(0xFFFFFFFF) as u32;
+    let io_obj = this.io_obj();
+    let started = unsafe { sys::read_file_vectored(io_obj.handle, bufs, io_obj.ovl) };
+    match started {
+        Some(result) => wait_read(result.map(|result| ReadHandle::<T> {
             io: None,
             io_ref: Some(this),
+            bytes_read: result.unwrap_or(0),
+            len,
+            more_data: false,
+            pending: result.is_none(),
             buffer: None,
-            bytes_written: bytes_written,
-            num_bytes: buf.len() as u32,
-            pending: false,
-        })
-    } else {
-        let err = io::Error::last_os_error();
-        if result == 0 && err.raw_os_error().unwrap() == ERROR_IO_PENDING as i32 {
-            Ok(WriteHandle {
+        })),
+        None => {
+            let mut buf = vec![0; len as usize];
+            let bytes_read = wait_read(init_read(this, &mut buf))?;
+            let mut data = &buf[..bytes_read];
+            for buf in bufs.iter_mut() {
+                let n = buf.len().min(data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                data = &data[n..];
+            }
+            Ok(bytes_read)
+        }
+    }
+}
+
+/// Writes `bufs` with a single write: natively if pipe supports it, otherwise gathered into a
+/// temporary buffer, so message-mode pipe gets them as one message.
+fn write_vectored<T: PipeIo>(this: &mut T, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+    let bufs = &bufs[..bufs.len().min(sys::IOV_MAX)];
+    let num_bytes = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+    assert!(num_bytes <= 0xFFFFFFFF);
+    let io_obj = this.io_obj();
+    let started = unsafe { sys::write_file_vectored(io_obj.handle, bufs, io_obj.ovl) };
+    let mut write_handle = match started {
+        Some(result) => {
+            let result = result?;
+            WriteHandle::<T> {
                 io: None,
                 io_ref: Some(this),
                 buffer: None,
-                bytes_written: 0,
-                num_bytes: buf.len() as u32,
-                pending: true,
-            })
-        } else {
-            Err(err)
+                bytes_written: result.unwrap_or(0),
+                num_bytes: num_bytes as u32,
+                pending: result.is_none(),
+            }
         }
-    }
+        None => {
+            let buf = gather(bufs);
+            let mut write_handle = init_write(this, &buf)?;
+            write_handle.wait_impl()?;
+            return Ok(write_handle.bytes_written as usize);
+        }
+    };
+    write_handle.wait_impl()?;
+    Ok(write_handle.bytes_written as usize)
 }
 
-fn init_write_owned<'a, 'b: 'a, T>(mut this: T, buf: Vec<u8>) -> io::Result<WriteHandle<'static, T>>
-where
-    T: PipeIo,
-{
-    assert!(buf.len() <= 0xFFFFFFFF);
-    let mut bytes_written = 0;
-    let result = unsafe {
-        let io_obj = this.io_obj();
-        WriteFile(
-            io_obj.handle,
-            buf.as_ptr() as *mut c_void,
-            buf.len() as u32,
-            &mut bytes_written,
-            &mut *io_obj.ovl.ovl,
-        )
-    };
+/// Concatenates `bufs` into a single buffer.
+fn gather(bufs: &[io::IoSlice<'_>]) -> Vec<u8> {
+    bufs.iter().flat_map(|buf| buf.iter()).copied().collect()
+}
 
-    if result != 0 && bytes_written == buf.len() as u32 {
-        Ok(WriteHandle {
-            io_ref: None,
-            io: Some(this),
-            num_bytes: buf.len() as u32,
-            buffer: Some(buf),
-            bytes_written: bytes_written,
-            pending: false,
-        })
-    } else {
-        let err = io::Error::last_os_error();
-        if result == 0 && err.raw_os_error().unwrap() == ERROR_IO_PENDING as i32 {
-            Ok(WriteHandle {
-                io_ref: None,
-                io: Some(this),
-                num_bytes: buf.len() as u32,
-                buffer: Some(buf),
-                bytes_written: 0,
-                pending: true,
-            })
-        } else {
-            Err(err)
-        }
-    }
+/// Cancels pending operation and waits until it is finished. Returns number of bytes transferred
+/// before the cancellation.
+fn cancel_io<T: PipeIo>(this: &mut T) -> io::Result<u32> {
+    let io_obj = this.io_obj();
+    sys::cancel_io(io_obj.handle, io_obj.ovl)
 }
 
-fn get_ovl_result<T: PipeIo>(this: &mut T, count: &mut u32) -> io::Result<usize> {
-    let result = unsafe {
-        let io_obj = this.io_obj();
-        GetOverlappedResult(io_obj.handle, &mut *io_obj.ovl.ovl, count, TRUE)
-    };
-    if result != 0 {
-        Ok(*count as usize)
-    } else {
-        Err(io::Error::last_os_error())
-    }
+fn get_ovl_result<T: PipeIo>(this: &mut T) -> io::Result<u32> {
+    let io_obj = this.io_obj();
+    sys::get_overlapped_result(io_obj.handle, io_obj.ovl)
 }
 
-fn wait_for_single_obj<T>(this: &mut T, timeout: u32) -> io::Result<Option<usize>>
+/// Returns `Ok(true)` if operation was completed and `Ok(false)` on timeout.
+fn wait_for_single_obj<T>(this: &mut T, timeout: u32) -> io::Result<bool>
 where
     T: PipeIo,
 {
-    let result = unsafe {
-        let io_obj = this.io_obj();
-        WaitForSingleObject(io_obj.ovl.event.handle.value, timeout)
-    };
-
-    match result {
-        WAIT_OBJECT_0 => Ok(Some(0)),
-        WAIT_TIMEOUT => Ok(None),
-        WAIT_FAILED => Err(io::Error::last_os_error()),
-        _ => unreachable!(),
-    }
+    let io_obj = this.io_obj();
+    let shutdown = io_obj.shutdown.into_iter().collect::<Vec<_>>();
+    sys::wait_for_single(io_obj.handle, io_obj.ovl, timeout, &shutdown)
 }
 
 fn wait_for_multiple_obj<T>(list: &[T], all: bool, timeout: u32) -> io::Result<Option<usize>>
 where
     T: PipeIo,
 {
-    assert!(list.len() <= MAXIMUM_WAIT_OBJECTS as usize);
-    if list.len() == 0 {
-        Ok(None)
-    } else {
-        let mut events = Vec::with_capacity(list.len());
-
-        for obj in list.iter() {
-            events.push(obj.io_handles().event_handle);
-        }
+    let handles = list.iter().map(|obj| obj.io_handles()).collect::<Vec<_>>();
+    let list = handles
+        .iter()
+        .map(|handles| (handles.pipe_handle, handles.ovl))
+        .collect::<Vec<_>>();
+    sys::wait_for_multiple(&list, all, timeout, &shutdown_handles(&handles))
+}
 
-        let result = unsafe {
-            WaitForMultipleObjects(
-                events.len() as u32,
-                events.as_ptr(),
-                if all { TRUE } else { FALSE },
-                timeout,
-            )
-        };
+fn wait_for_ready_obj<'a, T, I>(list: I, timeout: u32) -> io::Result<Vec<usize>>
+where
+    T: PipeIo + ?Sized + 'a,
+    I: IntoIterator<Item = &'a T>,
+{
+    let handles = list
+        .into_iter()
+        .map(|obj| obj.io_handles())
+        .collect::<Vec<_>>();
+    let list = handles
+        .iter()
+        .map(|handles| (handles.pipe_handle, handles.ovl))
+        .collect::<Vec<_>>();
+    sys::wait_for_ready(&list, timeout, &shutdown_handles(&handles))
+}
 
-        if all {
-            match result {
-                WAIT_OBJECT_0 => Ok(Some(0)),
-                WAIT_TIMEOUT => Ok(None),
-                WAIT_FAILED => Err(io::Error::last_os_error()),
-                _ => unreachable!(),
-            }
-        } else {
-            match result {
-                i if i < 64 => Ok(Some(i as usize)),
-                WAIT_TIMEOUT => Ok(None),
-                WAIT_FAILED => Err(io::Error::last_os_error()),
-                _ => unreachable!(),
-            }
+/// Returns distinct shutdown handles of objects.
+fn shutdown_handles<'a>(handles: &[PipeIoHandles<'a>]) -> Vec<&'a sys::Shutdown> {
+    let mut shutdown: Vec<&sys::Shutdown> = Vec::new();
+    for handle in handles.iter().filter_map(|handles| handles.shutdown) {
+        if !shutdown.iter().any(|x| std::ptr::eq(*x, handle)) {
+            shutdown.push(handle);
         }
     }
+    shutdown
 }
 
 /// This function will wait for first finished io operation and return it's index in `list`.
 ///
+/// Number of operations isn't limited (see [`WaitSet`](struct.WaitSet.html)).
+///
 /// # Panics
 ///
-/// This function will panic if `list.len() == 0` or `list.len() > MAXIMUM_WAIT_OBJECTS`
-pub fn wait<T: PipeIo>(list: &[T]) -> io::Result<usize> {
-    assert!(list.len() > 0);
+/// This function will panic if `list.len() == 0`
+pub fn wait<T: PipeIo>(list: &[T]) -> Result<usize, PipeError> {
+    assert!(!list.is_empty());
 
-    match wait_for_multiple_obj(list, false, INFINITE)? {
-        Some(x) => Ok(x),
+    match wait_for_ready_obj(list, INFINITE)
+        .map_err(|err| PipeError::new(err, None, Operation::Wait))?
+        .first()
+    {
+        Some(x) => Ok(*x),
         None => unreachable!(),
     }
 }
 
+/// This function will wait for all io operations in `list` to finish.
+///
+/// # Panics
+///
+/// This function will panic if `list.len() == 0`
+pub fn wait_all<T: PipeIo>(list: &[T]) -> Result<(), PipeError> {
+    assert!(!list.is_empty());
+
+    wait_for_multiple_obj(list, true, INFINITE)
+        .map_err(|err| PipeError::new(err, None, Operation::Wait))?;
+    Ok(())
+}
+
+/// Same as [`wait`](fn.wait.html) but returns `None` if no operation is finished within `timeout`.
+///
+/// # Panics
+///
+/// This function will panic if `list.len() == 0`
+pub fn wait_timeout<T: PipeIo>(list: &[T], timeout: Duration) -> Result<Option<usize>, PipeError> {
+    assert!(!list.is_empty());
+
+    let ready = wait_for_ready_obj(list, timeout_ms(timeout))
+        .map_err(|err| PipeError::new(err, None, Operation::Wait))?;
+    Ok(ready.first().cloned())
+}
+
+/// Same as [`wait_all`](fn.wait_all.html) but returns `false` if not all operations are finished
+/// within `timeout`.
+///
+/// # Panics
+///
+/// This function will panic if `list.len() == 0`
+pub fn wait_all_timeout<T: PipeIo>(list: &[T], timeout: Duration) -> Result<bool, PipeError> {
+    assert!(!list.is_empty());
+
+    let result = wait_for_multiple_obj(list, true, timeout_ms(timeout))
+        .map_err(|err| PipeError::new(err, None, Operation::Wait))?;
+    Ok(result.is_some())
+}
+
+/// Set of io operations (or pipes waiting for clients) to wait on.
+///
+/// Unlike `wait` it reports all finished operations at once, so it's suitable for a server
+/// that serves a lot of clients from one thread. Number of operations isn't limited by
+/// `MAXIMUM_WAIT_OBJECTS`: on Windows larger sets are split into groups waited by helper
+/// threads.
+///
+/// ```
+/// # use named_pipe::{PipeClient, PipeOptions, WaitSet};
+/// # use std::io::Write;
+/// let servers = PipeOptions::new("mem://wait_set_doc").multiple(100).unwrap();
+/// let mut client = PipeClient::connect("mem://wait_set_doc").unwrap();
+///
+/// let set = servers.iter().collect::<WaitSet<_>>();
+/// let ready = set.wait(None).unwrap();
+/// assert_eq!(ready.len(), 1);
+/// let mut server = servers.into_iter().nth(ready[0]).unwrap().wait().unwrap();
+/// # client.write_all(b"ping").unwrap();
+/// # let mut buf = [0; 4];
+/// # std::io::Read::read_exact(&mut server, &mut buf).unwrap();
+/// ```
+///
+/// Default `WaitSet` holds objects of different types, so it's possible to wait on new clients,
+/// reads and writes at the same time. Every object is identified by a user-assigned token:
+///
+/// ```
+/// # use named_pipe::{PipeClient, PipeOptions, WaitSet};
+/// # use std::io::Write;
+/// const ACCEPT: usize = 0;
+/// const READ: usize = 1;
+///
+/// let connecting = PipeOptions::new("mem://wait_set_tokens_doc").single().unwrap();
+/// let server = PipeOptions::new("mem://wait_set_tokens_doc_2").single().unwrap();
+/// let client = PipeClient::connect("mem://wait_set_tokens_doc_2").unwrap();
+/// let mut server = server.wait().unwrap();
+/// let read = client.read_async_owned(vec![0; 4]).unwrap();
+///
+/// server.write_all(b"ping").unwrap();
+/// let mut set: WaitSet = WaitSet::new();
+/// set.insert(&connecting, ACCEPT);
+/// set.insert(&read, READ);
+/// assert_eq!(set.wait(None).unwrap(), vec![READ]);
+/// ```
+pub struct WaitSet<'a, T: PipeIo + ?Sized = dyn PipeIo + 'a> {
+    list: Vec<(&'a T, usize)>,
+}
+
+impl<'a, T: PipeIo + ?Sized> WaitSet<'a, T> {
+    pub fn new() -> WaitSet<'a, T> {
+        WaitSet { list: Vec::new() }
+    }
+
+    /// Adds `io` to the set using its index as a token. Returns the index.
+    pub fn push(&mut self, io: &'a T) -> usize {
+        let index = self.list.len();
+        self.list.push((io, index));
+        index
+    }
+
+    /// Adds `io` to the set. `token` will be returned by `wait` once `io` is ready.
+    pub fn insert(&mut self, io: &'a T, token: usize) {
+        self.list.push((io, token));
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    /// Waits until at least one operation is finished and returns tokens of all finished
+    /// operations in order of insertion. Returns empty list if `timeout` is reached. `None`
+    /// stands for infinite waiting.
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<Vec<usize>, PipeError> {
+        let timeout = timeout.map(timeout_ms).unwrap_or(INFINITE);
+        let ready = wait_for_ready_obj(self.list.iter().map(|(io, _)| *io), timeout)
+            .map_err(|err| PipeError::new(err, None, Operation::Wait))?;
+        Ok(ready.into_iter().map(|i| self.list[i].1).collect())
+    }
+}
+
+impl<'a, T: PipeIo + ?Sized> Default for WaitSet<'a, T> {
+    fn default() -> WaitSet<'a, T> {
+        WaitSet::new()
+    }
+}
+
+impl<'a, T: PipeIo + ?Sized> FromIterator<&'a T> for WaitSet<'a, T> {
+    fn from_iter<I: IntoIterator<Item = &'a T>>(iter: I) -> WaitSet<'a, T> {
+        let mut set = WaitSet::new();
+        for io in iter {
+            set.push(io);
+        }
+        set
+    }
+}
+
+impl<'a, T: PipeIo + ?Sized> fmt::Debug for WaitSet<'a, T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_map()
+            .entries(self.list.iter().map(|(io, token)| (token, io.io_handles())))
+            .finish()
+    }
+}
+
+/// Converts `timeout` into milliseconds, saturating to infinite.
+fn timeout_ms(timeout: Duration) -> u32 {
+    let val = timeout.as_millis();
+    if val > INFINITE as u128 {
+        INFINITE
+    } else {
+        val as u32
+    }
+}
+
+/// Converts `timeout` into a deadline. Returns `None` for infinite timeout or if the deadline
+/// can't be represented.
+fn duration_deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.and_then(|timeout| Instant::now().checked_add(timeout))
+}
+
+/// Function that receives errors that occur in destructors.
+pub type DropErrorHook = Box<dyn Fn(&PipeError) + Send + Sync>;
+
+static DROP_ERROR_HOOK: Mutex<Option<DropErrorHook>> = Mutex::new(None);
+
+/// Registers a hook for errors that occur while a pending `ReadHandle` or `WriteHandle` is dropped
+/// (i.e. failed cancellation of the operation). Such errors are ignored by default.
+///
+/// Note that destructor always waits until the operation is finished, so the buffer is never
+/// used after the handle is dropped.
+pub fn set_drop_error_hook(hook: Option<DropErrorHook>) {
+    *DROP_ERROR_HOOK
+        .lock()
+        .unwrap_or_else(|err| err.into_inner()) = hook;
+}
+
+fn report_drop_error(err: PipeError) {
+    let hook = DROP_ERROR_HOOK
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    if let Some(ref hook) = *hook {
+        hook(&err);
+    }
+}
+
+/// Returns function that converts error of an `operation` on a pipe named `name`.
+fn pipe_error(name: &Arc<OsString>, operation: Operation) -> impl Fn(io::Error) -> PipeError {
+    let name = name.clone();
+    move |err| PipeError::new(err, Some(name.clone()), operation)
+}
+
+/// Creates pipe `options` and connects a client to it.
+#[cfg(test)]
+fn connected_pair(options: &PipeOptions) -> (PipeServer, PipeClient) {
+    let connecting = options.single().unwrap();
+    let client = PipeClient::connect(&*options.name).unwrap();
+    (connecting.wait().unwrap(), client)
+}
+
 #[test]
 fn test_io_single_thread() {
     let connecting_server = PipeOptions::new(r"\\.\pipe\test_io_single_thread")
@@ -1451,6 +1883,7 @@ fn test_io_single_thread() {
 }
 
 #[test]
+#[allow(clippy::unused_io_amount)]
 fn test_io_multiple_threads() {
     use std::io::{Read, Write};
     use std::thread;
@@ -1493,6 +1926,7 @@ fn test_io_multiple_threads() {
 }
 
 #[test]
+#[allow(clippy::len_zero, clippy::unused_io_amount)]
 fn test_wait() {
     use std::io::{Read, Write};
     use std::thread;
@@ -1523,6 +1957,7 @@ fn test_wait() {
 }
 
 #[test]
+#[allow(clippy::unused_io_amount)]
 fn test_timeout() {
     use std::io::{self, Read, Write};
     use std::thread;
@@ -1557,6 +1992,646 @@ fn test_timeout() {
 }
 
 #[test]
+fn test_connect_and_wait_timeout() {
+    use std::time::{Duration, Instant};
+
+    let timeout = Duration::from_millis(50);
+    let server = PipeOptions::new(r"\\.\pipe\test_connect_and_wait_timeout")
+        .single()
+        .unwrap();
+    let start = Instant::now();
+    let server = server.wait_timeout(Some(timeout)).unwrap().unwrap_err();
+    assert!(start.elapsed() >= timeout);
+    let server = server
+        .wait_deadline(Instant::now() - timeout)
+        .unwrap()
+        .unwrap_err();
+    drop(server);
+
+    for &name in &[
+        r"\\.\pipe\test_connect_timeout_busy",
+        "mem://test_connect_timeout_busy",
+    ] {
+        let server = PipeOptions::new(name).single().unwrap();
+        let _client = PipeClient::connect_timeout(name, None).unwrap();
+        let start = Instant::now();
+        let err = PipeClient::connect_timeout(name, Some(timeout)).unwrap_err();
+        assert!(matches!(err, PipeError::Busy(_)));
+        assert!(start.elapsed() >= timeout);
+        let err = PipeClient::connect_deadline(name, Instant::now()).unwrap_err();
+        assert!(matches!(err, PipeError::Busy(_)));
+        drop(server);
+    }
+}
+
+#[test]
+fn test_busy() {
+    let timeout = Some(Duration::from_millis(10));
+    for &name in &[r"\\.\pipe\test_busy", "mem://test_busy"] {
+        // Every instance takes a single client, connected or not.
+        let first = PipeOptions::new(name).single().unwrap();
+        let second = PipeOptions::new(name).first(false).single().unwrap();
+        let _first_client = PipeClient::connect(name).unwrap();
+        let _second_client = PipeClient::connect(name).unwrap();
+        let err = PipeClient::connect_timeout(name, timeout).unwrap_err();
+        assert!(matches!(err, PipeError::Busy(_)));
+        let first = first.wait().unwrap();
+        let second = second.wait().unwrap();
+        let err = PipeClient::connect_timeout(name, timeout).unwrap_err();
+        assert!(matches!(err, PipeError::Busy(_)));
+
+        // Disconnected instance takes the next client.
+        let first = first.disconnect().unwrap();
+        let _client = PipeClient::connect_timeout(name, timeout).unwrap();
+        let _first = first.wait().unwrap();
+        drop(second);
+        let err = PipeClient::connect_timeout(name, timeout).unwrap_err();
+        assert!(matches!(err, PipeError::Busy(_)));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_stale_socket() {
+    use std::ffi::OsString;
+    use std::fs::File;
+    use std::io::ErrorKind;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixListener;
+
+    let name = r"\\.\pipe\test_stale_socket";
+    let path = PipeName::new(name).unwrap().socket_path().unwrap();
+    let mut lock_name = OsString::from(".");
+    lock_name.push(path.file_name().unwrap());
+    lock_name.push(".lock");
+    let lock_path = path.with_file_name(lock_name);
+
+    // Socket that isn't guarded by a lock file belongs to someone else.
+    let listener = UnixListener::bind(&path).unwrap();
+    let err = PipeOptions::new(name).single().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+    // Lock is held by a server of another process.
+    let lock = File::create(&lock_path).unwrap();
+    assert_eq!(unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) }, 0);
+    let err = PipeOptions::new(name).single().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+    // Server crashed and left its files behind.
+    drop((lock, listener));
+    let server = PipeOptions::new(name).single().unwrap();
+    let client = PipeClient::connect(name).unwrap();
+    let server = server.wait().unwrap();
+    drop((client, server));
+    assert!(!path.exists());
+    assert!(!lock_path.exists());
+}
+
+#[test]
+fn test_try_clone() {
+    use std::io::{Read, Write};
+    use std::thread;
+
+    for &name in &[r"\\.\pipe\test_try_clone", "mem://test_memory_try_clone"] {
+        let (mut server, mut client) = connected_pair(&PipeOptions::new(name));
+
+        // Clones read while originals write, more than fits into pipe buffers.
+        let mut client_clone = client.try_clone().unwrap();
+        let client_reader = thread::spawn(move || {
+            let mut buf = vec![0; 100_000];
+            client_clone.read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|&x| x == 1));
+        });
+        let mut server_clone = server.try_clone().unwrap();
+        let server_reader = thread::spawn(move || {
+            let mut buf = vec![0; 100_000];
+            server_clone.read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|&x| x == 2));
+            server_clone
+        });
+        server.write_all(&[1; 100_000]).unwrap();
+        client.write_all(&[2; 100_000]).unwrap();
+        client_reader.join().unwrap();
+        let mut server_clone = server_reader.join().unwrap();
+
+        // Client can't be disconnected while there are other clones.
+        let err = server.try_clone().unwrap().disconnect().unwrap_err();
+        assert!(matches!(err, PipeError::Os(_)));
+
+        // Client stays connected until the last clone of the server is gone.
+        drop(server);
+        server_clone.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        let _server = server_clone.disconnect().unwrap();
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+}
+
+#[test]
+fn test_vectored_io() {
+    use std::io::{IoSlice, IoSliceMut, Read, Write};
+    use std::thread;
+
+    for &(name, pipe_mode) in &[
+        (r"\\.\pipe\test_vectored_io", PipeMode::Byte),
+        (r"\\.\pipe\test_vectored_io_message_mode", PipeMode::Message),
+        ("mem://test_memory_vectored_io", PipeMode::Byte),
+        ("mem://test_memory_vectored_io_message", PipeMode::Message),
+    ] {
+        let (mut server, mut client) = connected_pair(PipeOptions::new(name).pipe_mode(pipe_mode));
+
+        let bufs = [
+            IoSlice::new(b"head"),
+            IoSlice::new(b""),
+            IoSlice::new(b"body"),
+        ];
+        assert_eq!(client.write_vectored(&bufs).unwrap(), 8);
+        server.write_all(b"0123456789").unwrap();
+        let mut buf = [0; 8];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"headbody");
+
+        let (mut head, mut body) = ([0; 4], [0; 10]);
+        let mut bufs = [IoSliceMut::new(&mut head), IoSliceMut::new(&mut body)];
+        assert_eq!(client.read_vectored(&mut bufs).unwrap(), 10);
+        assert_eq!(&head, b"0123");
+        assert_eq!(&body[..6], b"456789");
+
+        let bufs = [IoSlice::new(b"owned "), IoSlice::new(b"write")];
+        let (bytes_written, owned) = client
+            .write_vectored_async_owned(&bufs)
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert_eq!(bytes_written, 11);
+        let (mut client, buf) = owned.unwrap();
+        assert_eq!(buf, b"owned write");
+        let mut buf = [0; 11];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"owned write");
+
+        if let PipeMode::Byte = pipe_mode {
+            // More than fits into pipe buffers.
+            let reader = thread::spawn(move || {
+                let mut buf = vec![0; 200_000];
+                server.read_exact(&mut buf).unwrap();
+                assert!(buf[..100_000].iter().all(|&x| x == 1));
+                assert!(buf[100_000..].iter().all(|&x| x == 2));
+            });
+            let (ones, twos) = (vec![1; 100_000], vec![2; 100_000]);
+            let bufs = [IoSlice::new(&ones), IoSlice::new(&twos)];
+            assert_eq!(client.write_vectored(&bufs).unwrap(), 200_000);
+            reader.join().unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_vectored_io_message_boundaries() {
+    use std::io::{IoSlice, Write};
+
+    // Buffers are written as a single message.
+    for &name in &[
+        r"\\.\pipe\test_vectored_io_message_boundaries",
+        "mem://test_memory_vectored_io_message_boundaries",
+    ] {
+        let (mut server, mut client) =
+            connected_pair(PipeOptions::new(name).pipe_mode(PipeMode::Message));
+        let bufs = [IoSlice::new(b"head"), IoSlice::new(b"body")];
+        assert_eq!(client.write_vectored(&bufs).unwrap(), 8);
+        client.write_all(b"next").unwrap();
+        assert_eq!(server.read_message().unwrap(), b"headbody");
+        assert_eq!(server.read_message().unwrap(), b"next");
+    }
+}
+
+#[test]
+fn test_peek() {
+    use std::io::{Read, Write};
+
+    for &(name, pipe_mode) in &[
+        (r"\\.\pipe\test_peek", PipeMode::Byte),
+        (r"\\.\pipe\test_peek_message", PipeMode::Message),
+        ("mem://test_memory_peek", PipeMode::Byte),
+        ("mem://test_memory_peek_message", PipeMode::Message),
+    ] {
+        let (mut server, mut client) = connected_pair(PipeOptions::new(name).pipe_mode(pipe_mode));
+        let message_mode = matches!(pipe_mode, PipeMode::Message);
+        // Byte-mode pipes have no messages.
+        let left = |n: usize| if message_mode { n } else { 0 };
+
+        let mut buf = [0; 8];
+        assert_eq!(server.peek(&mut buf).unwrap(), 0);
+        assert_eq!(server.bytes_available().unwrap(), 0);
+        assert_eq!(server.message_bytes_left().unwrap(), 0);
+
+        client.write_all(b"hello").unwrap();
+        client.write_all(b"world!!").unwrap();
+        assert_eq!(server.bytes_available().unwrap(), 12);
+        assert_eq!(server.peek(&mut buf[..3]).unwrap(), 3);
+        assert_eq!(&buf[..3], b"hel");
+        assert_eq!(server.message_bytes_left().unwrap(), left(5));
+
+        // Nothing is consumed by peeking.
+        let result = server.read_message_part(&mut buf[..2]).unwrap();
+        if message_mode {
+            assert_eq!(result, MessageRead::Partial(2));
+        } else {
+            assert_eq!(result, MessageRead::Complete(2));
+        }
+        assert_eq!(&buf[..2], b"he");
+        assert_eq!(server.bytes_available().unwrap(), 10);
+        assert_eq!(server.message_bytes_left().unwrap(), left(3));
+        if message_mode {
+            assert_eq!(server.peek(&mut buf).unwrap(), 3);
+            assert_eq!(&buf[..3], b"llo");
+        } else {
+            assert_eq!(server.peek(&mut buf).unwrap(), 8);
+            assert_eq!(&buf, b"lloworld");
+        }
+        let mut buf = [0; 10];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"lloworld!!");
+
+        server.write_all(b"ping").unwrap();
+        assert_eq!(client.bytes_available().unwrap(), 4);
+        assert_eq!(client.peek(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+
+        drop(client);
+        let err = server.peek(&mut buf).unwrap_err();
+        assert!(matches!(err, PipeError::Disconnected(_)));
+    }
+}
+
+#[test]
+fn test_eof() {
+    use std::io::{self, IoSliceMut, Read, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    for &name in &[r"\\.\pipe\test_eof", "mem://test_memory_eof"] {
+        // Client is closed, but its data is still there.
+        let (mut server, mut client) = connected_pair(&PipeOptions::new(name));
+        assert!(server.is_connected().unwrap());
+        client.write_all(b"data").unwrap();
+        drop(client);
+        assert!(server.is_connected().unwrap());
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"data");
+        assert!(!server.is_connected().unwrap());
+        assert_eq!(server.read(&mut buf).unwrap(), 0);
+        assert_eq!(server.read(&mut buf).unwrap(), 0);
+        let mut bufs = [IoSliceMut::new(&mut buf)];
+        assert_eq!(server.read_vectored(&mut bufs).unwrap(), 0);
+        let read = server.read_async_owned(vec![0; 4]).unwrap();
+        let (bytes_read, owned) = read.wait().unwrap();
+        assert_eq!(bytes_read, 0);
+        let (mut server, _) = owned.unwrap();
+        let err = server.write(b"data").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        let err = server
+            .write_async_owned(b"data".to_vec())
+            .and_then(|write| write.wait())
+            .unwrap_err();
+        assert!(matches!(err, PipeError::Disconnected(_)));
+
+        // Server is closed while client waits for data.
+        let (server, client) = connected_pair(&PipeOptions::new(name));
+        assert!(client.is_connected().unwrap());
+        let read = client.read_async_owned(vec![0; 4]).unwrap();
+        let closer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(server);
+        });
+        let (bytes_read, owned) = read.wait().unwrap();
+        assert_eq!(bytes_read, 0);
+        closer.join().unwrap();
+        let (mut client, _) = owned.unwrap();
+        assert!(!client.is_connected().unwrap());
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        let err = client.write_all(b"data").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
+
+#[test]
+fn test_message_mode() {
+    use std::io::{self, Write};
+
+    let server = PipeOptions::new(r"\\.\pipe\test_message_mode")
+        .pipe_mode(PipeMode::Message)
+        .single()
+        .unwrap();
+    let mut client = PipeClient::connect(r"\\.\pipe\test_message_mode").unwrap();
+    let mut server = server.wait().unwrap();
+
+    client.write_all(b"hello").unwrap();
+    client.write_all(b"world!!").unwrap();
+    assert_eq!(server.read_message().unwrap(), b"hello");
+    let mut buf = [0; 4];
+    let result = server.read_message_part(&mut buf).unwrap();
+    assert_eq!(result, MessageRead::Partial(4));
+    assert_eq!(&buf, b"worl");
+    let result = server.read_message_part(&mut buf).unwrap();
+    assert_eq!(result, MessageRead::Complete(3));
+    assert_eq!(&buf[..3], b"d!!");
+
+    let message = vec![7; 10000];
+    server.write_all(&message).unwrap();
+    assert_eq!(client.read_message().unwrap(), message);
+
+    drop(client);
+    let err = server.read_message().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn test_zero_buffers() {
+    use std::io::{ErrorKind, Read, Write};
+    use std::thread;
+
+    let name = r"\\.\pipe\test_zero_buffers";
+    let server = PipeOptions::new(name)
+        .out_buffer(0)
+        .in_buffer(0)
+        .single()
+        .unwrap();
+    let mut client = PipeClient::connect(name).unwrap();
+    let mut server = server.wait().unwrap();
+
+    // Nobody reads.
+    client.set_write_timeout(Some(Duration::from_millis(10)));
+    let err = client.write(b"0123456789").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    client.set_write_timeout(None);
+
+    // Write is completed by reads.
+    let writer = thread::spawn(move || {
+        client.write_all(b"0123456789").unwrap();
+        client
+    });
+    let mut buf = [0; 4];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"0123");
+    let mut buf = [0; 6];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"456789");
+    let mut client = writer.join().unwrap();
+
+    let reader = thread::spawn(move || {
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).unwrap();
+        buf
+    });
+    server.write_all(b"hello").unwrap();
+    assert_eq!(&reader.join().unwrap(), b"hello");
+
+    let name = r"\\.\pipe\test_zero_buffers_message_mode";
+    let server = PipeOptions::new(name)
+        .pipe_mode(PipeMode::Message)
+        .out_buffer(0)
+        .in_buffer(0)
+        .single()
+        .unwrap();
+    let mut client = PipeClient::connect(name).unwrap();
+    let mut server = server.wait().unwrap();
+    let writer = thread::spawn(move || client.write_all(b"hello").unwrap());
+    assert_eq!(server.read_message().unwrap(), b"hello");
+    writer.join().unwrap();
+}
+
+#[test]
+fn drop_pending_read_on_disconnected_pipe() {
+    let name = r"\\.\pipe\drop_pending_read_on_disconnected_pipe";
+    let server = PipeOptions::new(name).single().unwrap();
+    let mut client = PipeClient::connect(name).unwrap();
+    let server = server.wait().unwrap();
+
+    let mut buf = [0; 10];
+    let read_handle = unsafe { client.read_async(&mut buf).unwrap() };
+    let server = server.disconnect().unwrap();
+    drop(read_handle);
+    assert_eq!(buf, [0; 10]);
+
+    let client = PipeClient::connect(name).unwrap();
+    let server = server.wait().unwrap();
+    let read_handle = client.read_async_owned(vec![0; 10]).unwrap();
+    drop(server);
+    drop(read_handle);
+}
+
+#[test]
+fn cancel_pending_io() {
+    use std::io::{Read, Write};
+
+    let name = r"\\.\pipe\cancel_pending_io";
+    let server = PipeOptions::new(name).single().unwrap();
+    let client = PipeClient::connect(name).unwrap();
+    let server = server.wait().unwrap();
+
+    let read_handle = client.read_async_owned(vec![0; 4]).unwrap();
+    let (n, owned) = read_handle.cancel().unwrap();
+    assert_eq!(n, 0);
+    let (mut client, buf) = owned.unwrap();
+    assert_eq!(buf, vec![0; 4]);
+
+    // Nobody reads, so the write can't complete.
+    let write_handle = server.write_async_owned(vec![7; 1 << 22]).unwrap();
+    let (n, owned) = write_handle.cancel().unwrap();
+    assert!(n < 1 << 22);
+    let (mut server, buf) = owned.unwrap();
+    assert_eq!(buf.len(), 1 << 22);
+
+    // Pipe is still usable.
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0; 4];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[test]
+fn try_wait_pending_io() {
+    let name = r"\\.\pipe\try_wait_pending_io";
+    let mut connecting = PipeOptions::new(name).single().unwrap();
+    let client = PipeClient::connect(name).unwrap();
+    while !connecting.try_wait().unwrap() {
+        std::thread::yield_now();
+    }
+    let server = connecting.wait().unwrap();
+
+    let mut read_handle = client.read_async_owned(vec![0; 4]).unwrap();
+    assert_eq!(read_handle.try_wait().unwrap(), None);
+
+    let mut write_handle = server.write_async_owned(b"ping".to_vec()).unwrap();
+    while write_handle.try_wait().unwrap().is_none() {
+        std::thread::yield_now();
+    }
+    while read_handle.try_wait().unwrap().is_none() {
+        std::thread::yield_now();
+    }
+    assert_eq!(read_handle.try_wait().unwrap(), Some(4));
+
+    let (n, owned) = read_handle.wait().unwrap();
+    assert_eq!(n, 4);
+    assert_eq!(owned.unwrap().1, b"ping");
+    let (n, _) = write_handle.wait().unwrap();
+    assert_eq!(n, 4);
+}
+
+#[test]
+fn test_wait_all() {
+    use std::io::Write;
+    use std::time::Duration;
+
+    let name = r"\\.\pipe\test_wait_all";
+    let connecting = PipeOptions::new(name).multiple(2).unwrap();
+    let mut clients = (0..2)
+        .map(|_| PipeClient::connect(name).unwrap())
+        .collect::<Vec<_>>();
+    let mut servers = connecting
+        .into_iter()
+        .map(|server| server.wait().unwrap())
+        .collect::<Vec<_>>();
+
+    let mut bufs = vec![[0u8; 4]; 2];
+    {
+        let handles = servers
+            .iter_mut()
+            .zip(bufs.iter_mut())
+            .map(|(server, buf)| unsafe { server.read_async(buf).unwrap() })
+            .collect::<Vec<_>>();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(wait_timeout(&handles, timeout).unwrap(), None);
+
+        clients[1].write_all(b"pong").unwrap();
+        assert!(wait_timeout(&handles, timeout).unwrap().is_some());
+        assert!(!wait_all_timeout(&handles, timeout).unwrap());
+
+        clients[0].write_all(b"ping").unwrap();
+        wait_all(&handles).unwrap();
+        assert!(wait_all_timeout(&handles, timeout).unwrap());
+        for handle in handles {
+            assert_eq!(handle.wait().unwrap().0, 4);
+        }
+    }
+    bufs.sort();
+    assert_eq!(bufs, [*b"ping", *b"pong"]);
+}
+
+#[test]
+fn test_wait_set() {
+    use std::io::Write;
+    use std::time::Duration;
+
+    // More than `MAXIMUM_WAIT_OBJECTS` on Windows.
+    let mut servers = Vec::new();
+    let mut clients = Vec::new();
+    for i in 0..70 {
+        let name = format!(r"\\.\pipe\test_wait_set_{}", i);
+        let server = PipeOptions::new(&name).single().unwrap();
+        clients.push(PipeClient::connect(&name).unwrap());
+        servers.push(server.wait().unwrap());
+    }
+    let handles = clients
+        .into_iter()
+        .map(|client| client.read_async_owned(vec![0; 4]).unwrap())
+        .collect::<Vec<_>>();
+
+    let set = handles.iter().collect::<WaitSet<_>>();
+    assert_eq!(set.len(), 70);
+    let timeout = Some(Duration::from_millis(10));
+    assert_eq!(set.wait(timeout).unwrap(), Vec::<usize>::new());
+
+    servers[68].write_all(b"ping").unwrap();
+    servers[3].write_all(b"pong").unwrap();
+    let mut ready = set.wait(None).unwrap();
+    while ready.len() < 2 {
+        ready = set.wait(None).unwrap();
+    }
+    assert_eq!(ready, vec![3, 68]);
+    assert_eq!(wait(&handles).unwrap(), 3);
+    assert!(!wait_all_timeout(&handles, Duration::from_millis(10)).unwrap());
+
+    for server in servers.iter_mut() {
+        server.write_all(b"ping").unwrap();
+    }
+    wait_all(&handles).unwrap();
+}
+
+#[test]
+fn test_wait_set_tokens() {
+    use std::io::Write;
+    use std::time::Duration;
+
+    const ACCEPT: usize = 10;
+    const READ: usize = 20;
+    const WRITE: usize = 30;
+
+    let name = r"\\.\pipe\test_wait_set_tokens";
+    let accept_name = r"\\.\pipe\test_wait_set_tokens_accept";
+    let server = PipeOptions::new(name).single().unwrap();
+    let client = PipeClient::connect(name).unwrap();
+    let mut server = server.wait().unwrap();
+    let connecting = PipeOptions::new(accept_name).single().unwrap();
+    let read = client.read_async_owned(vec![0; 4]).unwrap();
+
+    let mut set: WaitSet = WaitSet::new();
+    set.insert(&connecting, ACCEPT);
+    set.insert(&read, READ);
+    let timeout = Some(Duration::from_millis(10));
+    assert_eq!(set.wait(timeout).unwrap(), Vec::<usize>::new());
+
+    let _accepted = PipeClient::connect(accept_name).unwrap();
+    assert_eq!(set.wait(None).unwrap(), vec![ACCEPT]);
+
+    server.write_all(b"ping").unwrap();
+    let mut ready = set.wait(None).unwrap();
+    while ready.len() < 2 {
+        ready = set.wait(None).unwrap();
+    }
+    assert_eq!(ready, vec![ACCEPT, READ]);
+
+    let write = server.write_async_owned(b"pong".to_vec()).unwrap();
+    set.clear();
+    set.insert(&write, WRITE);
+    assert_eq!(set.wait(None).unwrap(), vec![WRITE]);
+    drop(set);
+
+    let (_, owned) = read.wait().unwrap();
+    assert_eq!(owned.unwrap().1, b"ping");
+    write.wait().unwrap();
+    connecting.wait().unwrap();
+}
+
+#[test]
+fn test_wait_connecting() {
+    use std::time::Duration;
+
+    let name = r"\\.\pipe\test_wait_connecting";
+    let servers = PipeOptions::new(name).multiple(3).unwrap();
+    let _client = PipeClient::connect(name).unwrap();
+
+    // Only one instance gets the client.
+    let ready = servers.iter().collect::<WaitSet<_>>().wait(None).unwrap();
+    assert_eq!(ready.len(), 1);
+    let others = servers
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != ready[0])
+        .map(|(_, server)| server)
+        .collect::<WaitSet<_>>();
+    let timeout = Some(Duration::from_millis(10));
+    assert_eq!(others.wait(timeout).unwrap(), Vec::<usize>::new());
+    let server = servers.into_iter().nth(ready[0]).unwrap().wait().unwrap();
+    assert!(server.is_connected().unwrap());
+}
+
+#[test]
+#[allow(clippy::unused_io_amount)]
 fn cancel_io_clien_read_on_timeout() {
     use std::{
         io::{ErrorKind, Read, Write},
@@ -1587,6 +2662,7 @@ fn cancel_io_clien_read_on_timeout() {
 }
 
 #[test]
+#[allow(clippy::unused_io_amount)]
 fn cancel_io_server_read_on_timeout() {
     use std::{
         io::{ErrorKind, Read, Write},
@@ -1676,8 +2752,8 @@ fn cancel_io_server_write_on_timeout() {
     let mut client = PipeClient::connect(name).unwrap();
 
     thread::sleep(Duration::from_millis(100));
-    let err = client.read(&mut buf[..]).unwrap_err();
-    assert_eq!(err.raw_os_error().unwrap(), ERROR_PIPE_NOT_CONNECTED as i32);
+    // Disconnected pipe reads as end of stream.
+    assert_eq!(client.read(&mut buf[..]).unwrap(), 0);
 
     handle.join().unwrap();
 }
@@ -1721,11 +2797,12 @@ fn zero_timeout_read() {
     let mut line = String::new();
     loop {
         match reader.read_line(&mut line) {
+            // Server is disconnected.
+            Ok(0) => break,
             Ok(_) => line.clear(),
             Err(ref err) if err.kind() == ErrorKind::TimedOut => {
                 read_timeout.store(true, Ordering::Relaxed);
             },
-            Err(ref err) if err.raw_os_error() == Some(233) => break,
             Err(err) => panic!("Read error: {:?}", err),
         }
     }
@@ -0,0 +1,120 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `PipeListener` keeps a number of pipe instances waiting for clients.
+
+use std::ffi::OsStr;
+
+use crate::{wait, ConnectingServer, PipeError, PipeOptions, PipeServer};
+
+/// Server of a pipe that accepts clients like `std::net::TcpListener`.
+///
+/// Listener keeps a fixed number of instances waiting for clients and replaces each accepted
+/// instance with a new one, so clients don't get `ERROR_PIPE_BUSY` while the server is busy
+/// with a client.
+///
+/// ```no_run
+/// # use named_pipe::{PipeListener, PipeOptions};
+/// # use std::io::Write;
+/// let mut listener = PipeListener::new(&PipeOptions::new(r"\\.\pipe\listener"), 4).unwrap();
+/// for server in listener.incoming() {
+///     server.unwrap().write_all(b"hello").unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct PipeListener {
+    options: PipeOptions,
+    pending: Vec<ConnectingServer>,
+}
+
+impl PipeListener {
+    /// Creates `instances` instances of a pipe with `options`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `instances == 0`
+    pub fn new(options: &PipeOptions, instances: u32) -> Result<PipeListener, PipeError> {
+        assert!(instances > 0);
+
+        let pending = options.multiple(instances)?;
+        let mut options = options.clone();
+        options.first(false);
+        Ok(PipeListener { options, pending })
+    }
+
+    /// Waits for a client. Accepted instance is replaced with a new one before it's returned.
+    pub fn accept(&mut self) -> Result<PipeServer, PipeError> {
+        let index = wait(&self.pending)?;
+        let new_instance = self.options.single()?;
+        let connecting = std::mem::replace(&mut self.pending[index], new_instance);
+        connecting.wait()
+    }
+
+    /// Returns an iterator over clients. It never returns `None`.
+    pub fn incoming(&mut self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+
+    /// Returns number of instances waiting for clients.
+    pub fn instances(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn name(&self) -> &OsStr {
+        &self.options.name
+    }
+}
+
+/// Iterator over clients of a [`PipeListener`](struct.PipeListener.html), see
+/// `PipeListener::incoming`.
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a mut PipeListener,
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = Result<PipeServer, PipeError>;
+
+    fn next(&mut self) -> Option<Result<PipeServer, PipeError>> {
+        Some(self.listener.accept())
+    }
+}
+
+#[test]
+fn test_pipe_listener() {
+    use crate::PipeClient;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    for &name in &[r"\\.\pipe\test_pipe_listener", "mem://test_pipe_listener"] {
+        let mut listener = PipeListener::new(&PipeOptions::new(name), 2).unwrap();
+        assert_eq!(listener.name(), OsStr::new(name));
+        let clients = (0..5)
+            .map(|i| {
+                thread::spawn(move || {
+                    let mut client = PipeClient::connect(name).unwrap();
+                    client.write_all(&[i]).unwrap();
+                    let mut buf = [0; 1];
+                    client.read_exact(&mut buf).unwrap();
+                    assert_eq!(buf[0], i + 1);
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for server in listener.incoming().take(5) {
+            let mut server = server.unwrap();
+            let mut buf = [0; 1];
+            server.read_exact(&mut buf).unwrap();
+            server.write_all(&[buf[0] + 1]).unwrap();
+        }
+        for client in clients {
+            client.join().unwrap();
+        }
+        assert_eq!(listener.instances(), 2);
+    }
+}
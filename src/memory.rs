@@ -0,0 +1,1121 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! In-memory pipe transport.
+//!
+//! Pipes whose names start with [`SCHEME`](constant.SCHEME.html) (e.g. `mem://foo`) never touch
+//! the OS. Pipe instances are registered in a process-wide registry and data goes through bounded
+//! in-memory buffers, so code that takes `PipeServer`/`PipeClient` can be unit-tested without
+//! creating real pipes:
+//!
+//! ```
+//! # use named_pipe::{PipeClient, PipeOptions};
+//! # use std::io::{Read, Write};
+//! let server = PipeOptions::new("mem://doc").single().unwrap();
+//! let mut client = PipeClient::connect("mem://doc").unwrap();
+//! let mut server = server.wait().unwrap();
+//!
+//! client.write_all(b"ping").unwrap();
+//! let mut buf = [0; 4];
+//! server.read_exact(&mut buf).unwrap();
+//! assert_eq!(&buf, b"ping");
+//! ```
+//!
+//! Semantics follows Windows named pipes:
+//!
+//! - `in_buffer`/`out_buffer` bound the amount of data that may be written but not yet read. With
+//!   zero-sized buffer write completes only as the other side reads the data;
+//! - `PipeClient::connect` fails with `ErrorKind::NotFound` if there is no such pipe and waits
+//!   (up to the given timeout) if all instances are busy;
+//! - `PipeServer::disconnect` discards unread data and client reads end of stream, whereas
+//!   a dropped client is seen by the server as end of stream once its data is read;
+//! - in message mode every write is delivered as a single message.
+//!
+//! In-memory pipes can't be waited on together with OS pipes by the [`wait`](../fn.wait.html)
+//! function.
+
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::slice;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+use crate::sys::PeekInfo;
+use crate::{OpenMode, PipeMode, PipeOptions};
+
+/// Name prefix of in-memory pipes.
+pub const SCHEME: &str = "mem://";
+
+const INFINITE: u32 = 0xFFFFFFFF;
+
+static STATE: Mutex<State> = Mutex::new(State {
+    next_id: 0,
+    pipes: BTreeMap::new(),
+    connections: BTreeMap::new(),
+    wakers: BTreeMap::new(),
+});
+
+/// Notified on every change of the `STATE`, see `notify`.
+static CHANGED: Condvar = Condvar::new();
+
+fn lock() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Must be called on every change of the `STATE`.
+fn notify(state: &mut State) {
+    CHANGED.notify_all();
+    for (_, waker) in mem::take(&mut state.wakers) {
+        waker.wake();
+    }
+}
+
+/// Wakes all waits, so they could check whether they are interrupted.
+pub(crate) fn wake_all() {
+    notify(&mut lock());
+}
+
+fn wait_timeout(
+    state: MutexGuard<'static, State>,
+    deadline: Option<Instant>,
+) -> Option<MutexGuard<'static, State>> {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            let result = CHANGED.wait_timeout(state, deadline - now);
+            Some(result.unwrap_or_else(|err| err.into_inner()).0)
+        }
+        None => Some(CHANGED.wait(state).unwrap_or_else(|err| err.into_inner())),
+    }
+}
+
+fn deadline(timeout: u32) -> Option<Instant> {
+    if timeout == INFINITE {
+        None
+    } else {
+        Some(Instant::now() + Duration::from_millis(timeout as u64))
+    }
+}
+
+pub(crate) fn is_memory_name(name: &OsStr) -> bool {
+    name.to_str().is_some_and(|name| name.starts_with(SCHEME))
+}
+
+fn broken_pipe() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the pipe has been ended")
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        "no process is on the other end of the pipe",
+    )
+}
+
+fn access_denied() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "access is denied")
+}
+
+pub(crate) fn is_broken_pipe(err: &io::Error) -> bool {
+    err.raw_os_error().is_none() && err.kind() == io::ErrorKind::BrokenPipe
+}
+
+pub(crate) fn is_disconnected(err: &io::Error) -> bool {
+    is_broken_pipe(err)
+        || (err.raw_os_error().is_none() && err.kind() == io::ErrorKind::NotConnected)
+}
+
+/// Error of a read that didn't consume the whole message (analog of `ERROR_MORE_DATA`).
+#[derive(Debug)]
+struct MoreData;
+
+impl fmt::Display for MoreData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("more data is available")
+    }
+}
+
+impl Error for MoreData {}
+
+fn more_data() -> io::Error {
+    io::Error::other(MoreData)
+}
+
+pub(crate) fn is_more_data(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<MoreData>())
+}
+
+#[derive(Debug)]
+struct Channel {
+    data: VecDeque<u8>,
+    /// Lengths of messages stored in `data`. `None` for byte-mode pipes.
+    messages: Option<VecDeque<usize>>,
+    capacity: usize,
+    /// Size of a pending read, if any. Writer is allowed to exceed `capacity` up to this value.
+    demand: usize,
+}
+
+impl Channel {
+    fn new(capacity: u32, pipe_mode: PipeMode) -> Channel {
+        Channel {
+            data: VecDeque::new(),
+            messages: match pipe_mode {
+                PipeMode::Byte => None,
+                PipeMode::Message => Some(VecDeque::new()),
+            },
+            capacity: capacity as usize,
+            demand: 0,
+        }
+    }
+
+    fn space(&self) -> usize {
+        cmp::max(self.capacity, self.demand).saturating_sub(self.data.len())
+    }
+
+    fn is_empty(&self) -> bool {
+        match self.messages {
+            Some(ref messages) => messages.is_empty(),
+            None => self.data.is_empty(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+        if let Some(ref mut messages) = self.messages {
+            messages.clear();
+        }
+    }
+
+    /// Writes as much of `data` as possible. Message is written either whole or not at all,
+    /// but it may exceed the buffer if the channel is empty and there is a pending read. Returns
+    /// `None` if nothing was written (an empty message counts as written).
+    fn write(&mut self, data: &[u8]) -> Option<usize> {
+        let space = self.space();
+        let count = match self.messages {
+            Some(ref mut messages) => {
+                let fits = data.len() <= space || (self.data.is_empty() && self.demand > 0);
+                if !fits {
+                    return None;
+                }
+                messages.push_back(data.len());
+                data.len()
+            }
+            None if space == 0 || data.is_empty() => return None,
+            None => cmp::min(space, data.len()),
+        };
+        self.data.extend(data[..count].iter());
+        Some(count)
+    }
+
+    /// Reads up to `buf.len()` bytes. Returns `more_data` error if message didn't fit.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (count, rest) = match self.messages {
+            Some(ref mut messages) => match messages.front_mut() {
+                Some(message) => {
+                    let count = cmp::min(buf.len(), *message);
+                    *message -= count;
+                    if *message == 0 {
+                        messages.pop_front();
+                        (count, false)
+                    } else {
+                        (count, true)
+                    }
+                }
+                None => (0, false),
+            },
+            None => (cmp::min(buf.len(), self.data.len()), false),
+        };
+        for (dst, byte) in buf.iter_mut().zip(self.data.drain(..count)) {
+            *dst = byte;
+        }
+        if rest {
+            Err(more_data())
+        } else {
+            Ok(count)
+        }
+    }
+
+    /// Copies up to `buf.len()` bytes without removing them. Returns number of copied bytes and
+    /// number of bytes left in the current message.
+    fn peek(&self, buf: &mut [u8]) -> (usize, usize) {
+        let (count, left) = match self.messages {
+            Some(ref messages) => {
+                let message = messages.front().copied().unwrap_or(0);
+                let count = cmp::min(buf.len(), message);
+                (count, message - count)
+            }
+            None => (cmp::min(buf.len(), self.data.len()), 0),
+        };
+        for (dst, byte) in buf[..count].iter_mut().zip(self.data.iter()) {
+            *dst = *byte;
+        }
+        (count, left)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum ServerSide {
+    Connected,
+    /// Server called `DisconnectNamedPipe`.
+    Disconnected,
+    /// Server handle is closed.
+    Closed,
+}
+
+#[derive(Debug)]
+struct Connection {
+    to_server: Channel,
+    to_client: Channel,
+    open_mode: OpenMode,
+    server: ServerSide,
+    client: bool,
+}
+
+impl Connection {
+    fn is_orphan(&self) -> bool {
+        self.server != ServerSide::Connected && !self.client
+    }
+
+    /// Returns channel that server (or client) reads from and `true` if the other side is
+    /// still connected.
+    fn incoming(&mut self, server: bool) -> io::Result<(&mut Channel, bool)> {
+        let (allowed, peer) = match server {
+            true => (self.open_mode != OpenMode::Write, self.client),
+            false => match self.server {
+                ServerSide::Disconnected => return Err(not_connected()),
+                side => (
+                    self.open_mode != OpenMode::Read,
+                    side == ServerSide::Connected,
+                ),
+            },
+        };
+        if !allowed {
+            return Err(access_denied());
+        }
+        match server {
+            true => Ok((&mut self.to_server, peer)),
+            false => Ok((&mut self.to_client, peer)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Instance {
+    listening: bool,
+    connection: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Pipe {
+    open_mode: OpenMode,
+    pipe_mode: PipeMode,
+    in_buffer: u32,
+    out_buffer: u32,
+    instances: BTreeMap<u64, Instance>,
+}
+
+#[derive(Debug)]
+struct State {
+    next_id: u64,
+    pipes: BTreeMap<OsString, Pipe>,
+    connections: BTreeMap<u64, Connection>,
+    /// Tasks to wake on the next change, see `register_waker`.
+    wakers: BTreeMap<u64, Waker>,
+}
+
+impl State {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn instance(&mut self, name: &OsStr, id: u64) -> Option<&mut Instance> {
+        self.pipes
+            .get_mut(name)
+            .and_then(|pipe| pipe.instances.get_mut(&id))
+    }
+
+    /// Returns connection of an endpoint and `true` if endpoint is a server.
+    fn connection(&mut self, endpoint: &Endpoint) -> io::Result<(&mut Connection, bool)> {
+        match *endpoint {
+            Endpoint::Server { ref name, id } => {
+                let connection = self.instance(name, id).and_then(|x| x.connection);
+                match connection.and_then(move |id| self.connections.get_mut(&id)) {
+                    Some(connection) => Ok((connection, true)),
+                    None => Err(not_connected()),
+                }
+            }
+            Endpoint::Client { connection } => match self.connections.get_mut(&connection) {
+                Some(connection) => Ok((connection, false)),
+                None => Err(not_connected()),
+            },
+        }
+    }
+
+    fn disconnect(&mut self, name: &OsStr, id: u64, side: ServerSide) {
+        let connection = self.instance(name, id).and_then(|x| x.connection.take());
+        if let Some(id) = connection {
+            let orphan = match self.connections.get_mut(&id) {
+                Some(connection) => {
+                    connection.server = side;
+                    if side == ServerSide::Disconnected {
+                        connection.to_client.clear();
+                        connection.to_server.clear();
+                    }
+                    connection.is_orphan()
+                }
+                None => false,
+            };
+            if orphan {
+                self.connections.remove(&id);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Endpoint {
+    Server { name: OsString, id: u64 },
+    Client { connection: u64 },
+}
+
+#[derive(Debug)]
+pub(crate) struct Handle {
+    endpoint: Endpoint,
+    closer: Arc<Closer>,
+}
+
+impl Handle {
+    fn new(endpoint: Endpoint) -> Handle {
+        Handle {
+            closer: Arc::new(Closer(endpoint.clone())),
+            endpoint,
+        }
+    }
+}
+
+/// Closes the endpoint once the last of its handles (see `duplicate_handle`) is dropped.
+#[derive(Debug)]
+struct Closer(Endpoint);
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        let mut state = lock();
+        match self.0 {
+            Endpoint::Server { ref name, id } => {
+                state.disconnect(name, id, ServerSide::Closed);
+                let unused = match state.pipes.get_mut(name) {
+                    Some(pipe) => {
+                        pipe.instances.remove(&id);
+                        pipe.instances.is_empty()
+                    }
+                    None => false,
+                };
+                if unused {
+                    state.pipes.remove(name);
+                }
+            }
+            Endpoint::Client { connection } => {
+                let orphan = match state.connections.get_mut(&connection) {
+                    Some(connection) => {
+                        connection.client = false;
+                        connection.is_orphan()
+                    }
+                    None => false,
+                };
+                if orphan {
+                    state.connections.remove(&connection);
+                }
+            }
+        }
+        notify(&mut state);
+    }
+}
+
+#[derive(Debug)]
+enum Op {
+    Idle,
+    Connect,
+    Read {
+        buf: *mut u8,
+        len: usize,
+    },
+    Write {
+        buf: *const u8,
+        len: usize,
+        done: usize,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) struct Overlapped {
+    endpoint: Endpoint,
+    op: Op,
+    signaled: bool,
+    result: Option<io::Result<u32>>,
+}
+
+unsafe impl Send for Overlapped {}
+unsafe impl Sync for Overlapped {}
+
+impl Overlapped {
+    pub(crate) fn new(handle: &Handle) -> Overlapped {
+        Overlapped {
+            endpoint: handle.endpoint.clone(),
+            op: Op::Idle,
+            signaled: false,
+            result: None,
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.op = Op::Idle;
+        self.signaled = false;
+        self.result = None;
+    }
+
+    fn start(&mut self, op: Op) {
+        self.op = op;
+        self.signaled = false;
+        self.result = None;
+    }
+
+    fn complete(&mut self, result: io::Result<u32>) -> bool {
+        self.op = Op::Idle;
+        self.signaled = true;
+        self.result = Some(result);
+        true
+    }
+
+    /// Tries to make progress. Returns `true` once operation is completed.
+    fn progress(&mut self, state: &mut State) -> bool {
+        match self.op {
+            Op::Idle => self.signaled,
+            Op::Connect => match self.endpoint {
+                Endpoint::Server { ref name, id } => match state.instance(name, id) {
+                    Some(instance) if instance.connection.is_some() => self.complete(Ok(0)),
+                    Some(_) => false,
+                    None => self.complete(Err(not_connected())),
+                },
+                Endpoint::Client { .. } => unreachable!(),
+            },
+            Op::Read { buf, len } => {
+                let incoming = state
+                    .connection(&self.endpoint)
+                    .and_then(|(connection, server)| connection.incoming(server));
+                let (channel, peer) = match incoming {
+                    Ok(x) => x,
+                    Err(err) => return self.complete(Err(err)),
+                };
+                if !channel.is_empty() || len == 0 {
+                    let buf = unsafe { slice::from_raw_parts_mut(buf, len) };
+                    let result = channel.read(buf);
+                    channel.demand = 0;
+                    notify(state);
+                    self.complete(result.map(|count| count as u32))
+                } else if !peer {
+                    self.complete(Err(broken_pipe()))
+                } else {
+                    if channel.demand != len {
+                        channel.demand = len;
+                        notify(state);
+                    }
+                    false
+                }
+            }
+            Op::Write {
+                buf,
+                len,
+                ref mut done,
+            } => {
+                let (connection, server) = match state.connection(&self.endpoint) {
+                    Ok(x) => x,
+                    Err(err) => return self.complete(Err(err)),
+                };
+                let (allowed, peer) = match server {
+                    true => (connection.open_mode != OpenMode::Read, connection.client),
+                    false => match connection.server {
+                        ServerSide::Disconnected => return self.complete(Err(not_connected())),
+                        side => (
+                            connection.open_mode != OpenMode::Write,
+                            side == ServerSide::Connected,
+                        ),
+                    },
+                };
+                if !allowed {
+                    return self.complete(Err(access_denied()));
+                }
+                if !peer {
+                    return self.complete(Err(broken_pipe()));
+                }
+
+                let channel = match server {
+                    true => &mut connection.to_client,
+                    false => &mut connection.to_server,
+                };
+                let data = unsafe { slice::from_raw_parts(buf.add(*done), len - *done) };
+                if let Some(count) = channel.write(data) {
+                    *done += count;
+                    notify(state);
+                }
+                if *done == len {
+                    self.complete(Ok(len as u32))
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if `progress` is able to do something.
+    fn is_ready(&self, state: &mut State) -> bool {
+        match self.op {
+            Op::Idle => self.signaled,
+            Op::Connect => match self.endpoint {
+                Endpoint::Server { ref name, id } => state
+                    .instance(name, id)
+                    .is_none_or(|instance| instance.connection.is_some()),
+                Endpoint::Client { .. } => unreachable!(),
+            },
+            Op::Read { .. } => match state.connection(&self.endpoint) {
+                Ok((connection, true)) => !connection.to_server.is_empty() || !connection.client,
+                Ok((connection, false)) => {
+                    !connection.to_client.is_empty() || connection.server != ServerSide::Connected
+                }
+                Err(_) => true,
+            },
+            Op::Write { .. } => match state.connection(&self.endpoint) {
+                Ok((connection, true)) => connection.to_client.space() > 0 || !connection.client,
+                Ok((connection, false)) => {
+                    connection.to_server.space() > 0 || connection.server != ServerSide::Connected
+                }
+                Err(_) => true,
+            },
+        }
+    }
+
+    /// Cancels pending operation, so it is safe to deallocate the buffer. Returns number of bytes
+    /// transferred before the cancellation.
+    fn cancel(&mut self, state: &mut State) -> u32 {
+        let count = match self.op {
+            Op::Read { .. } => {
+                if let Ok((connection, server)) = state.connection(&self.endpoint) {
+                    match server {
+                        true => connection.to_server.demand = 0,
+                        false => connection.to_client.demand = 0,
+                    }
+                }
+                0
+            }
+            Op::Write { done, .. } => done as u32,
+            _ => match self.result {
+                Some(Ok(count)) => count,
+                _ => 0,
+            },
+        };
+        self.op = Op::Idle;
+        self.result = None;
+        count
+    }
+}
+
+pub(crate) fn create_named_pipe(options: &PipeOptions, first: bool) -> io::Result<Handle> {
+    let mut state = lock();
+    let id = state.next_id();
+    match state.pipes.get_mut(options.name.as_os_str()) {
+        Some(_) if first => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "pipe instance already exists",
+            ))
+        }
+        Some(pipe) => {
+            pipe.instances.insert(id, Instance::default());
+        }
+        None => {
+            let mut pipe = Pipe {
+                open_mode: options.open_mode.clone(),
+                pipe_mode: options.pipe_mode,
+                in_buffer: options.in_buffer,
+                out_buffer: options.out_buffer,
+                instances: BTreeMap::new(),
+            };
+            pipe.instances.insert(id, Instance::default());
+            state.pipes.insert((*options.name).clone(), pipe);
+        }
+    }
+    Ok(Handle::new(Endpoint::Server {
+        name: (*options.name).clone(),
+        id,
+    }))
+}
+
+pub(crate) fn duplicate_handle(handle: &Handle) -> Handle {
+    Handle {
+        endpoint: handle.endpoint.clone(),
+        closer: handle.closer.clone(),
+    }
+}
+
+/// Returns `Ok(true)` if connection is pending or `Ok(false)` if pipe is connected.
+pub(crate) fn connect_named_pipe(handle: &Handle, ovl: &mut Overlapped) -> io::Result<bool> {
+    let mut state = lock();
+    if let Endpoint::Server { ref name, id } = handle.endpoint {
+        if let Some(instance) = state.instance(name, id) {
+            instance.listening = instance.connection.is_none();
+            notify(&mut state);
+        }
+    }
+    ovl.start(Op::Connect);
+    if ovl.progress(&mut state) {
+        ovl.result.take().unwrap().map(|_| false)
+    } else {
+        Ok(true)
+    }
+}
+
+pub(crate) fn disconnect_named_pipe(handle: &Handle) -> io::Result<()> {
+    if let Endpoint::Server { ref name, id } = handle.endpoint {
+        let mut state = lock();
+        state.disconnect(name, id, ServerSide::Disconnected);
+        notify(&mut state);
+    }
+    Ok(())
+}
+
+/// Waits until the other side reads all the data written into the pipe.
+pub(crate) fn flush_file_buffers(handle: &Handle) -> io::Result<()> {
+    let mut state = lock();
+    loop {
+        let flushed = match state.connection(&handle.endpoint) {
+            Ok((connection, true)) => connection.to_client.is_empty() || !connection.client,
+            Ok((connection, false)) => {
+                connection.to_server.is_empty() || connection.server != ServerSide::Connected
+            }
+            Err(_) => true,
+        };
+        if flushed {
+            return Ok(());
+        }
+        state = wait_timeout(state, None).unwrap();
+    }
+}
+
+/// Connects to a listening instance waiting until `deadline` (`None` stands for infinite
+/// waiting).
+pub(crate) fn open_pipe(name: &OsStr, deadline: Option<Instant>) -> io::Result<Handle> {
+    let mut state = lock();
+    loop {
+        let id = state.next_id();
+        let pipe = match state.pipes.get_mut(name) {
+            Some(pipe) => pipe,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "the system cannot find the pipe specified",
+                ))
+            }
+        };
+
+        let listening = pipe.instances.values_mut().find(|x| x.listening);
+        if let Some(instance) = listening {
+            instance.listening = false;
+            instance.connection = Some(id);
+            let connection = Connection {
+                to_server: Channel::new(pipe.in_buffer, pipe.pipe_mode),
+                to_client: Channel::new(pipe.out_buffer, pipe.pipe_mode),
+                open_mode: pipe.open_mode.clone(),
+                server: ServerSide::Connected,
+                client: true,
+            };
+            state.connections.insert(id, connection);
+            notify(&mut state);
+            return Ok(Handle::new(Endpoint::Client { connection: id }));
+        }
+
+        state = match wait_timeout(state, deadline) {
+            Some(state) => state,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    "all pipe instances are busy",
+                ))
+            }
+        };
+    }
+}
+
+/// Copies data from the pipe into `buf` without removing it.
+pub(crate) fn peek_named_pipe(handle: &Handle, buf: &mut [u8]) -> io::Result<PeekInfo> {
+    let mut state = lock();
+    let (connection, server) = state.connection(&handle.endpoint)?;
+    let (channel, peer) = connection.incoming(server)?;
+    if channel.is_empty() && !peer {
+        return Err(broken_pipe());
+    }
+    let (count, left) = channel.peek(buf);
+    Ok(PeekInfo {
+        bytes_read: count as u32,
+        bytes_available: channel.data.len() as u32,
+        message_bytes_left: left as u32,
+    })
+}
+
+/// Starts read. Returns `Ok(Some(bytes_read))` if operation was completed synchronously and
+/// `Ok(None)` if it is pending.
+///
+/// # Unsafety
+/// `buf` must stay valid until operation is completed or cancelled.
+pub(crate) unsafe fn read_file(
+    buf: *mut u8,
+    len: u32,
+    ovl: &mut Overlapped,
+) -> io::Result<Option<u32>> {
+    let mut state = lock();
+    ovl.start(Op::Read {
+        buf,
+        len: len as usize,
+    });
+    if ovl.progress(&mut state) {
+        ovl.result.take().unwrap().map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Starts write. Returns `Ok(Some(bytes_written))` if operation was completed synchronously and
+/// `Ok(None)` if it is pending.
+///
+/// # Unsafety
+/// `buf` must stay valid until operation is completed or cancelled.
+pub(crate) unsafe fn write_file(
+    buf: *const u8,
+    len: u32,
+    ovl: &mut Overlapped,
+) -> io::Result<Option<u32>> {
+    let mut state = lock();
+    ovl.start(Op::Write {
+        buf,
+        len: len as usize,
+        done: 0,
+    });
+    if ovl.progress(&mut state) {
+        ovl.result.take().unwrap().map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Waits for completion of an operation and returns number of bytes transferred.
+pub(crate) fn get_overlapped_result(ovl: &mut Overlapped) -> io::Result<u32> {
+    let mut state = lock();
+    loop {
+        if let Some(result) = ovl.result.take() {
+            return result;
+        }
+        if let Op::Idle = ovl.op {
+            return Ok(0);
+        }
+        if !ovl.progress(&mut state) {
+            state = wait_timeout(state, None).unwrap();
+        }
+    }
+}
+
+/// Cancels pending operation. Returns number of bytes transferred before the cancellation.
+pub(crate) fn cancel_io(ovl: &mut Overlapped) -> io::Result<u32> {
+    let mut state = lock();
+    let count = ovl.cancel(&mut state);
+    notify(&mut state);
+    Ok(count)
+}
+
+/// Returns `Ok(true)` if operation was completed and `Ok(false)` on timeout or once
+/// `interrupted` returns `true` (see `wake_all`).
+pub(crate) fn wait_for_single(
+    ovl: &mut Overlapped,
+    timeout: u32,
+    interrupted: &dyn Fn() -> bool,
+) -> io::Result<bool> {
+    let deadline = deadline(timeout);
+    let mut state = lock();
+    loop {
+        if ovl.signaled || ovl.progress(&mut state) {
+            return Ok(true);
+        }
+        if interrupted() {
+            return Ok(false);
+        }
+        state = match wait_timeout(state, deadline) {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+    }
+}
+
+/// Waits for readiness of operations. Completed operations are always ready. Returns `Ok(None)`
+/// on timeout or once `interrupted` returns `true`.
+pub(crate) fn wait_for_multiple(
+    list: &[&Overlapped],
+    all: bool,
+    timeout: u32,
+    interrupted: &dyn Fn() -> bool,
+) -> io::Result<Option<usize>> {
+    if list.is_empty() {
+        return Ok(None);
+    }
+
+    let deadline = deadline(timeout);
+    let mut state = lock();
+    loop {
+        let ready = list
+            .iter()
+            .map(|ovl| ovl.is_ready(&mut state))
+            .collect::<Vec<_>>();
+        if all && ready.iter().all(|x| *x) {
+            return Ok(Some(0));
+        }
+        if !all {
+            if let Some(i) = ready.iter().position(|x| *x) {
+                return Ok(Some(i));
+            }
+        }
+        if interrupted() {
+            return Ok(None);
+        }
+        state = match wait_timeout(state, deadline) {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+    }
+}
+
+/// Registration of a waker, see `register_waker`.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        lock().wakers.remove(&self.id);
+    }
+}
+
+/// Wakes `waker` once, when operation may be ready.
+pub(crate) fn register_waker(ovl: &Overlapped, waker: Waker) -> Registration {
+    let mut state = lock();
+    let id = state.next_id();
+    if ovl.is_ready(&mut state) {
+        waker.wake();
+    } else {
+        state.wakers.insert(id, waker);
+    }
+    Registration { id }
+}
+
+/// Waits for readiness of any operation and returns indexes of all ready operations (empty on
+/// timeout or once `interrupted` returns `true`).
+pub(crate) fn wait_for_ready(
+    list: &[&Overlapped],
+    timeout: u32,
+    interrupted: &dyn Fn() -> bool,
+) -> io::Result<Vec<usize>> {
+    if list.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let deadline = deadline(timeout);
+    let mut state = lock();
+    loop {
+        let ready = (0..list.len())
+            .filter(|i| list[*i].is_ready(&mut state))
+            .collect::<Vec<_>>();
+        if !ready.is_empty() || interrupted() {
+            return Ok(ready);
+        }
+        state = match wait_timeout(state, deadline) {
+            Some(state) => state,
+            None => return Ok(ready),
+        };
+    }
+}
+
+#[test]
+fn test_memory_io() {
+    use crate::{wait, PipeClient};
+    use std::io::{Read, Write};
+    use std::thread;
+
+    let servers = PipeOptions::new("mem://test_memory_io")
+        .multiple(4)
+        .unwrap();
+    let t1 = thread::spawn(move || {
+        for i in 0..4u8 {
+            let mut buf = [0; 4];
+            let mut client = PipeClient::connect("mem://test_memory_io").unwrap();
+            client.write_all(&[i; 10]).unwrap();
+            client.read_exact(&mut buf).unwrap();
+            assert_eq!(b"done", &buf[..]);
+        }
+    });
+
+    let mut servers = servers;
+    let mut seen = Vec::new();
+    while !servers.is_empty() {
+        let mut buf = [0; 10];
+        let which = wait(servers.as_ref()).unwrap();
+        let mut server = servers.remove(which).wait().unwrap();
+        server.read_exact(&mut buf).unwrap();
+        server.write_all(b"done").unwrap();
+        seen.push(buf[0]);
+    }
+    assert_eq!(seen, vec![0, 1, 2, 3]);
+
+    t1.join().unwrap();
+}
+
+#[test]
+fn test_memory_not_found_and_busy() {
+    use crate::{Operation, PipeClient, PipeError};
+
+    let err = PipeClient::connect("mem://test_memory_not_found").unwrap_err();
+    assert!(matches!(err, PipeError::NotFound(_)));
+    assert_eq!(err.operation(), Operation::Connect);
+    assert_eq!(err.name(), Some(OsStr::new("mem://test_memory_not_found")));
+
+    let server = PipeOptions::new("mem://test_memory_busy").single().unwrap();
+    let _client = PipeClient::connect("mem://test_memory_busy").unwrap();
+    let err = PipeClient::connect_ms("mem://test_memory_busy", 10).unwrap_err();
+    assert!(matches!(err, PipeError::Busy(_)));
+
+    let err = PipeOptions::new("mem://test_memory_busy")
+        .single()
+        .unwrap_err();
+    assert!(matches!(err, PipeError::AlreadyExists(_)));
+    assert_eq!(err.operation(), Operation::Create);
+    drop(server);
+}
+
+#[test]
+fn test_memory_zero_buffer_write_timeout() {
+    use crate::PipeClient;
+    use std::io::{ErrorKind, Read, Write};
+    use std::thread;
+
+    let name = "mem://test_memory_zero_buffer_write_timeout";
+    let server = PipeOptions::new(name)
+        .out_buffer(0)
+        .in_buffer(0)
+        .single()
+        .unwrap();
+
+    let handle = thread::spawn(move || {
+        let mut client = PipeClient::connect(name).unwrap();
+        client.set_write_timeout(Some(Duration::from_millis(10)));
+        let err = client.write(b"0123456789").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        thread::sleep(Duration::from_millis(100 * 2));
+    });
+
+    let mut server = server.wait().unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let mut buf = [0; 10];
+    let n = server.read(&mut buf[..]).unwrap();
+    assert_eq!(n, 0);
+    assert_eq!(buf, [0; 10]);
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_memory_disconnect() {
+    use crate::PipeClient;
+    use std::io::{ErrorKind, Read, Write};
+
+    let name = "mem://test_memory_disconnect";
+    let server = PipeOptions::new(name).single().unwrap();
+    let mut client = PipeClient::connect(name).unwrap();
+    let mut server = server.wait().unwrap();
+
+    server.write_all(b"0123456789").unwrap();
+    let mut buf = [0; 10];
+    client.read_exact(&mut buf).unwrap();
+
+    let server = server.disconnect().unwrap();
+    assert_eq!(client.read(&mut buf).unwrap(), 0);
+    let err = client.write(b"data").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    drop(client);
+
+    let mut client = PipeClient::connect(name).unwrap();
+    let mut server = server.wait().unwrap();
+    client.write_all(b"01234").unwrap();
+    drop(client);
+
+    let mut buf = Vec::new();
+    server.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"01234");
+}
+
+#[test]
+fn test_memory_message_mode() {
+    use crate::{MessageRead, PipeClient, PipeMode};
+    use std::io::Write;
+    use std::thread;
+
+    let name = "mem://test_memory_message_mode";
+    let server = PipeOptions::new(name)
+        .pipe_mode(PipeMode::Message)
+        .out_buffer(0)
+        .single()
+        .unwrap();
+    let mut client = PipeClient::connect(name).unwrap();
+    let mut server = server.wait().unwrap();
+
+    client.write_all(b"hello").unwrap();
+    assert_eq!(client.write(b"").unwrap(), 0);
+    client.write_all(b"world!!").unwrap();
+    assert_eq!(server.read_message().unwrap(), b"hello");
+    assert_eq!(server.read_message().unwrap(), b"");
+    let mut buf = [0; 4];
+    let result = server.read_message_part(&mut buf).unwrap();
+    assert_eq!(result, MessageRead::Partial(4));
+    assert_eq!(&buf, b"worl");
+    let result = server.read_message_part(&mut buf).unwrap();
+    assert_eq!(result, MessageRead::Complete(3));
+
+    // Message is larger than zero-sized buffer of the server.
+    let handle = thread::spawn(move || {
+        server.write_all(&[7; 10000]).unwrap();
+        server
+    });
+    assert_eq!(client.read_message().unwrap(), vec![7; 10000]);
+    let mut server = handle.join().unwrap();
+
+    // Empty message wakes up a blocked reader before its timeout.
+    client.set_read_timeout(Some(Duration::from_secs(5)));
+    let handle = thread::spawn(move || {
+        let start = Instant::now();
+        (client.read_message().unwrap(), start.elapsed())
+    });
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(server.write(b"").unwrap(), 0);
+    let (message, elapsed) = handle.join().unwrap();
+    assert_eq!(message, b"");
+    assert!(elapsed < Duration::from_secs(5));
+}
@@ -0,0 +1,232 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::PipeError;
+
+#[cfg(unix)]
+use crate::sys;
+#[cfg(unix)]
+use std::{env, path::PathBuf};
+
+/// Maximum length of the entire pipe name string.
+const MAX_NAME_LEN: usize = 256;
+
+/// Validated pipe name of the form `\\<server>\pipe\<name>`, where server is `.` for the local
+/// machine.
+///
+/// ```
+/// # use named_pipe::PipeName;
+/// let name: PipeName = r"\\.\pipe\my_pipe".parse().unwrap();
+/// assert!(name.is_local());
+/// assert_eq!(name.local_name(), "my_pipe");
+/// assert_eq!(name, PipeName::local("my_pipe").unwrap());
+///
+/// assert!(PipeName::new(r"\\.\pipe\").is_err());
+/// ```
+///
+/// `PipeName` can be passed wherever a pipe name is expected, e.g. to `PipeOptions::new` or
+/// `PipeClient::connect`.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct PipeName {
+    name: OsString,
+    server: String,
+    local_name: String,
+}
+
+impl PipeName {
+    /// Parses `\\<server>\pipe\<name>`. Prefix is case-insensitive.
+    pub fn new<T: AsRef<OsStr>>(name: T) -> Result<PipeName, PipeError> {
+        let name = name.as_ref();
+        let full = name
+            .to_str()
+            .ok_or_else(|| PipeError::invalid_name(name, "not a valid unicode"))?;
+        let rest = full
+            .strip_prefix(r"\\")
+            .ok_or_else(|| PipeError::invalid_name(name, r"must start with `\\`"))?;
+        let (server, rest) = match rest.find('\\') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => {
+                return Err(PipeError::invalid_name(
+                    name,
+                    r"must be of the form `\\server\pipe\name`",
+                ))
+            }
+        };
+        if server.is_empty() {
+            return Err(PipeError::invalid_name(name, "server name is empty"));
+        }
+        let local_name = match rest.get(..5) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(r"pipe\") => &rest[5..],
+            _ => {
+                return Err(PipeError::invalid_name(
+                    name,
+                    r"must be of the form `\\server\pipe\name`",
+                ))
+            }
+        };
+        if local_name.is_empty() {
+            return Err(PipeError::invalid_name(name, "name is empty"));
+        }
+        if let Some(c) = local_name.chars().find(|&c| c == '\\' || c == '\0') {
+            return Err(PipeError::invalid_name(
+                name,
+                &format!("invalid character {:?}", c),
+            ));
+        }
+        if full.encode_utf16().count() > MAX_NAME_LEN {
+            return Err(PipeError::invalid_name(
+                name,
+                &format!("longer than {} characters", MAX_NAME_LEN),
+            ));
+        }
+        Ok(PipeName {
+            name: name.to_os_string(),
+            server: server.into(),
+            local_name: local_name.into(),
+        })
+    }
+
+    /// Creates name of a pipe on the local machine, i.e. `\\.\pipe\<local_name>`.
+    pub fn local<T: AsRef<str>>(local_name: T) -> Result<PipeName, PipeError> {
+        PipeName::new(format!(r"\\.\pipe\{}", local_name.as_ref()))
+    }
+
+    /// Server part of the name (`.` for the local machine).
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    /// Name of the pipe on the server.
+    pub fn local_name(&self) -> &str {
+        &self.local_name
+    }
+
+    /// Is this pipe on the local machine?
+    pub fn is_local(&self) -> bool {
+        self.server == "."
+    }
+
+    pub fn as_os_str(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// Path of the Unix domain socket backing this pipe.
+    ///
+    /// Socket is placed into `$XDG_RUNTIME_DIR` or into the temporary directory if it's not set.
+    /// Local name is percent-encoded: `/`, `%` and a leading `.` are escaped, so every name is
+    /// mapped to its own file in that directory. Fails with `PipeError::InvalidName` if the path
+    /// doesn't fit into a socket address (107 bytes on Linux).
+    #[cfg(unix)]
+    pub fn socket_path(&self) -> Result<PathBuf, PipeError> {
+        let dir = env::var_os("XDG_RUNTIME_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir);
+        let mut file_name = String::with_capacity(self.local_name.len());
+        for (i, c) in self.local_name.char_indices() {
+            match c {
+                '/' => file_name.push_str("%2F"),
+                '%' => file_name.push_str("%25"),
+                '.' if i == 0 => file_name.push_str("%2E"),
+                c => file_name.push(c),
+            }
+        }
+        let path = dir.join(file_name);
+        sys::check_socket_path(&path)
+            .map_err(|err| PipeError::invalid_name(&self.name, &err.to_string()))?;
+        Ok(path)
+    }
+}
+
+impl AsRef<OsStr> for PipeName {
+    fn as_ref(&self) -> &OsStr {
+        &self.name
+    }
+}
+
+impl FromStr for PipeName {
+    type Err = PipeError;
+
+    fn from_str(s: &str) -> Result<PipeName, PipeError> {
+        PipeName::new(s)
+    }
+}
+
+impl fmt::Display for PipeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, r"\\{}\pipe\{}", self.server, self.local_name)
+    }
+}
+
+#[test]
+fn test_pipe_name_parse() {
+    let name = PipeName::new(r"\\.\PIPE\foo/bar").unwrap();
+    assert_eq!(name.server(), ".");
+    assert_eq!(name.local_name(), "foo/bar");
+    assert!(name.is_local());
+
+    let name = PipeName::new(r"\\server\pipe\foo").unwrap();
+    assert_eq!(name.server(), "server");
+    assert_eq!(name.local_name(), "foo");
+    assert!(!name.is_local());
+    assert_eq!(name.to_string(), r"\\server\pipe\foo");
+}
+
+#[test]
+fn test_pipe_name_invalid() {
+    let long_name = format!(r"\\.\pipe\{}", "x".repeat(MAX_NAME_LEN));
+    let invalid = [
+        "foo",
+        r"\\.\foo",
+        r"\\\pipe\foo",
+        r"\\.\pipe\",
+        r"\\.\pipe\foo\bar",
+        "\\\\.\\pipe\\foo\0",
+        &long_name,
+    ];
+    for name in invalid.iter() {
+        let err = PipeName::new(name).unwrap_err();
+        assert!(matches!(err, PipeError::InvalidName(_)), "{}", name);
+    }
+
+    assert!(PipeName::new(&long_name[..MAX_NAME_LEN]).is_ok());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pipe_name_socket_path() {
+    let name = PipeName::local("foo/bar").unwrap();
+    let path = name.socket_path().unwrap();
+    assert_eq!(path.file_name().unwrap(), "foo%2Fbar");
+    assert_eq!(path, name.socket_path().unwrap());
+
+    let socket_path = |name| PipeName::local(name).unwrap().socket_path().unwrap();
+    assert_ne!(socket_path("foo/bar"), socket_path("foo_bar"));
+    assert_ne!(socket_path("foo/bar"), socket_path("foo%2Fbar"));
+    let dir = path.parent().unwrap();
+    for (name, encoded) in [(".", "%2E"), ("..", "%2E."), (".hidden", "%2Ehidden")].iter() {
+        let path = socket_path(name);
+        assert_eq!(path.parent(), Some(dir));
+        assert_eq!(path.file_name().unwrap(), *encoded);
+    }
+
+    // Name is valid, but its socket doesn't fit into a socket address.
+    let name = PipeName::local("x".repeat(200)).unwrap();
+    let err = name.socket_path().unwrap_err();
+    assert!(matches!(err, PipeError::InvalidName(_)));
+    assert!(err.to_string().contains("longer than 107 bytes"), "{}", err);
+
+    // So does a socket path used as a name.
+    let path = env::temp_dir().join("x".repeat(200));
+    let err = crate::PipeOptions::new(&path).single().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
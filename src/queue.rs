@@ -0,0 +1,396 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    sys, timeout_ms, ConnectingServer, Operation, OwnedIoResult, PipeClient, PipeError, PipeIo,
+    PipeServer, ReadHandle, WriteHandle, INFINITE,
+};
+
+/// Pipe owned by an [`IoQueue`](struct.IoQueue.html) operation.
+#[derive(Debug)]
+pub enum Pipe {
+    Server(PipeServer),
+    Client(PipeClient),
+}
+
+impl From<PipeServer> for Pipe {
+    fn from(server: PipeServer) -> Pipe {
+        Pipe::Server(server)
+    }
+}
+
+impl From<PipeClient> for Pipe {
+    fn from(client: PipeClient) -> Pipe {
+        Pipe::Client(client)
+    }
+}
+
+/// Finished operation of an [`IoQueue`](struct.IoQueue.html).
+#[derive(Debug)]
+pub struct Completion {
+    /// Token the operation was submitted with.
+    pub token: usize,
+    /// Number of bytes transferred.
+    pub bytes: usize,
+    pub result: Result<(), PipeError>,
+    /// Pipe of the operation (connected `PipeServer` for accepts). `None` if it was lost due to
+    /// an error.
+    pub pipe: Option<Pipe>,
+    /// Buffer of the operation. Empty for accepts or if it was lost due to an error.
+    pub buffer: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Op {
+    Accept(ConnectingServer),
+    ServerRead(ReadHandle<'static, PipeServer>),
+    ClientRead(ReadHandle<'static, PipeClient>),
+    ServerWrite(WriteHandle<'static, PipeServer>),
+    ClientWrite(WriteHandle<'static, PipeClient>),
+}
+
+impl Op {
+    fn io(&self) -> &dyn PipeIo {
+        match *self {
+            Op::Accept(ref server) => server,
+            Op::ServerRead(ref handle) => handle,
+            Op::ClientRead(ref handle) => handle,
+            Op::ServerWrite(ref handle) => handle,
+            Op::ClientWrite(ref handle) => handle,
+        }
+    }
+
+    /// Returns `None` if operation is still pending.
+    fn try_wait(&mut self) -> Option<Result<(), PipeError>> {
+        let result = match *self {
+            Op::Accept(ref mut server) => server.try_wait().map(|done| done.then_some(0)),
+            Op::ServerRead(ref mut handle) => handle.try_wait(),
+            Op::ClientRead(ref mut handle) => handle.try_wait(),
+            Op::ServerWrite(ref mut handle) => handle.try_wait(),
+            Op::ClientWrite(ref mut handle) => handle.try_wait(),
+        };
+        match result {
+            Ok(None) => None,
+            Ok(Some(_)) => Some(Ok(())),
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// Takes owned data of a finished operation.
+    fn complete(self, result: Result<(), PipeError>, token: usize) -> Completion {
+        // Operation isn't pending, so `wait` and `cancel` return immediately.
+        let (bytes, pipe, buffer) = match self {
+            Op::Accept(server) => {
+                let (result, pipe) = match result.and_then(|_| server.wait()) {
+                    Ok(server) => (Ok(()), Some(Pipe::Server(server))),
+                    Err(err) => (Err(err), None),
+                };
+                return Completion {
+                    token,
+                    bytes: 0,
+                    result,
+                    pipe,
+                    buffer: Vec::new(),
+                };
+            }
+            Op::ServerRead(handle) => output(handle.cancel()),
+            Op::ClientRead(handle) => output(handle.cancel()),
+            Op::ServerWrite(handle) => output(handle.cancel()),
+            Op::ClientWrite(handle) => output(handle.cancel()),
+        };
+        Completion {
+            token,
+            bytes,
+            result,
+            pipe,
+            buffer,
+        }
+    }
+}
+
+fn output<T: Into<Pipe>>(result: OwnedIoResult<T>) -> (usize, Option<Pipe>, Vec<u8>) {
+    match result {
+        Ok((bytes, Some((pipe, buffer)))) => (bytes, Some(pipe.into()), buffer),
+        Ok((bytes, None)) => (bytes, None, Vec::new()),
+        Err(_) => (0, None, Vec::new()),
+    }
+}
+
+/// Completion queue of io operations.
+///
+/// Operations are submitted with a user token. Queue owns pipe and buffer of every pending
+/// operation and gives them back within a `Completion` returned by `poll`. Queue is backed by an
+/// I/O completion port on Windows and epoll on Linux. Pipe is associated with the queue by its
+/// first operation, on Windows submitting it to another queue afterwards fails. In-memory pipes
+/// are not supported.
+///
+/// ```no_run
+/// # use named_pipe::{IoQueue, PipeClient, PipeOptions};
+/// const ACCEPT: usize = 0;
+/// const READ: usize = 1;
+///
+/// let mut queue = IoQueue::new().unwrap();
+/// queue.accept(PipeOptions::new(r"\\.\pipe\queue").single().unwrap(), ACCEPT).unwrap();
+/// loop {
+///     for completion in queue.poll(None).unwrap() {
+///         let pipe = completion.pipe.unwrap();
+///         match completion.token {
+///             ACCEPT => queue.read(pipe, vec![0; 1024], READ).unwrap(),
+///             READ => println!("read {} bytes", completion.bytes),
+///             _ => unreachable!(),
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct IoQueue {
+    poller: sys::Poller,
+    /// Pending operation and its token for every key. Key is the index in this list.
+    ops: Vec<Option<(Op, usize)>>,
+    /// Keys of operations to check before waiting on the poller.
+    check: Vec<usize>,
+}
+
+impl IoQueue {
+    pub fn new() -> Result<IoQueue, PipeError> {
+        let poller =
+            sys::Poller::new().map_err(|err| PipeError::new(err, None, Operation::Wait))?;
+        Ok(IoQueue {
+            poller,
+            ops: Vec::new(),
+            check: Vec::new(),
+        })
+    }
+
+    /// Submits waiting for a client. Completion will hold connected `PipeServer`.
+    pub fn accept(&mut self, server: ConnectingServer, token: usize) -> Result<(), PipeError> {
+        self.submit(Op::Accept(server), token)
+    }
+
+    /// Submits read into `buf`.
+    pub fn read<T: Into<Pipe>>(
+        &mut self,
+        pipe: T,
+        buf: Vec<u8>,
+        token: usize,
+    ) -> Result<(), PipeError> {
+        let op = match pipe.into() {
+            Pipe::Server(server) => Op::ServerRead(server.read_async_owned(buf)?),
+            Pipe::Client(client) => Op::ClientRead(client.read_async_owned(buf)?),
+        };
+        self.submit(op, token)
+    }
+
+    /// Submits write of the whole `buf`.
+    pub fn write<T: Into<Pipe>>(
+        &mut self,
+        pipe: T,
+        buf: Vec<u8>,
+        token: usize,
+    ) -> Result<(), PipeError> {
+        let op = match pipe.into() {
+            Pipe::Server(server) => Op::ServerWrite(server.write_async_owned(buf)?),
+            Pipe::Client(client) => Op::ClientWrite(client.write_async_owned(buf)?),
+        };
+        self.submit(op, token)
+    }
+
+    /// Number of pending operations.
+    pub fn len(&self) -> usize {
+        self.ops.iter().filter(|op| op.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.iter().all(|op| op.is_none())
+    }
+
+    /// Waits for completion of pending operations and returns all completed operations. Returns
+    /// empty list if `timeout` is reached or if there are no pending operations. `None` stands
+    /// for infinite waiting.
+    pub fn poll(&mut self, timeout: Option<Duration>) -> Result<Vec<Completion>, PipeError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut completions = Vec::new();
+        loop {
+            if self.is_empty() {
+                return Ok(completions);
+            }
+            let keys = if !self.check.is_empty() {
+                std::mem::take(&mut self.check)
+            } else {
+                let timeout = deadline
+                    .map(|deadline| timeout_ms(deadline.saturating_duration_since(Instant::now())))
+                    .unwrap_or(INFINITE);
+                self.poller
+                    .wait(timeout)
+                    .map_err(|err| PipeError::new(err, None, Operation::Wait))?
+            };
+            for key in keys {
+                if let Some(completion) = self.try_complete(key)? {
+                    completions.push(completion);
+                }
+            }
+            let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if !completions.is_empty() || timed_out {
+                return Ok(completions);
+            }
+        }
+    }
+
+    fn submit(&mut self, op: Op, token: usize) -> Result<(), PipeError> {
+        let key = match self.ops.iter().position(|op| op.is_none()) {
+            Some(key) => key,
+            None => {
+                self.ops.push(None);
+                self.ops.len() - 1
+            }
+        };
+        register(&mut self.poller, &op, key)?;
+        self.ops[key] = Some((op, token));
+        // Operation may be already completed.
+        self.check.push(key);
+        Ok(())
+    }
+
+    /// Completes operation of `key` if it's finished, otherwise watches it again.
+    fn try_complete(&mut self, key: usize) -> Result<Option<Completion>, PipeError> {
+        let op = match self.ops.get_mut(key).and_then(Option::as_mut) {
+            Some((op, _)) => op,
+            None => return Ok(None),
+        };
+        let result = match op.try_wait() {
+            Some(result) => result,
+            None => return register(&mut self.poller, op, key).map(|_| None),
+        };
+        let (op, token) = self.ops[key].take().unwrap();
+        // Only bookkeeping of the poller is updated, so it can't fail.
+        let _ = self.poller.deregister(key);
+        Ok(Some(op.complete(result, token)))
+    }
+}
+
+fn register(poller: &mut sys::Poller, op: &Op, key: usize) -> Result<(), PipeError> {
+    let handles = op.io().io_handles();
+    poller
+        .register(handles.pipe_handle, handles.ovl, key)
+        .map_err(|err| PipeError::new(err, Some(handles.name.clone()), Operation::Wait))
+}
+
+#[test]
+fn test_io_queue() {
+    use crate::PipeOptions;
+    use std::io::Write;
+
+    const ACCEPT: usize = 1;
+    const READ: usize = 2;
+    const WRITE: usize = 3;
+
+    let name = r"\\.\pipe\test_io_queue";
+    let mut queue = IoQueue::new().unwrap();
+    queue
+        .accept(PipeOptions::new(name).single().unwrap(), ACCEPT)
+        .unwrap();
+    let timeout = Some(Duration::from_millis(10));
+    assert!(queue.poll(timeout).unwrap().is_empty());
+    assert_eq!(queue.len(), 1);
+
+    let mut client = PipeClient::connect(name).unwrap();
+    let mut completions = queue.poll(None).unwrap();
+    assert_eq!(completions.len(), 1);
+    let completion = completions.pop().unwrap();
+    assert_eq!(completion.token, ACCEPT);
+    completion.result.unwrap();
+    let server = completion.pipe.unwrap();
+    assert!(queue.is_empty());
+
+    queue.read(server, vec![0; 4], READ).unwrap();
+    assert!(queue.poll(timeout).unwrap().is_empty());
+    client.write_all(b"ping").unwrap();
+    let completion = queue.poll(None).unwrap().pop().unwrap();
+    assert_eq!(completion.token, READ);
+    assert_eq!(completion.bytes, 4);
+    assert_eq!(completion.buffer, b"ping");
+    completion.result.unwrap();
+
+    queue
+        .write(completion.pipe.unwrap(), b"pong".to_vec(), WRITE)
+        .unwrap();
+    queue.read(client, vec![0; 4], READ).unwrap();
+    let mut completions = Vec::new();
+    while completions.len() < 2 {
+        completions.extend(queue.poll(None).unwrap());
+    }
+    completions.sort_by_key(|completion| completion.token);
+    assert_eq!(completions[0].token, READ);
+    assert_eq!(completions[0].buffer, b"pong");
+    assert!(matches!(completions[0].pipe, Some(Pipe::Client(_))));
+    assert_eq!(completions[1].token, WRITE);
+    assert_eq!(completions[1].bytes, 4);
+    assert!(matches!(completions[1].pipe, Some(Pipe::Server(_))));
+}
+
+#[test]
+fn test_io_queue_disconnect() {
+    use crate::PipeOptions;
+
+    const READ: usize = 1;
+
+    let name = r"\\.\pipe\test_io_queue_disconnect";
+    let server = PipeOptions::new(name).single().unwrap();
+    let client = PipeClient::connect(name).unwrap();
+    let server = server.wait().unwrap();
+
+    let mut queue = IoQueue::new().unwrap();
+    queue.read(server, vec![0; 4], READ).unwrap();
+    drop(client);
+    let completion = queue.poll(None).unwrap().pop().unwrap();
+    assert_eq!(completion.token, READ);
+    assert_eq!(completion.bytes, 0);
+    assert!(completion.pipe.is_some());
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn test_io_queue_memory_pipe() {
+    use crate::PipeOptions;
+
+    let server = PipeOptions::new("mem://test_io_queue_memory_pipe")
+        .single()
+        .unwrap();
+    let mut queue = IoQueue::new().unwrap();
+    let err = queue.accept(server, 0).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn test_io_queue_accept_multiple() {
+    use crate::PipeOptions;
+
+    let name = r"\\.\pipe\test_io_queue_accept_multiple";
+    let mut queue = IoQueue::new().unwrap();
+    let servers = PipeOptions::new(name).multiple(2).unwrap();
+    for (token, server) in servers.into_iter().enumerate() {
+        queue.accept(server, token).unwrap();
+    }
+
+    let _client = PipeClient::connect(name).unwrap();
+    let mut completions = queue.poll(None).unwrap();
+    assert_eq!(completions.len(), 1);
+    completions.pop().unwrap().result.unwrap();
+    let timeout = Some(Duration::from_millis(10));
+    assert!(queue.poll(timeout).unwrap().is_empty());
+
+    // Another instance is still watched.
+    let _client = PipeClient::connect(name).unwrap();
+    let mut completions = queue.poll(None).unwrap();
+    assert_eq!(completions.len(), 1);
+    completions.pop().unwrap().result.unwrap();
+    assert!(queue.is_empty());
+}
@@ -0,0 +1,363 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `ReconnectingClient` reconnects to a restarted server.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::connect::Attempts;
+use crate::sys;
+use crate::{ConnectOptions, Operation, PipeClient, PipeError, ShutdownHandle};
+
+type ReconnectCallback = Box<dyn FnMut(&mut PipeClient, u64) -> io::Result<()> + Send>;
+
+/// Client that transparently reconnects when the server goes away.
+///
+/// If a read, write or flush fails with `PipeError::Disconnected`, client connects again with its
+/// `ConnectOptions` (so they act as the reconnection policy) and retries the operation on the new
+/// connection. Connections made for one operation count against the retries and the deadline of
+/// the options, and the client sleeps for the backoff before every one but the first. Data that
+/// was in flight when the connection was lost is not recovered. A read at
+/// the end of stream returns `Ok(0)` and drops the connection, so the next operation connects
+/// again.
+///
+/// Every new connection increments the [`generation`](#method.generation) and runs the
+/// callbacks registered with [`on_reconnect`](#method.on_reconnect), so higher layers can replay
+/// their handshake. Timeouts and the shutdown handle are applied to every connection.
+///
+/// ```no_run
+/// # use named_pipe::{ConnectOptions, ReconnectingClient};
+/// # use std::io::Write;
+/// let mut options = ConnectOptions::new(r"\\.\pipe\service");
+/// options.wait_for_pipe(true);
+/// let mut client = ReconnectingClient::connect(&options).unwrap();
+/// client.on_reconnect(|client, _generation| client.write_all(b"hello"));
+/// client.write_all(b"hello").unwrap();
+/// ```
+pub struct ReconnectingClient {
+    options: ConnectOptions,
+    client: Option<PipeClient>,
+    generation: u64,
+    callbacks: Vec<ReconnectCallback>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    shutdown: Option<ShutdownHandle>,
+}
+
+impl ReconnectingClient {
+    /// Connects to a server with `options` that are also used to reconnect.
+    pub fn connect(options: &ConnectOptions) -> Result<ReconnectingClient, PipeError> {
+        Ok(ReconnectingClient {
+            options: options.clone(),
+            client: Some(options.connect()?),
+            generation: 0,
+            callbacks: Vec::new(),
+            read_timeout: None,
+            write_timeout: None,
+            shutdown: None,
+        })
+    }
+
+    /// Number of reconnections, `0` for the first connection.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Registers `callback` that is called with every new connection and its generation before
+    /// the connection is used. If it fails, the connection is dropped. The client connects again
+    /// if the connection was lost, other errors are returned.
+    pub fn on_reconnect<F>(&mut self, callback: F) -> &mut ReconnectingClient
+    where
+        F: FnMut(&mut PipeClient, u64) -> io::Result<()> + Send + 'static,
+    {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Returns current connection, reconnecting if the previous one is lost.
+    pub fn client(&mut self) -> Result<&mut PipeClient, PipeError> {
+        match self.client {
+            Some(ref mut client) => Ok(client),
+            None => self.reconnect(),
+        }
+    }
+
+    /// Drops current connection (if any) and connects again.
+    pub fn reconnect(&mut self) -> Result<&mut PipeClient, PipeError> {
+        let mut attempts = Attempts::new(&self.options);
+        self.reconnect_with(&mut attempts)
+    }
+
+    fn reconnect_with(&mut self, attempts: &mut Attempts) -> Result<&mut PipeClient, PipeError> {
+        self.client = None;
+        loop {
+            let mut client = self.options.connect_with(attempts)?;
+            client.set_read_timeout(self.read_timeout);
+            client.set_write_timeout(self.write_timeout);
+            client.set_shutdown_handle(self.shutdown.clone());
+            self.generation += 1;
+            match self.run_callbacks(&mut client) {
+                Ok(()) => return Ok(self.client.get_or_insert(client)),
+                Err(err @ PipeError::Disconnected(_)) if !attempts.next() => return Err(err),
+                Err(PipeError::Disconnected(_)) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn run_callbacks(&mut self, client: &mut PipeClient) -> Result<(), PipeError> {
+        for callback in self.callbacks.iter_mut() {
+            callback(client, self.generation).map_err(|err| {
+                PipeError::new(err, Some(client.name.clone()), Operation::Connect)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Returns `false` if connection is lost and not reestablished yet.
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    pub fn name(&self) -> &OsStr {
+        self.options.name()
+    }
+
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+        if let Some(ref mut client) = self.client {
+            client.set_read_timeout(read_timeout);
+        }
+    }
+
+    pub fn set_write_timeout(&mut self, write_timeout: Option<Duration>) {
+        self.write_timeout = write_timeout;
+        if let Some(ref mut client) = self.client {
+            client.set_write_timeout(write_timeout);
+        }
+    }
+
+    /// Attaches `handle` to connections or detaches it if `None`, see
+    /// [`ShutdownHandle`](struct.ShutdownHandle.html).
+    pub fn set_shutdown_handle(&mut self, handle: Option<ShutdownHandle>) {
+        if let Some(ref mut client) = self.client {
+            client.set_shutdown_handle(handle.clone());
+        }
+        self.shutdown = handle;
+    }
+
+    /// Runs `op` on new connections while `lost` says the connection is lost. Stops when the
+    /// client fails to reconnect or runs out of attempts.
+    fn retry<T, F, L>(&mut self, mut op: F, lost: L) -> io::Result<T>
+    where
+        F: FnMut(&mut PipeClient) -> io::Result<T>,
+        L: Fn(&io::Result<T>) -> bool,
+    {
+        let mut result = op(self.client()?);
+        if !lost(&result) {
+            return result;
+        }
+        let mut attempts = Attempts::new(&self.options);
+        loop {
+            result = op(self.reconnect_with(&mut attempts)?);
+            if !lost(&result) {
+                return result;
+            }
+            if !attempts.next() {
+                // The next operation connects again.
+                self.client = None;
+                return result;
+            }
+        }
+    }
+
+    /// Drops the connection if a read of `len` bytes returned the end of stream.
+    fn end_of_stream(&mut self, read: usize, len: usize) -> io::Result<usize> {
+        if read == 0 && len > 0 {
+            self.client = None;
+        }
+        Ok(read)
+    }
+}
+
+fn is_disconnected<T>(result: &io::Result<T>) -> bool {
+    let err = match *result {
+        Ok(_) => return false,
+        Err(ref err) => err,
+    };
+    // OS errors are converted into `io::Error` by their code, see `PipeError`.
+    sys::is_disconnected(err)
+        || matches!(
+            err.get_ref()
+                .and_then(|err| err.downcast_ref::<PipeError>()),
+            Some(PipeError::Disconnected(_))
+        )
+}
+
+impl io::Read for ReconnectingClient {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.retry(|client| client.read(buf), is_disconnected)?;
+        self.end_of_stream(read, buf.len())
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        let read = self.retry(|client| client.read_vectored(bufs), is_disconnected)?;
+        self.end_of_stream(read, bufs.iter().map(|buf| buf.len()).sum())
+    }
+}
+
+impl io::Write for ReconnectingClient {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(|client| client.write(buf), is_disconnected)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.retry(|client| client.write_vectored(bufs), is_disconnected)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(|client| client.flush(), is_disconnected)
+    }
+}
+
+impl fmt::Debug for ReconnectingClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectingClient")
+            .field("options", &self.options)
+            .field("client", &self.client)
+            .field("generation", &self.generation)
+            .field("callbacks", &self.callbacks.len())
+            .finish()
+    }
+}
+
+#[test]
+fn test_reconnecting_client() {
+    use crate::PipeOptions;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    for &name in &[
+        r"\\.\pipe\test_reconnecting_client",
+        "mem://test_memory_reconnecting_client",
+    ] {
+        let server_name = name.to_string();
+        let server = thread::spawn(move || {
+            // First run of the service sends a byte and stops.
+            let mut server = PipeOptions::new(&server_name)
+                .single()
+                .unwrap()
+                .wait()
+                .unwrap();
+            server.write_all(b"1").unwrap();
+            drop(server);
+
+            // Restarted service expects a handshake.
+            thread::sleep(Duration::from_millis(50));
+            let mut server = PipeOptions::new(&server_name)
+                .single()
+                .unwrap()
+                .wait()
+                .unwrap();
+            let mut buf = [0; 5];
+            server.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"hello");
+            server.write_all(b"2").unwrap();
+        });
+
+        let mut options = ConnectOptions::new(name);
+        options.wait_for_pipe(true).timeout(Duration::from_secs(10));
+        let mut client = ReconnectingClient::connect(&options).unwrap();
+        let generations = Arc::new(Mutex::new(Vec::new()));
+        let reconnects = generations.clone();
+        client.on_reconnect(move |client, generation| {
+            reconnects.lock().unwrap().push(generation);
+            client.write_all(b"hello")
+        });
+        assert_eq!(client.generation(), 0);
+        assert_eq!(client.name(), OsStr::new(name));
+
+        let mut buf = [0; 1];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"1");
+        // End of stream is returned to the caller, the next read connects again.
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        assert!(!client.is_connected());
+        assert_eq!(client.generation(), 0);
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"2");
+        assert_eq!(client.generation(), 1);
+        assert_eq!(*generations.lock().unwrap(), [1]);
+        server.join().unwrap();
+
+        // Server is gone for good.
+        let mut options = ConnectOptions::new(name);
+        options.retries(Some(0));
+        let err = ReconnectingClient::connect(&options).unwrap_err();
+        assert!(matches!(err, PipeError::NotFound(_)));
+        let server = PipeOptions::new(name).single().unwrap();
+        let mut client = ReconnectingClient::connect(&options).unwrap();
+        drop(server);
+        let err = client.write_all(b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!client.is_connected());
+    }
+}
+
+#[test]
+fn test_reconnect_attempts() {
+    use crate::PipeOptions;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    for &name in &[
+        r"\\.\pipe\test_reconnect_attempts",
+        "mem://test_memory_reconnect_attempts",
+    ] {
+        let servers = (0..6)
+            .map(|i| PipeOptions::new(name).first(i == 0).single().unwrap())
+            .collect::<Vec<_>>();
+        let mut options = ConnectOptions::new(name);
+        options
+            .retries(Some(2))
+            .backoff(Duration::from_millis(50), Duration::from_millis(50))
+            .jitter(false);
+        let mut client = ReconnectingClient::connect(&options).unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let callback_calls = calls.clone();
+        // Handshake is never accepted.
+        client.on_reconnect(move |_, _| {
+            callback_calls.fetch_add(1, Ordering::SeqCst);
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "handshake"))
+        });
+
+        let start = Instant::now();
+        let err = client.reconnect().unwrap_err();
+        assert!(matches!(err, PipeError::Disconnected(_)));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(client.generation(), 3);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(!client.is_connected());
+
+        // Operation stops at the deadline even though retries are unlimited.
+        options.retries(None).timeout(Duration::from_millis(100));
+        let mut client = ReconnectingClient::connect(&options).unwrap();
+        client.on_reconnect(|_, _| Err(io::Error::new(io::ErrorKind::BrokenPipe, "handshake")));
+        let start = Instant::now();
+        client.reconnect().unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(servers);
+        assert!(client.write_all(b"x").is_err());
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Cancellation of blocking waits from other threads.
+
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use crate::{sys, Operation, PipeError};
+
+/// Wakes threads blocked on pipes it's attached to.
+///
+/// Handle is attached to pipe instances with `PipeOptions::shutdown_handle` (so it's inherited by
+/// `PipeServer`s and `PipeListener`s) and to clients with `PipeClient::set_shutdown_handle`.
+/// Clones of a handle share the same state.
+///
+/// Once [`shutdown`](#method.shutdown) is called, waits of pending accepts, reads and writes of
+/// attached pipes (including `wait`, `wait_all`, their timeout variants and `WaitSet::wait`) that
+/// are not finished yet return `PipeError::Cancelled` promptly, as well as all the following
+/// waits.
+///
+/// ```
+/// # use named_pipe::{PipeError, PipeOptions, ShutdownHandle};
+/// let shutdown = ShutdownHandle::new().unwrap();
+/// let server = PipeOptions::new("mem://shutdown_doc")
+///     .shutdown_handle(&shutdown)
+///     .single()
+///     .unwrap();
+///
+/// let handle = shutdown.clone();
+/// std::thread::spawn(move || handle.shutdown().unwrap());
+/// assert!(matches!(server.wait(), Err(PipeError::Cancelled(_))));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<sys::Shutdown>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Result<ShutdownHandle, PipeError> {
+        let inner =
+            sys::Shutdown::new().map_err(|err| PipeError::new(err, None, Operation::Create))?;
+        Ok(ShutdownHandle {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Interrupts waits of attached pipes. Calling it more than once has no effect.
+    pub fn shutdown(&self) -> Result<(), PipeError> {
+        self.inner
+            .trigger()
+            .map_err(|err| PipeError::new(err, None, Operation::Cancel))
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.is_triggered()
+    }
+
+    pub(crate) fn inner(&self) -> &sys::Shutdown {
+        &self.inner
+    }
+}
+
+/// Handles are equal if they are clones of each other.
+impl PartialEq for ShutdownHandle {
+    fn eq(&self, other: &ShutdownHandle) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for ShutdownHandle {}
+
+impl Hash for ShutdownHandle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.inner).hash(state)
+    }
+}
+
+#[test]
+fn test_shutdown() {
+    use crate::{wait, wait_all, wait_all_timeout, PipeClient, PipeOptions};
+    use std::io::Read;
+    use std::thread;
+    use std::time::Duration;
+
+    fn shutdown_later(shutdown: &ShutdownHandle) -> thread::JoinHandle<()> {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            shutdown.shutdown().unwrap();
+        })
+    }
+
+    for &name in &[r"\\.\pipe\test_shutdown", "mem://test_shutdown"] {
+        // Accept.
+        let shutdown = ShutdownHandle::new().unwrap();
+        let server = PipeOptions::new(name)
+            .shutdown_handle(&shutdown)
+            .single()
+            .unwrap();
+        let thread = shutdown_later(&shutdown);
+        let err = server.wait().unwrap_err();
+        assert!(matches!(err, PipeError::Cancelled(_)));
+        assert_eq!(err.kind(), std::io::ErrorKind::Other);
+        thread.join().unwrap();
+        assert!(shutdown.is_shutdown());
+
+        // Read and `wait`.
+        let shutdown = ShutdownHandle::new().unwrap();
+        let mut options = PipeOptions::new(name);
+        options.shutdown_handle(&shutdown);
+        let mut servers = options.multiple(2).unwrap();
+        let mut client = PipeClient::connect(name).unwrap();
+        client.set_shutdown_handle(Some(shutdown.clone()));
+        let thread = shutdown_later(&shutdown);
+        let mut buf = [0; 4];
+        let err = client.read(&mut buf).unwrap_err();
+        let err = err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<PipeError>());
+        assert!(matches!(err, Some(PipeError::Cancelled(_))));
+        thread.join().unwrap();
+
+        // Connected instance is still ready, the other one is interrupted.
+        let connected = wait(&servers).unwrap();
+        let _server = servers.remove(connected).wait().unwrap();
+        assert!(matches!(wait(&servers), Err(PipeError::Cancelled(_))));
+        assert!(matches!(wait_all(&servers), Err(PipeError::Cancelled(_))));
+        let err = wait_all_timeout(&servers, Duration::from_secs(10)).unwrap_err();
+        assert!(matches!(err, PipeError::Cancelled(_)));
+    }
+}
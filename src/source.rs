@@ -0,0 +1,236 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `mio::event::Source` implementations (requires `mio` feature).
+//!
+//! On Unix the socket of a pipe is registered, so events report its readiness. `ConnectingServer`
+//! registers a duplicate of the listening socket and must be deregistered before it's waited.
+//! Pipes with zero-sized buffers can't be registered on Unix.
+//!
+//! On Windows readiness is emulated: the token is delivered once an operation started with
+//! `read_async`, `write_async` (or their owned variants) or a pending connection is finished, so
+//! `try_wait` of the pending handle wouldn't block.
+//!
+//! In-memory pipes can't be registered.
+
+use std::ffi::OsString;
+use std::io;
+use std::sync::Arc;
+
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+
+use crate::sys::{self, Handle, Overlapped};
+use crate::{ConnectingServer, Operation, PipeClient, PipeError, PipeServer};
+
+fn error(name: &Arc<OsString>) -> impl Fn(io::Error) -> io::Error + '_ {
+    move |err| PipeError::new(err, Some(name.clone()), Operation::Wait).into()
+}
+
+fn register(
+    name: &Arc<OsString>,
+    handle: &Handle,
+    ovl: &mut Overlapped,
+    registry: &Registry,
+    token: Token,
+    interests: Interest,
+) -> io::Result<()> {
+    sys::mio_register(handle, ovl, registry, token, interests).map_err(error(name))
+}
+
+fn reregister(
+    name: &Arc<OsString>,
+    handle: &Handle,
+    ovl: &mut Overlapped,
+    registry: &Registry,
+    token: Token,
+    interests: Interest,
+) -> io::Result<()> {
+    sys::mio_reregister(handle, ovl, registry, token, interests).map_err(error(name))
+}
+
+fn deregister(
+    name: &Arc<OsString>,
+    handle: &Handle,
+    ovl: &mut Overlapped,
+    registry: &Registry,
+) -> io::Result<()> {
+    sys::mio_deregister(handle, ovl, registry).map_err(error(name))
+}
+
+impl Source for PipeServer {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match (&self.handle, &mut self.ovl) {
+            (Some(handle), Some(ovl)) => {
+                register(&self.name, handle, ovl, registry, token, interests)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match (&self.handle, &mut self.ovl) {
+            (Some(handle), Some(ovl)) => {
+                reregister(&self.name, handle, ovl, registry, token, interests)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match (&self.handle, &mut self.ovl) {
+            (Some(handle), Some(ovl)) => deregister(&self.name, handle, ovl, registry),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Source for PipeClient {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        register(
+            &self.name,
+            &self.handle,
+            &mut self.ovl,
+            registry,
+            token,
+            interests,
+        )
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        reregister(
+            &self.name,
+            &self.handle,
+            &mut self.ovl,
+            registry,
+            token,
+            interests,
+        )
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        deregister(&self.name, &self.handle, &mut self.ovl, registry)
+    }
+}
+
+impl Source for ConnectingServer {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        register(
+            &self.name,
+            &self.handle,
+            &mut self.ovl,
+            registry,
+            token,
+            interests,
+        )
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        reregister(
+            &self.name,
+            &self.handle,
+            &mut self.ovl,
+            registry,
+            token,
+            interests,
+        )
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        deregister(&self.name, &self.handle, &mut self.ovl, registry)
+    }
+}
+
+#[test]
+fn test_mio_source() {
+    use crate::PipeOptions;
+    use mio::{Events, Poll};
+    use std::io::Write;
+    use std::thread;
+    use std::time::Duration;
+
+    const SERVER: Token = Token(0);
+    const CLIENT: Token = Token(1);
+
+    let name = r"\\.\pipe\test_mio_source";
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(8);
+    let mut connecting = PipeOptions::new(name).single().unwrap();
+    poll.registry()
+        .register(&mut connecting, SERVER, Interest::READABLE)
+        .unwrap();
+
+    let client = thread::spawn(move || PipeClient::connect(name).unwrap());
+    poll.poll(&mut events, Some(Duration::from_secs(5)))
+        .unwrap();
+    assert!(events.iter().any(|event| event.token() == SERVER));
+    poll.registry().deregister(&mut connecting).unwrap();
+    let mut server = connecting.wait().unwrap();
+
+    let mut client = client.join().unwrap();
+    poll.registry()
+        .register(&mut client, CLIENT, Interest::READABLE)
+        .unwrap();
+    let mut buf = [0; 4];
+    let mut read = unsafe { client.read_async(&mut buf) }.unwrap();
+    assert_eq!(read.try_wait().unwrap(), None);
+    server.write_all(b"ping").unwrap();
+    poll.poll(&mut events, Some(Duration::from_secs(5)))
+        .unwrap();
+    assert!(events.iter().any(|event| event.token() == CLIENT));
+    assert_eq!(read.try_wait().unwrap(), Some(4));
+    drop(read);
+    assert_eq!(&buf, b"ping");
+    poll.registry().deregister(&mut client).unwrap();
+}
+
+#[test]
+fn test_mio_memory_pipe() {
+    use crate::PipeOptions;
+    use mio::Poll;
+
+    let poll = Poll::new().unwrap();
+    let mut connecting = PipeOptions::new("mem://test_mio_memory_pipe")
+        .single()
+        .unwrap();
+    let err = poll
+        .registry()
+        .register(&mut connecting, Token(0), Interest::READABLE)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+}
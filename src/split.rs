@@ -0,0 +1,431 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Read and write halves of a connected pipe.
+
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::sys::{self, Handle, Overlapped};
+use crate::{
+    init_read, init_write, pipe_error, read_message, read_message_part, read_vectored, timeout_ms,
+    wait_read, write_vectored, MessageRead, Operation, PipeClient, PipeError, PipeIo,
+    PipeIoHandles, PipeIoObj, PipeServer,
+};
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// Pipe that can be split into read and write halves, i.e. `PipeServer` or `PipeClient`.
+pub trait SplitPipe: PipeIo + private::Sealed {}
+
+impl private::Sealed for PipeServer {}
+
+impl SplitPipe for PipeServer {}
+
+impl private::Sealed for PipeClient {}
+
+impl SplitPipe for PipeClient {}
+
+fn server_handle(server: &PipeServer) -> &Handle {
+    server.handle.as_ref().unwrap()
+}
+
+fn client_handle(client: &PipeClient) -> &Handle {
+    &client.handle
+}
+
+impl PipeServer {
+    /// Splits server into halves that borrow it, see [`PipeReadHalf`](struct.PipeReadHalf.html).
+    pub fn split(
+        &mut self,
+    ) -> Result<(PipeReadHalf<'_, Self>, PipeWriteHalf<'_, Self>), PipeError> {
+        split(Shared::Borrowed(self), server_handle)
+    }
+
+    /// Splits server into halves that own it. Use `PipeReadHalf::reunite` to get it back.
+    pub fn into_split(
+        self,
+    ) -> Result<(PipeReadHalf<'static, Self>, PipeWriteHalf<'static, Self>), PipeError> {
+        split(Shared::Owned(Arc::new(self)), server_handle)
+    }
+}
+
+impl PipeClient {
+    /// Splits client into halves that borrow it, see [`PipeReadHalf`](struct.PipeReadHalf.html).
+    pub fn split(
+        &mut self,
+    ) -> Result<(PipeReadHalf<'_, Self>, PipeWriteHalf<'_, Self>), PipeError> {
+        split(Shared::Borrowed(self), client_handle)
+    }
+
+    /// Splits client into halves that own it. Use `PipeReadHalf::reunite` to get it back.
+    pub fn into_split(
+        self,
+    ) -> Result<(PipeReadHalf<'static, Self>, PipeWriteHalf<'static, Self>), PipeError> {
+        split(Shared::Owned(Arc::new(self)), client_handle)
+    }
+}
+
+/// Pipe shared by halves.
+#[derive(Debug)]
+enum Shared<'a, P> {
+    Borrowed(&'a P),
+    Owned(Arc<P>),
+}
+
+impl<'a, P> Clone for Shared<'a, P> {
+    fn clone(&self) -> Shared<'a, P> {
+        match *self {
+            Shared::Borrowed(pipe) => Shared::Borrowed(pipe),
+            Shared::Owned(ref pipe) => Shared::Owned(pipe.clone()),
+        }
+    }
+}
+
+impl<'a, P> Deref for Shared<'a, P> {
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        match *self {
+            Shared::Borrowed(pipe) => pipe,
+            Shared::Owned(ref pipe) => pipe,
+        }
+    }
+}
+
+fn split<P: SplitPipe>(
+    pipe: Shared<'_, P>,
+    handle: fn(&P) -> &Handle,
+) -> Result<(PipeReadHalf<'_, P>, PipeWriteHalf<'_, P>), PipeError> {
+    let error = pipe_error(pipe.io_handles().name, Operation::Create);
+    let read = PipeReadHalf {
+        ovl: Overlapped::new(handle(&pipe)).map_err(&error)?,
+        read_timeout: pipe.get_read_timeout(),
+        pipe: pipe.clone(),
+    };
+    let write = PipeWriteHalf {
+        ovl: Overlapped::new(handle(&pipe)).map_err(&error)?,
+        write_timeout: pipe.get_write_timeout(),
+        handle,
+        pipe,
+    };
+    Ok((read, write))
+}
+
+/// Read half of a `PipeServer` or `PipeClient`.
+///
+/// Halves have their own pending operation state and timeouts (initially copied from the pipe),
+/// so a read and a write could be in flight at the same time, e.g. in different threads. Borrowed
+/// halves are returned by `split` and owned ones by `into_split`.
+///
+/// ```
+/// # use named_pipe::{PipeClient, PipeOptions};
+/// # use std::io::{Read, Write};
+/// let server = PipeOptions::new("mem://split_doc").single().unwrap();
+/// let client = PipeClient::connect("mem://split_doc").unwrap();
+/// let mut server = server.wait().unwrap();
+///
+/// let (mut read, mut write) = client.into_split().unwrap();
+/// let reader = std::thread::spawn(move || {
+///     let mut buf = [0; 4];
+///     read.read_exact(&mut buf).unwrap();
+///     read
+/// });
+/// write.write_all(b"ping").unwrap();
+/// server.write_all(b"pong").unwrap();
+///
+/// let read = reader.join().unwrap();
+/// let client = read.reunite(write).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct PipeReadHalf<'a, P: SplitPipe> {
+    pipe: Shared<'a, P>,
+    ovl: Overlapped,
+    read_timeout: Option<u32>,
+}
+
+/// Write half of a `PipeServer` or `PipeClient`, see [`PipeReadHalf`](struct.PipeReadHalf.html).
+#[derive(Debug)]
+pub struct PipeWriteHalf<'a, P: SplitPipe> {
+    pipe: Shared<'a, P>,
+    ovl: Overlapped,
+    write_timeout: Option<u32>,
+    handle: fn(&P) -> &Handle,
+}
+
+// Halves only share the pipe handle, concurrent operations use their own `Overlapped`.
+unsafe impl<'a, P: SplitPipe + Send> Send for PipeReadHalf<'a, P> {}
+unsafe impl<'a, P: SplitPipe + Send> Send for PipeWriteHalf<'a, P> {}
+
+impl<'a, P: SplitPipe> PipeReadHalf<'a, P> {
+    pub fn name(&self) -> &OsStr {
+        self.pipe.io_handles().name
+    }
+
+    /// Reads from a message-mode pipe, see `PipeServer::read_message_part`.
+    pub fn read_message_part(&mut self, buf: &mut [u8]) -> Result<MessageRead, PipeError> {
+        let error = pipe_error(self.pipe.io_handles().name, Operation::Read);
+        read_message_part(self, buf).map_err(error)
+    }
+
+    /// Reads whole message from a message-mode pipe. See `read_message_part`.
+    pub fn read_message(&mut self) -> Result<Vec<u8>, PipeError> {
+        let error = pipe_error(self.pipe.io_handles().name, Operation::Read);
+        read_message(self).map_err(error)
+    }
+
+    /// Sets read timeout of this half. Defaults to the read timeout of the pipe.
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout.map(timeout_ms);
+    }
+
+    pub fn get_read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+            .map(|millis| Duration::from_millis(millis as u64))
+    }
+}
+
+impl<P: SplitPipe + 'static> PipeReadHalf<'static, P> {
+    /// Joins halves returned by `into_split` back. Fails if they are halves of different pipes.
+    #[allow(clippy::result_large_err)] // Halves are given back on error.
+    pub fn reunite(self, write: PipeWriteHalf<'static, P>) -> Result<P, ReuniteError<P>> {
+        match (&self.pipe, &write.pipe) {
+            (Shared::Owned(read_pipe), Shared::Owned(write_pipe))
+                if Arc::ptr_eq(read_pipe, write_pipe) => {}
+            _ => return Err(ReuniteError(self, write)),
+        }
+        drop(write);
+        match self.pipe {
+            Shared::Owned(pipe) => match Arc::try_unwrap(pipe) {
+                Ok(pipe) => Ok(pipe),
+                Err(_) => unreachable!(),
+            },
+            Shared::Borrowed(_) => unreachable!(),
+        }
+    }
+}
+
+impl<'a, P: SplitPipe> PipeWriteHalf<'a, P> {
+    pub fn name(&self) -> &OsStr {
+        self.pipe.io_handles().name
+    }
+
+    /// Sets write timeout of this half. Defaults to the write timeout of the pipe.
+    pub fn set_write_timeout(&mut self, write_timeout: Option<Duration>) {
+        self.write_timeout = write_timeout.map(timeout_ms);
+    }
+
+    pub fn get_write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+            .map(|millis| Duration::from_millis(millis as u64))
+    }
+}
+
+impl<P: SplitPipe + 'static> PipeWriteHalf<'static, P> {
+    /// Joins halves returned by `into_split` back, see `PipeReadHalf::reunite`.
+    #[allow(clippy::result_large_err)] // Halves are given back on error.
+    pub fn reunite(self, read: PipeReadHalf<'static, P>) -> Result<P, ReuniteError<P>> {
+        read.reunite(self)
+    }
+}
+
+impl<'a, P: SplitPipe> io::Read for PipeReadHalf<'a, P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let error = pipe_error(self.pipe.io_handles().name, Operation::Read);
+        wait_read(init_read(self, buf)).map_err(|err| error(err).into())
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        let error = pipe_error(self.pipe.io_handles().name, Operation::Read);
+        read_vectored(self, bufs).map_err(|err| error(err).into())
+    }
+}
+
+impl<'a, P: SplitPipe> io::Write for PipeWriteHalf<'a, P> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let error = pipe_error(self.pipe.io_handles().name, Operation::Write);
+        let write_handle = init_write(self, buf);
+        let result = write_handle
+            .map_err(error)
+            .and_then(|write_handle| write_handle.wait());
+        Ok(result?.0)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let error = pipe_error(self.pipe.io_handles().name, Operation::Write);
+        write_vectored(self, bufs).map_err(|err| error(err).into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        sys::flush_file_buffers((self.handle)(&self.pipe))
+            .map_err(|err| pipe_error(self.pipe.io_handles().name, Operation::Flush)(err).into())
+    }
+}
+
+impl<'a, P: SplitPipe> PipeIo for PipeReadHalf<'a, P> {
+    fn io_obj<'b>(&'b mut self) -> PipeIoObj<'b> {
+        let handles = self.pipe.io_handles();
+        PipeIoObj {
+            handle: handles.pipe_handle,
+            ovl: &mut self.ovl,
+            shutdown: handles.shutdown,
+        }
+    }
+
+    fn io_handles<'b>(&'b self) -> PipeIoHandles<'b> {
+        let handles = self.pipe.io_handles();
+        PipeIoHandles {
+            ovl: &self.ovl,
+            ..handles
+        }
+    }
+
+    fn get_read_timeout(&self) -> Option<u32> {
+        self.read_timeout
+    }
+
+    fn get_write_timeout(&self) -> Option<u32> {
+        None
+    }
+}
+
+impl<'a, P: SplitPipe> PipeIo for PipeWriteHalf<'a, P> {
+    fn io_obj<'b>(&'b mut self) -> PipeIoObj<'b> {
+        let handles = self.pipe.io_handles();
+        PipeIoObj {
+            handle: handles.pipe_handle,
+            ovl: &mut self.ovl,
+            shutdown: handles.shutdown,
+        }
+    }
+
+    fn io_handles<'b>(&'b self) -> PipeIoHandles<'b> {
+        let handles = self.pipe.io_handles();
+        PipeIoHandles {
+            ovl: &self.ovl,
+            ..handles
+        }
+    }
+
+    fn get_read_timeout(&self) -> Option<u32> {
+        None
+    }
+
+    fn get_write_timeout(&self) -> Option<u32> {
+        self.write_timeout
+    }
+}
+
+/// Error returned by `reunite` if halves belong to different pipes. Contains the halves.
+pub struct ReuniteError<P: SplitPipe + 'static>(
+    pub PipeReadHalf<'static, P>,
+    pub PipeWriteHalf<'static, P>,
+);
+
+impl<P: SplitPipe + 'static> fmt::Debug for ReuniteError<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReuniteError").finish()
+    }
+}
+
+impl<P: SplitPipe + 'static> fmt::Display for ReuniteError<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("tried to reunite halves that are not from the same pipe")
+    }
+}
+
+impl<P: SplitPipe + 'static> Error for ReuniteError<P> {}
+
+#[test]
+fn test_split() {
+    use crate::{connected_pair, PipeOptions};
+    use std::io::{Read, Write};
+    use std::thread;
+
+    for &name in &[r"\\.\pipe\test_split", "mem://test_memory_split"] {
+        let (mut server, client) = connected_pair(&PipeOptions::new(name));
+
+        // Both sides read and write at the same time, more than fits into pipe buffers.
+        let (mut client_read, mut client_write) = client.into_split().unwrap();
+        let writer = thread::spawn(move || {
+            client_write.write_all(&[1; 100_000]).unwrap();
+            client_write
+        });
+        let reader = thread::spawn(move || {
+            let mut buf = vec![0; 100_000];
+            client_read.read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|&x| x == 2));
+            client_read
+        });
+        {
+            let (mut read, mut write) = server.split().unwrap();
+            assert_eq!(read.name(), OsStr::new(name));
+            write.write_all(&[2; 100_000]).unwrap();
+            let mut buf = vec![0; 100_000];
+            read.read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|&x| x == 1));
+        }
+        let client_write = writer.join().unwrap();
+        let mut client_read = reader.join().unwrap();
+        let mut buf = [0; 4];
+
+        // Timeouts are separate.
+        client_read.set_read_timeout(Some(Duration::from_millis(10)));
+        let err = client_read.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(client_write.get_write_timeout(), None);
+
+        let mut client = client_read.reunite(client_write).unwrap();
+        assert_eq!(client.get_read_timeout(), None);
+        server.write_all(b"done").unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"done");
+
+        // Halves of different pipes.
+        let other = PipeOptions::new(name).first(false).single().unwrap();
+        let other_client = PipeClient::connect(name).unwrap();
+        let _other = other.wait().unwrap();
+        let (read, _) = client.into_split().unwrap();
+        let (_, write) = other_client.into_split().unwrap();
+        assert!(read.reunite(write).is_err());
+    }
+}
+
+#[test]
+fn test_split_message_remainder() {
+    use crate::{connected_pair, MessageRead, PipeMode, PipeOptions};
+    use std::io::Write;
+
+    for &name in &[
+        r"\\.\pipe\test_split_message_remainder",
+        "mem://test_memory_split_message_remainder",
+    ] {
+        let (mut server, mut client) =
+            connected_pair(PipeOptions::new(name).pipe_mode(PipeMode::Message));
+        client.write_all(b"hello").unwrap();
+        client.write_all(b"world").unwrap();
+
+        // Rest of a partially read message goes to the read half and back.
+        let mut buf = [0; 2];
+        let read = server.read_message_part(&mut buf).unwrap();
+        assert!(matches!(read, MessageRead::Partial(2)));
+        let (mut read, write) = server.into_split().unwrap();
+        assert_eq!(read.read_message().unwrap(), b"llo");
+        let part = read.read_message_part(&mut buf).unwrap();
+        assert!(matches!(part, MessageRead::Partial(2)));
+        let mut server = read.reunite(write).unwrap();
+        assert_eq!(server.read_message().unwrap(), b"rld");
+    }
+}
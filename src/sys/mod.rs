@@ -0,0 +1,557 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Platform backends.
+//!
+//! Every backend exposes the same set of overlapped-IO-like primitives (see the Windows backend
+//! for reference semantics), so the public types are written once on top of them. This module
+//! dispatches between the OS backend and the [in-memory transport](../memory/index.html).
+
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::Waker;
+use std::time::Instant;
+
+use crate::memory;
+use crate::PipeOptions;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+use self::windows as os;
+
+// The Unix backend relies on Linux APIs (`epoll`, `accept4`, `SOCK_NONBLOCK`, `MSG_NOSIGNAL`).
+#[cfg(target_os = "linux")]
+mod unix;
+#[cfg(target_os = "linux")]
+use self::unix as os;
+
+#[cfg(not(any(windows, target_os = "linux")))]
+compile_error!("named_pipe supports only Windows and Linux");
+
+pub use self::os::{RawHandle, INFINITE};
+
+#[derive(Debug)]
+pub enum Handle {
+    Os(os::Handle),
+    Memory(memory::Handle),
+}
+
+impl Handle {
+    pub fn raw(&self) -> RawHandle {
+        match *self {
+            Handle::Os(ref handle) => handle.raw(),
+            Handle::Memory(_) => os::NULL_HANDLE,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Overlapped {
+    Os(os::Overlapped),
+    Memory(memory::Overlapped),
+}
+
+impl Overlapped {
+    pub fn new(handle: &Handle) -> io::Result<Overlapped> {
+        match *handle {
+            Handle::Os(ref handle) => os::Overlapped::new(handle).map(Overlapped::Os),
+            Handle::Memory(ref handle) => Ok(Overlapped::Memory(memory::Overlapped::new(handle))),
+        }
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        match *self {
+            Overlapped::Os(ref mut ovl) => ovl.clear(),
+            Overlapped::Memory(ref mut ovl) => {
+                ovl.clear();
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn create_named_pipe(options: &PipeOptions, first: bool) -> io::Result<Handle> {
+    if memory::is_memory_name(&options.name) {
+        memory::create_named_pipe(options, first).map(Handle::Memory)
+    } else {
+        os::create_named_pipe(options, first).map(Handle::Os)
+    }
+}
+
+/// Returns `Ok(true)` if connection is pending or `Ok(false)` if pipe is connected.
+pub fn connect_named_pipe(handle: &Handle, ovl: &mut Overlapped) -> io::Result<bool> {
+    match (handle, ovl) {
+        (Handle::Os(handle), Overlapped::Os(ovl)) => os::connect_named_pipe(handle, ovl),
+        (Handle::Memory(handle), Overlapped::Memory(ovl)) => {
+            memory::connect_named_pipe(handle, ovl)
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Returns handle of a connected pipe instance once `connect_named_pipe` is completed.
+pub fn finish_connect(handle: Handle, ovl: &mut Overlapped) -> io::Result<Handle> {
+    match (handle, ovl) {
+        (Handle::Os(handle), Overlapped::Os(ovl)) => {
+            os::finish_connect(handle, ovl).map(Handle::Os)
+        }
+        (handle @ Handle::Memory(_), Overlapped::Memory(_)) => Ok(handle),
+        _ => unreachable!(),
+    }
+}
+
+/// Returns handle that is ready to be passed to `connect_named_pipe` after disconnection.
+pub fn listening_handle(handle: Handle) -> Handle {
+    match handle {
+        Handle::Os(handle) => Handle::Os(os::listening_handle(handle)),
+        handle @ Handle::Memory(_) => handle,
+    }
+}
+
+/// Duplicates handle of a connected pipe. Both handles refer to the same connection.
+pub fn duplicate_handle(handle: &Handle) -> io::Result<Handle> {
+    match *handle {
+        Handle::Os(ref handle) => os::duplicate_handle(handle).map(Handle::Os),
+        Handle::Memory(ref handle) => Ok(Handle::Memory(memory::duplicate_handle(handle))),
+    }
+}
+
+pub fn flush_file_buffers(handle: &Handle) -> io::Result<()> {
+    match *handle {
+        Handle::Os(ref handle) => os::flush_file_buffers(handle),
+        Handle::Memory(ref handle) => memory::flush_file_buffers(handle),
+    }
+}
+
+pub fn disconnect_named_pipe(handle: &Handle) -> io::Result<()> {
+    match *handle {
+        Handle::Os(ref handle) => os::disconnect_named_pipe(handle),
+        Handle::Memory(ref handle) => memory::disconnect_named_pipe(handle),
+    }
+}
+
+/// See `unix::bind_std_listener`.
+#[cfg(all(unix, feature = "tokio"))]
+pub fn bind_std_listener(
+    options: &PipeOptions,
+) -> io::Result<(Handle, std::os::unix::net::UnixListener)> {
+    if memory::is_memory_name(&options.name) {
+        return Err(memory_unsupported());
+    }
+    os::bind_std_listener(options).map(|(handle, listener)| (Handle::Os(handle), listener))
+}
+
+/// Applies pipe options to a socket accepted from the listener of `handle`.
+#[cfg(all(unix, feature = "tokio"))]
+pub fn setup_accepted(handle: &Handle, fd: std::os::unix::io::RawFd) -> io::Result<()> {
+    match *handle {
+        Handle::Os(ref handle) => os::setup_accepted(handle, fd),
+        Handle::Memory(_) => unreachable!(),
+    }
+}
+
+#[cfg(all(unix, feature = "tokio"))]
+pub use self::os::async_socket_path;
+
+#[cfg(unix)]
+pub use self::os::check_socket_path;
+
+/// Error for operations that in-memory pipes don't support.
+pub fn memory_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "operation is not supported by in-memory pipes",
+    )
+}
+
+/// Opens client end of a pipe waiting while all instances are busy until `deadline` (`None`
+/// stands for infinite waiting).
+pub fn open_pipe(name: &OsStr, deadline: Option<Instant>) -> io::Result<Handle> {
+    if memory::is_memory_name(name) {
+        memory::open_pipe(name, deadline).map(Handle::Memory)
+    } else {
+        os::open_pipe(name, deadline).map(Handle::Os)
+    }
+}
+
+/// Starts read. Returns `Ok(Some(bytes_read))` if operation was completed synchronously and
+/// `Ok(None)` if it is pending.
+///
+/// # Unsafety
+/// `buf` must stay valid until operation is completed or cancelled.
+pub unsafe fn read_file(
+    handle: RawHandle,
+    buf: *mut u8,
+    len: u32,
+    ovl: &mut Overlapped,
+) -> io::Result<Option<u32>> {
+    match *ovl {
+        Overlapped::Os(ref mut ovl) => os::read_file(handle, buf, len, ovl),
+        Overlapped::Memory(ref mut ovl) => memory::read_file(buf, len, ovl),
+    }
+}
+
+/// Starts write. Returns `Ok(Some(bytes_written))` if operation was completed synchronously and
+/// `Ok(None)` if it is pending.
+///
+/// # Unsafety
+/// `buf` must stay valid until operation is completed or cancelled.
+pub unsafe fn write_file(
+    handle: RawHandle,
+    buf: *const u8,
+    len: u32,
+    ovl: &mut Overlapped,
+) -> io::Result<Option<u32>> {
+    match *ovl {
+        Overlapped::Os(ref mut ovl) => os::write_file(handle, buf, len, ovl),
+        Overlapped::Memory(ref mut ovl) => memory::write_file(buf, len, ovl),
+    }
+}
+
+/// Limit of buffers of a vectored operation, the rest is left for the next call.
+pub const IOV_MAX: usize = 1024;
+
+/// Starts vectored read. Returns `None` if pipe doesn't support it, then the caller should read
+/// into a single buffer with `read_file`, otherwise the same as `read_file`.
+///
+/// # Unsafety
+/// `bufs` must stay valid until operation is completed or cancelled.
+pub unsafe fn read_file_vectored(
+    handle: RawHandle,
+    bufs: &mut [IoSliceMut<'_>],
+    ovl: &mut Overlapped,
+) -> Option<io::Result<Option<u32>>> {
+    match *ovl {
+        Overlapped::Os(ref mut ovl) => os::read_file_vectored(handle, bufs, ovl),
+        Overlapped::Memory(_) => None,
+    }
+}
+
+/// Starts vectored write. Returns `None` if pipe doesn't support it, then the caller should
+/// gather buffers for `write_file`, otherwise the same as `write_file`.
+///
+/// # Unsafety
+/// `bufs` must stay valid until operation is completed or cancelled.
+pub unsafe fn write_file_vectored(
+    handle: RawHandle,
+    bufs: &[IoSlice<'_>],
+    ovl: &mut Overlapped,
+) -> Option<io::Result<Option<u32>>> {
+    match *ovl {
+        Overlapped::Os(ref mut ovl) => os::write_file_vectored(handle, bufs, ovl),
+        Overlapped::Memory(_) => None,
+    }
+}
+
+/// Data in a pipe that can be read without waiting, see `peek_named_pipe`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeekInfo {
+    /// Number of bytes copied into the buffer.
+    pub bytes_read: u32,
+    /// Total number of bytes in the pipe.
+    pub bytes_available: u32,
+    /// Number of bytes of the current message that are left after the copied ones, `0` for
+    /// byte-mode pipes.
+    pub message_bytes_left: u32,
+}
+
+/// Copies data from the pipe into `buf` without removing it. Doesn't wait for data and fails
+/// with broken pipe error if the pipe is empty and the other side is closed. Must not be called
+/// while a read is pending on `ovl`.
+pub fn peek_named_pipe(handle: &Handle, ovl: &Overlapped, buf: &mut [u8]) -> io::Result<PeekInfo> {
+    match (handle, ovl) {
+        (Handle::Os(handle), Overlapped::Os(ovl)) => os::peek_named_pipe(handle, ovl, buf),
+        (Handle::Memory(handle), Overlapped::Memory(_)) => memory::peek_named_pipe(handle, buf),
+        _ => unreachable!(),
+    }
+}
+
+/// Waits for completion of an operation and returns number of bytes transferred.
+pub fn get_overlapped_result(handle: RawHandle, ovl: &mut Overlapped) -> io::Result<u32> {
+    match *ovl {
+        Overlapped::Os(ref mut ovl) => os::get_overlapped_result(handle, ovl),
+        Overlapped::Memory(ref mut ovl) => memory::get_overlapped_result(ovl),
+    }
+}
+
+/// Cancels pending operation and waits until it is finished, so it's safe to deallocate the
+/// buffer even if cancellation failed. Returns number of bytes transferred before the
+/// cancellation.
+pub fn cancel_io(handle: RawHandle, ovl: &mut Overlapped) -> io::Result<u32> {
+    match *ovl {
+        Overlapped::Os(ref mut ovl) => os::cancel_io(handle, ovl),
+        Overlapped::Memory(ref mut ovl) => memory::cancel_io(ovl),
+    }
+}
+
+/// Returns `Ok(true)` if operation was completed and `Ok(false)` on timeout. Fails with
+/// `shutdown_error` if any of `shutdown` is triggered before the operation is completed.
+pub fn wait_for_single(
+    handle: RawHandle,
+    ovl: &mut Overlapped,
+    timeout: u32,
+    shutdown: &[&Shutdown],
+) -> io::Result<bool> {
+    let completed = match *ovl {
+        Overlapped::Os(ref mut ovl) => {
+            os::wait_for_single(handle, ovl, timeout, &os_shutdown(shutdown))?
+        }
+        Overlapped::Memory(ref mut ovl) => {
+            memory::wait_for_single(ovl, timeout, &|| is_triggered(shutdown))?
+        }
+    };
+    if !completed && is_triggered(shutdown) {
+        return Err(shutdown_error());
+    }
+    Ok(completed)
+}
+
+/// Waits for any (or all if `all` is set) of operations. Returns `Ok(None)` on timeout. Fails
+/// with `shutdown_error` if any of `shutdown` is triggered before.
+pub fn wait_for_multiple(
+    list: &[(RawHandle, &Overlapped)],
+    all: bool,
+    timeout: u32,
+    shutdown: &[&Shutdown],
+) -> io::Result<Option<usize>> {
+    let mut os_list = Vec::with_capacity(list.len());
+    let mut memory_list = Vec::with_capacity(list.len());
+    for &(handle, ovl) in list.iter() {
+        match *ovl {
+            Overlapped::Os(ref ovl) => os_list.push((handle, ovl)),
+            Overlapped::Memory(ref ovl) => memory_list.push(ovl),
+        }
+    }
+
+    let result = match (os_list.is_empty(), memory_list.is_empty()) {
+        (_, true) => os::wait_for_multiple(&os_list, all, timeout, &os_shutdown(shutdown))?,
+        (true, false) => {
+            memory::wait_for_multiple(&memory_list, all, timeout, &|| is_triggered(shutdown))?
+        }
+        (false, false) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't wait on in-memory and OS pipes at the same time",
+            ))
+        }
+    };
+    if result.is_none() && is_triggered(shutdown) {
+        return Err(shutdown_error());
+    }
+    Ok(result)
+}
+
+/// Waits for any operation and returns indexes of all ready operations (empty on timeout).
+/// Unlike `wait_for_multiple` number of operations isn't limited. Fails with `shutdown_error`
+/// if any of `shutdown` is triggered before any operation is ready.
+pub fn wait_for_ready(
+    list: &[(RawHandle, &Overlapped)],
+    timeout: u32,
+    shutdown: &[&Shutdown],
+) -> io::Result<Vec<usize>> {
+    let mut os_list = Vec::with_capacity(list.len());
+    let mut memory_list = Vec::with_capacity(list.len());
+    for &(handle, ovl) in list.iter() {
+        match *ovl {
+            Overlapped::Os(ref ovl) => os_list.push((handle, ovl)),
+            Overlapped::Memory(ref ovl) => memory_list.push(ovl),
+        }
+    }
+
+    let ready = match (os_list.is_empty(), memory_list.is_empty()) {
+        (_, true) => os::wait_for_ready(&os_list, timeout, &os_shutdown(shutdown))?,
+        (true, false) => memory::wait_for_ready(&memory_list, timeout, &|| is_triggered(shutdown))?,
+        (false, false) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't wait on in-memory and OS pipes at the same time",
+            ))
+        }
+    };
+    if ready.is_empty() && is_triggered(shutdown) {
+        return Err(shutdown_error());
+    }
+    Ok(ready)
+}
+
+/// Shared state of a `ShutdownHandle`. Once triggered it wakes all the waits it's passed to.
+#[derive(Debug)]
+pub struct Shutdown {
+    triggered: AtomicBool,
+    os: os::Shutdown,
+}
+
+impl Shutdown {
+    pub fn new() -> io::Result<Shutdown> {
+        Ok(Shutdown {
+            triggered: AtomicBool::new(false),
+            os: os::Shutdown::new()?,
+        })
+    }
+
+    pub fn trigger(&self) -> io::Result<()> {
+        if !self.triggered.swap(true, Ordering::SeqCst) {
+            self.os.trigger()?;
+            memory::wake_all();
+        }
+        Ok(())
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+}
+
+fn is_triggered(shutdown: &[&Shutdown]) -> bool {
+    shutdown.iter().any(|shutdown| shutdown.is_triggered())
+}
+
+fn os_shutdown<'a>(shutdown: &[&'a Shutdown]) -> Vec<&'a os::Shutdown> {
+    shutdown.iter().map(|shutdown| &shutdown.os).collect()
+}
+
+/// Milliseconds left until `deadline` rounded up, so waits don't finish early. Never `INFINITE`.
+pub fn remaining_ms(deadline: Instant) -> u32 {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    remaining
+        .as_nanos()
+        .div_ceil(1_000_000)
+        .min(INFINITE as u128 - 1) as u32
+}
+
+/// Error of a wait that was interrupted by a `ShutdownHandle`.
+#[derive(Debug)]
+struct ShutDown;
+
+impl fmt::Display for ShutDown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("operation was interrupted by shutdown")
+    }
+}
+
+impl Error for ShutDown {}
+
+fn shutdown_error() -> io::Error {
+    io::Error::other(ShutDown)
+}
+
+/// Watches pending operations of OS pipes. Operations are registered under a key and `wait`
+/// returns keys of operations that may be finished (notifications may be spurious). Every
+/// notification is one-shot: operation that is still pending must be registered again. OS
+/// registration of a pipe is kept for its following operations.
+#[derive(Debug)]
+pub struct Poller(os::Poller);
+
+impl Poller {
+    pub fn new() -> io::Result<Poller> {
+        os::Poller::new().map(Poller)
+    }
+
+    /// Starts watching a pending operation (or watches it again after a notification).
+    pub fn register(&mut self, handle: RawHandle, ovl: &Overlapped, key: usize) -> io::Result<()> {
+        match *ovl {
+            Overlapped::Os(ref ovl) => self.0.register(handle, ovl, key),
+            Overlapped::Memory(_) => Err(memory_unsupported()),
+        }
+    }
+
+    pub fn deregister(&mut self, key: usize) -> io::Result<()> {
+        self.0.deregister(key)
+    }
+
+    pub fn wait(&mut self, timeout: u32) -> io::Result<Vec<usize>> {
+        self.0.wait(timeout)
+    }
+}
+
+/// Registration of a waker. Waker won't be woken once it's dropped.
+#[derive(Debug)]
+#[allow(dead_code)] // Registrations are only held to be dropped.
+pub enum Registration {
+    Os(os::Registration),
+    Memory(memory::Registration),
+}
+
+/// Wakes `waker` once, when operation may be finished. Registration must be dropped before the
+/// operation.
+pub fn register_waker(
+    handle: RawHandle,
+    ovl: &Overlapped,
+    waker: Waker,
+) -> io::Result<Registration> {
+    match *ovl {
+        Overlapped::Os(ref ovl) => os::register_waker(handle, ovl, waker).map(Registration::Os),
+        Overlapped::Memory(ref ovl) => Ok(Registration::Memory(memory::register_waker(ovl, waker))),
+    }
+}
+
+/// Registers pipe in a mio registry. In-memory pipes aren't supported.
+#[cfg(feature = "mio")]
+pub fn mio_register(
+    handle: &Handle,
+    ovl: &mut Overlapped,
+    registry: &mio::Registry,
+    token: mio::Token,
+    interests: mio::Interest,
+) -> io::Result<()> {
+    match (handle, ovl) {
+        (Handle::Os(handle), Overlapped::Os(ovl)) => {
+            os::mio_register(handle, ovl, registry, token, interests)
+        }
+        _ => Err(memory_unsupported()),
+    }
+}
+
+#[cfg(feature = "mio")]
+pub fn mio_reregister(
+    handle: &Handle,
+    ovl: &mut Overlapped,
+    registry: &mio::Registry,
+    token: mio::Token,
+    interests: mio::Interest,
+) -> io::Result<()> {
+    match (handle, ovl) {
+        (Handle::Os(handle), Overlapped::Os(ovl)) => {
+            os::mio_reregister(handle, ovl, registry, token, interests)
+        }
+        _ => Err(memory_unsupported()),
+    }
+}
+
+#[cfg(feature = "mio")]
+pub fn mio_deregister(
+    handle: &Handle,
+    ovl: &mut Overlapped,
+    registry: &mio::Registry,
+) -> io::Result<()> {
+    match (handle, ovl) {
+        (Handle::Os(handle), Overlapped::Os(ovl)) => os::mio_deregister(handle, ovl, registry),
+        _ => Err(memory_unsupported()),
+    }
+}
+
+pub fn is_disconnected(err: &io::Error) -> bool {
+    os::is_disconnected(err) || memory::is_disconnected(err)
+}
+
+pub fn is_busy(err: &io::Error) -> bool {
+    os::is_busy(err)
+}
+
+pub fn is_cancelled(err: &io::Error) -> bool {
+    os::is_cancelled(err) || err.get_ref().is_some_and(|err| err.is::<ShutDown>())
+}
+
+/// Returns `true` if a read on a message-mode pipe didn't consume the whole message.
+pub fn is_more_data(err: &io::Error) -> bool {
+    os::is_more_data(err) || memory::is_more_data(err)
+}
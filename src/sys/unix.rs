@@ -0,0 +1,2123 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Unix backend. Emulates overlapped IO of named pipes on top of Unix domain sockets.
+//!
+//! A pipe name is mapped to a socket path (see `socket_path`) guarded by a lock file next to it
+//! (see `Lock`). All instances of a pipe created within a process share a single listening
+//! socket, each `ConnectingServer` accepts one client from it. Operations never outlive the call
+//! that drives them, so "pending" operation is just a description of what to do once the socket
+//! becomes ready. Zero-sized pipe buffers are emulated, see `Control`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{IoSlice, IoSliceMut, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::Waker;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "mio")]
+use mio::unix::SourceFd;
+
+use super::PeekInfo;
+use crate::{OpenMode, PipeMode, PipeName, PipeOptions};
+
+pub type RawHandle = RawFd;
+
+pub const NULL_HANDLE: RawHandle = -1;
+
+pub const INFINITE: u32 = 0xFFFFFFFF;
+
+/// Listening sockets of pipes created by this process.
+static LISTENERS: Mutex<BTreeMap<PathBuf, Weak<Listener>>> = Mutex::new(BTreeMap::new());
+
+fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+fn cvt_size(result: libc::ssize_t) -> io::Result<usize> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result as usize)
+    }
+}
+
+fn would_block(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::Interrupted
+}
+
+fn broken_pipe() -> io::Error {
+    io::Error::from_raw_os_error(libc::EPIPE)
+}
+
+/// Reported by a read that didn't fit the whole message (analog of `ERROR_MORE_DATA`).
+fn more_data() -> io::Error {
+    io::Error::from_raw_os_error(libc::EMSGSIZE)
+}
+
+/// Message-mode pipes are emulated with `SOCK_SEQPACKET` sockets.
+fn socket_type(pipe_mode: PipeMode) -> libc::c_int {
+    match pipe_mode {
+        PipeMode::Byte => libc::SOCK_STREAM,
+        PipeMode::Message => libc::SOCK_SEQPACKET,
+    }
+}
+
+fn is_seqpacket(fd: RawFd) -> io::Result<bool> {
+    let mut ty: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    unsafe {
+        cvt(libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut ty as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        ))?;
+    }
+    Ok(ty == libc::SOCK_SEQPACKET)
+}
+
+#[derive(Debug)]
+struct Fd(RawFd);
+
+impl Fd {
+    fn socket(ty: libc::c_int) -> io::Result<Fd> {
+        let fd = unsafe {
+            cvt(libc::socket(
+                libc::AF_UNIX,
+                ty | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            ))?
+        };
+        Ok(Fd(fd))
+    }
+
+    fn set_buffers(&self, in_buffer: u32, out_buffer: u32) -> io::Result<()> {
+        // Kernel will clamp values to its own limits.
+        let options = [(libc::SO_RCVBUF, in_buffer), (libc::SO_SNDBUF, out_buffer)];
+        for &(option, value) in options.iter() {
+            let value = value.min(libc::c_int::MAX as u32) as libc::c_int;
+            unsafe {
+                cvt(libc::setsockopt(
+                    self.0,
+                    libc::SOL_SOCKET,
+                    option,
+                    &value as *const libc::c_int as *const libc::c_void,
+                    mem::size_of::<libc::c_int>() as libc::socklen_t,
+                ))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn duplicate(fd: RawFd) -> io::Result<Fd> {
+        let fd = unsafe { cvt(libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0))? };
+        Ok(Fd(fd))
+    }
+
+    fn shutdown(&self, how: libc::c_int) -> io::Result<()> {
+        match unsafe { cvt(libc::shutdown(self.0, how)) } {
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOTCONN) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        let _ = unsafe { libc::close(self.0) };
+    }
+}
+
+fn sockaddr(path: &Path) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    check_socket_path(path)?;
+    let bytes = path.as_os_str().as_bytes();
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes.iter()) {
+        *dst = *src as libc::c_char;
+    }
+
+    let len = mem::size_of::<libc::sa_family_t>() + bytes.len() + 1;
+    Ok((addr, len as libc::socklen_t))
+}
+
+/// Fails if `path` doesn't fit into `sun_path` of a socket address with its terminating zero.
+pub fn check_socket_path(path: &Path) -> io::Result<()> {
+    let max_len = mem::size_of::<libc::sockaddr_un>() - mem::size_of::<libc::sa_family_t>() - 1;
+    if path.as_os_str().len() > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("socket path {:?} is longer than {} bytes", path, max_len),
+        ));
+    }
+    Ok(())
+}
+
+/// Maps pipe name to a socket path.
+///
+/// Windows-style names (`\\.\pipe\<name>`) are mapped by `PipeName::socket_path`. Any other
+/// name is a Unix-only extension: it's used as a socket path as is.
+pub fn socket_path(name: &OsStr) -> io::Result<PathBuf> {
+    if !name.as_bytes().starts_with(br"\\") {
+        let path = PathBuf::from(name);
+        check_socket_path(&path)?;
+        return Ok(path);
+    }
+    let name = PipeName::new(name)?;
+    if !name.is_local() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "remote pipes are not supported on this platform",
+        ));
+    }
+    Ok(name.socket_path()?)
+}
+
+/// Binds listening socket of a byte-mode pipe for an async runtime. Returned handle keeps the
+/// socket registered as the listener of the pipe.
+#[cfg(feature = "tokio")]
+pub fn bind_std_listener(
+    options: &PipeOptions,
+) -> io::Result<(Handle, std::os::unix::net::UnixListener)> {
+    use std::os::unix::io::FromRawFd;
+
+    if options.pipe_mode == PipeMode::Message {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "message-mode pipes are not supported by async listener",
+        ));
+    }
+    let handle = create_named_pipe(options, options.first)?;
+    if handle
+        .listener
+        .as_ref()
+        .is_some_and(|listener| listener.has_control())
+    {
+        return Err(zero_buffers_unsupported());
+    }
+    let fd = unsafe { cvt(libc::fcntl(handle.raw(), libc::F_DUPFD_CLOEXEC, 0))? };
+    Ok((handle, unsafe {
+        std::os::unix::net::UnixListener::from_raw_fd(fd)
+    }))
+}
+
+/// Returns socket path of a pipe for an async runtime.
+#[cfg(feature = "tokio")]
+pub fn async_socket_path(name: &OsStr) -> io::Result<PathBuf> {
+    let path = socket_path(name)?;
+    match Lock::read_buffers(&path) {
+        Some((in_buffer, out_buffer)) if in_buffer == 0 || out_buffer == 0 => {
+            Err(zero_buffers_unsupported())
+        }
+        _ => Ok(path),
+    }
+}
+
+/// Async runtimes and mio watch the socket only, so they can't emulate zero-sized buffers.
+#[cfg(any(feature = "tokio", feature = "mio"))]
+fn zero_buffers_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pipes with zero-sized buffers are not supported",
+    )
+}
+
+/// Applies pipe options to a socket accepted from the listener of `handle`.
+#[cfg(feature = "tokio")]
+pub fn setup_accepted(handle: &Handle, fd: RawFd) -> io::Result<()> {
+    // Descriptor is owned by the caller.
+    let fd = mem::ManuallyDrop::new(Fd(fd));
+    match handle.listener {
+        Some(ref listener) => listener.setup(&fd),
+        None => unreachable!(),
+    }
+}
+
+/// Returns path of the lock file of a socket: `.<socket file name>.lock` next to it. Socket
+/// files of `\\.\pipe\` names never start with a dot (see `PipeName::socket_path`), so it
+/// isn't a socket of another pipe.
+fn lock_path(path: &Path) -> io::Result<PathBuf> {
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "pipe name doesn't name a socket file",
+        )
+    })?;
+    let mut name = OsString::from(".");
+    name.push(file_name);
+    name.push(".lock");
+    Ok(path.with_file_name(name))
+}
+
+/// Exclusive `flock` of the lock file of a socket. Socket file outlives a crashed server, but
+/// its lock is released by the OS, so a socket is stale if its lock can be taken.
+#[derive(Debug)]
+struct Lock {
+    file: File,
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Takes the lock of the socket at `path`. Returns the lock and whether the lock file was
+    /// left behind by a previous owner, fails with `EADDRINUSE` if the lock is held.
+    fn acquire(path: &Path) -> io::Result<(Lock, bool)> {
+        let path = lock_path(path)?;
+        loop {
+            let mut options = OpenOptions::new();
+            options.read(true).write(true).mode(0o644);
+            let (file, existed) = match options.clone().create_new(true).open(&path) {
+                Ok(file) => (file, false),
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    match options.open(&path) {
+                        Ok(file) => (file, true),
+                        // Removed by its owner in the meantime.
+                        Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                        Err(err) => return Err(err),
+                    }
+                }
+                Err(err) => return Err(err),
+            };
+
+            let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+            match cvt(result) {
+                Ok(_) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(io::Error::from_raw_os_error(libc::EADDRINUSE))
+                }
+                Err(err) => return Err(err),
+            }
+
+            // Previous owner might have removed the file between `open` and `flock`.
+            let locked = file.metadata()?;
+            match std::fs::metadata(&path) {
+                Ok(ref current)
+                    if current.dev() == locked.dev() && current.ino() == locked.ino() =>
+                {
+                    return Ok((Lock { file, path }, existed))
+                }
+                Ok(_) => continue,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Lock {
+    /// Publishes buffer sizes of the pipe for its clients, see `Control`.
+    fn write_buffers(&self, in_buffer: u32, out_buffer: u32) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file
+            .write_all_at(format!("{} {}\n", in_buffer, out_buffer).as_bytes(), 0)
+    }
+
+    /// Returns buffer sizes published by the server of the socket at `path`.
+    fn read_buffers(path: &Path) -> Option<(u32, u32)> {
+        let buffers = std::fs::read_to_string(lock_path(path).ok()?).ok()?;
+        let mut buffers = buffers.split_whitespace().map(|size| size.parse().ok());
+        Some((buffers.next()??, buffers.next()??))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // File is removed before the lock is released, so the next owner locks a new file.
+        let _ = std::fs::remove_file(&self.path);
+        let _ = unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Listening socket shared by instances of a pipe.
+///
+/// Windows lets a client connect only to an instance that is not connected yet, otherwise the
+/// pipe is busy. Here the backlog of the socket is kept equal to the number of such instances,
+/// so `connect` fails with `EAGAIN` once all of them are taken. Linux lets one more client into
+/// the backlog than `listen` is asked for, so a listener without instances takes this place with
+/// its own client (a placeholder) that is dropped once it's accepted.
+#[derive(Debug)]
+struct Listener {
+    fd: Fd,
+    path: PathBuf,
+    /// Taken before the socket is bound and released once it's removed, see `Listener::drop`.
+    lock: Option<Lock>,
+    open_mode: OpenMode,
+    message: bool,
+    in_buffer: u32,
+    out_buffer: u32,
+    backlog: Mutex<Backlog>,
+}
+
+#[derive(Debug, Default)]
+struct Backlog {
+    /// Instances that are not connected, see `Instance`.
+    instances: usize,
+    placeholder: Option<Placeholder>,
+}
+
+/// Client that occupies the backlog of a listener without instances.
+#[derive(Debug)]
+struct Placeholder {
+    /// Kept open while the placeholder waits in the backlog.
+    _fd: Fd,
+    /// Automatically bound address, so the placeholder is recognized when it's accepted.
+    addr: libc::sockaddr_un,
+    len: libc::socklen_t,
+}
+
+impl Placeholder {
+    fn connect(path: &Path, ty: libc::c_int) -> io::Result<Placeholder> {
+        let fd = Fd::socket(ty)?;
+        let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        let mut len = mem::size_of::<libc::sa_family_t>() as libc::socklen_t;
+        unsafe {
+            // Binding of just the address family picks a unique abstract address.
+            cvt(libc::bind(
+                fd.0,
+                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                len,
+            ))?;
+            len = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+            cvt(libc::getsockname(
+                fd.0,
+                &mut addr as *mut libc::sockaddr_un as *mut libc::sockaddr,
+                &mut len,
+            ))?;
+        }
+        let (target, target_len) = sockaddr(path)?;
+        unsafe {
+            cvt(libc::connect(
+                fd.0,
+                &target as *const libc::sockaddr_un as *const libc::sockaddr,
+                target_len,
+            ))?;
+        }
+        Ok(Placeholder { _fd: fd, addr, len })
+    }
+
+    /// Returns `true` if `addr` is the address of this placeholder.
+    fn is(&self, addr: &libc::sockaddr_un, len: libc::socklen_t) -> bool {
+        let path_len = self.len as usize - mem::size_of::<libc::sa_family_t>();
+        len == self.len && addr.sun_path[..path_len] == self.addr.sun_path[..path_len]
+    }
+}
+
+/// Instance of a pipe that is not connected, counted in the backlog of its listener.
+#[derive(Debug)]
+struct Instance(Arc<Listener>);
+
+impl Instance {
+    fn new(listener: &Arc<Listener>) -> Instance {
+        let mut backlog = listener.lock_backlog();
+        backlog.instances += 1;
+        listener.listen(&mut backlog);
+        Instance(listener.clone())
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let mut backlog = self.0.lock_backlog();
+        backlog.instances -= 1;
+        self.0.listen(&mut backlog);
+    }
+}
+
+impl Listener {
+    fn bind(options: &PipeOptions, path: PathBuf) -> io::Result<Listener> {
+        let (addr, len) = sockaddr(&path)?;
+        let (lock, existed) = Lock::acquire(&path)?;
+        lock.write_buffers(options.in_buffer, options.out_buffer)?;
+        let fd = Fd::socket(socket_type(options.pipe_mode))?;
+
+        let bind = || unsafe {
+            cvt(libc::bind(
+                fd.0,
+                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                len,
+            ))
+        };
+
+        if let Err(err) = bind() {
+            // Socket without a lock file doesn't belong to a pipe, so it's left alone.
+            if err.raw_os_error() != Some(libc::EADDRINUSE) || !existed {
+                return Err(err);
+            }
+            // Socket file was left behind by a process that is gone.
+            let _ = std::fs::remove_file(&path);
+            bind()?;
+        }
+
+        let listener = Listener {
+            fd,
+            path,
+            lock: Some(lock),
+            open_mode: options.open_mode.clone(),
+            message: options.pipe_mode == PipeMode::Message,
+            in_buffer: options.in_buffer,
+            out_buffer: options.out_buffer,
+            backlog: Mutex::new(Backlog::default()),
+        };
+        // The first instance is about to be created.
+        unsafe { cvt(libc::listen(listener.fd.0, 0))? };
+        Ok(listener)
+    }
+
+    fn lock_backlog(&self) -> MutexGuard<'_, Backlog> {
+        self.backlog.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Makes the backlog of the socket match `backlog`.
+    fn listen(&self, backlog: &mut Backlog) {
+        // Failure leaves a client in the backlog without an instance, just like a client that
+        // has connected to an instance which is dropped afterwards.
+        if backlog.instances == 0 && backlog.placeholder.is_none() {
+            let _ = unsafe { libc::listen(self.fd.0, 0) };
+            let ty = socket_type(match self.message {
+                true => PipeMode::Message,
+                false => PipeMode::Byte,
+            });
+            backlog.placeholder = Placeholder::connect(&self.path, ty).ok();
+        }
+        let places = backlog.instances + backlog.placeholder.is_some() as usize;
+        let _ = unsafe { libc::listen(self.fd.0, places.saturating_sub(1) as libc::c_int) };
+    }
+
+    /// Accepts a client skipping the placeholder.
+    fn accept(&self) -> io::Result<Fd> {
+        loop {
+            let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+            let fd = unsafe {
+                Fd(cvt(libc::accept4(
+                    self.fd.0,
+                    &mut addr as *mut libc::sockaddr_un as *mut libc::sockaddr,
+                    &mut len,
+                    libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                ))?)
+            };
+            let mut backlog = self.lock_backlog();
+            match backlog.placeholder {
+                Some(ref placeholder) if placeholder.is(&addr, len) => {
+                    backlog.placeholder = None;
+                    self.listen(&mut backlog);
+                }
+                _ => return Ok(fd),
+            }
+        }
+    }
+
+    /// Whether clients pass a control socket once connected, see `Control`.
+    fn has_control(&self) -> bool {
+        self.in_buffer == 0 || self.out_buffer == 0
+    }
+
+    /// Applies pipe options to an accepted socket.
+    fn setup(&self, fd: &Fd) -> io::Result<()> {
+        fd.set_buffers(self.in_buffer, self.out_buffer)?;
+        match self.open_mode {
+            OpenMode::Read => fd.shutdown(libc::SHUT_WR)?,
+            OpenMode::Write => fd.shutdown(libc::SHUT_RD)?,
+            OpenMode::Duplex => (),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // `create_named_pipe` waits until the entry is removed, so the socket and the lock are
+        // released before a new listener of the pipe is bound.
+        let mut listeners = LISTENERS.lock().unwrap_or_else(|err| err.into_inner());
+        listeners.remove(&self.path);
+        let _ = std::fs::remove_file(&self.path);
+        self.lock = None;
+    }
+}
+
+/// Emulation of zero-sized pipe buffers.
+///
+/// Socket buffers can't be smaller than a kernel limit, so a write into a pipe without a buffer
+/// is held back until the reader waits for data: once a read would block, reader sends the size
+/// of its buffer over a `SOCK_SEQPACKET` socket pair (the client passes one end with
+/// `SCM_RIGHTS` right after `connect`) and writer doesn't send more than that. A message is sent
+/// as a whole once the reader waits for any data.
+///
+/// Demand of a cancelled read isn't taken back, so the writer may send that much data before the
+/// next read.
+#[derive(Debug)]
+struct Control {
+    fd: Fd,
+    /// Reads of this end are waited for by the writer.
+    read_zero: bool,
+    /// Writes of this end wait for reads of the peer.
+    write_zero: bool,
+    message: bool,
+    /// Number of bytes that the peer is still expected to send since the last demand.
+    demanded: Mutex<usize>,
+    /// Number of bytes that the peer waits for.
+    credit: Mutex<usize>,
+}
+
+impl Control {
+    fn new(fd: Fd, read_zero: bool, write_zero: bool, message: bool) -> Control {
+        Control {
+            fd,
+            read_zero,
+            write_zero,
+            message,
+            demanded: Mutex::new(0),
+            credit: Mutex::new(0),
+        }
+    }
+
+    /// Creates control socket pair of a client connected with `fd` and passes one end to the
+    /// server.
+    fn connect(fd: &Fd, read_zero: bool, write_zero: bool, message: bool) -> io::Result<Control> {
+        let mut fds = [0; 2];
+        unsafe {
+            cvt(libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            ))?;
+        }
+        let (control, peer) = (Fd(fds[0]), Fd(fds[1]));
+
+        let mut byte = 0u8;
+        let mut iov = libc::iovec {
+            iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+            iov_len: 1,
+        };
+        let mut cmsg = Vec::new();
+        let msg = fd_message(&mut iov, &mut cmsg);
+        unsafe {
+            let header = libc::CMSG_FIRSTHDR(&msg);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(header) as *mut RawFd, peer.0);
+        }
+        loop {
+            match cvt_size(unsafe { libc::sendmsg(fd.0, &msg, libc::MSG_NOSIGNAL) }) {
+                Ok(_) => break,
+                Err(ref err) if would_block(err) => {
+                    poll(&mut [pollfd(fd.0, libc::POLLOUT)], None)?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Control::new(control, read_zero, write_zero, message))
+    }
+
+    /// Receives control socket passed by `Control::connect` to the accepted socket `fd`. Returns
+    /// `Ok(None)` if the client is closed without passing it.
+    fn accept(fd: &Fd) -> io::Result<Option<Fd>> {
+        let mut byte = 0u8;
+        let mut iov = libc::iovec {
+            iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+            iov_len: 1,
+        };
+        let mut cmsg = Vec::new();
+        let mut msg = fd_message(&mut iov, &mut cmsg);
+        let n = cvt_size(unsafe { libc::recvmsg(fd.0, &mut msg, libc::MSG_CMSG_CLOEXEC) })?;
+        if n == 0 {
+            return Ok(None);
+        }
+        unsafe {
+            let header = libc::CMSG_FIRSTHDR(&msg);
+            if !header.is_null()
+                && (*header).cmsg_level == libc::SOL_SOCKET
+                && (*header).cmsg_type == libc::SCM_RIGHTS
+            {
+                return Ok(Some(Fd(ptr::read_unaligned(
+                    libc::CMSG_DATA(header) as *const RawFd
+                ))));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "client didn't pass the control socket",
+        ))
+    }
+
+    /// Lets the peer send up to `len` bytes unless it may still send data of a previous demand.
+    fn demand(&self, len: usize) {
+        let mut demanded = self.demanded.lock().unwrap_or_else(|err| err.into_inner());
+        if *demanded > 0 || len == 0 {
+            return;
+        }
+        let demand = len.min(u32::MAX as usize) as u32;
+        let result = unsafe {
+            libc::send(
+                self.fd.0,
+                &demand as *const u32 as *const libc::c_void,
+                mem::size_of::<u32>(),
+                libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+            )
+        };
+        // Fails if the peer is gone, then the read fails by itself.
+        if result > 0 {
+            *demanded = len;
+        }
+    }
+
+    /// Accounts `n` bytes received from the peer.
+    fn received(&self, n: usize) {
+        let mut demanded = self.demanded.lock().unwrap_or_else(|err| err.into_inner());
+        *demanded = if self.message {
+            0
+        } else {
+            demanded.saturating_sub(n)
+        };
+    }
+
+    /// Receives demands of the peer and returns the number of bytes it waits for.
+    fn credit(&self) -> io::Result<MutexGuard<'_, usize>> {
+        let mut credit = self.credit.lock().unwrap_or_else(|err| err.into_inner());
+        loop {
+            let mut demand = 0u32;
+            let result = unsafe {
+                libc::recv(
+                    self.fd.0,
+                    &mut demand as *mut u32 as *mut libc::c_void,
+                    mem::size_of::<u32>(),
+                    0,
+                )
+            };
+            match cvt_size(result) {
+                Ok(0) => return Err(broken_pipe()),
+                // The latest demand replaces the previous ones.
+                Ok(_) => *credit = demand as usize,
+                Err(ref err) if would_block(err) => return Ok(credit),
+                Err(ref err) if err.raw_os_error() == Some(libc::ECONNRESET) => {
+                    return Err(broken_pipe())
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Sends up to `len` bytes with `send` once the peer waits for data, fails with
+    /// `WouldBlock` until then.
+    fn send<F>(control: Option<&Control>, len: usize, send: F) -> io::Result<usize>
+    where
+        F: FnOnce(usize) -> io::Result<usize>,
+    {
+        let control = match control {
+            Some(control) if control.write_zero => control,
+            _ => return send(len),
+        };
+        let mut credit = control.credit()?;
+        if *credit == 0 {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        if control.message {
+            let n = send(len)?;
+            *credit = 0;
+            return Ok(n);
+        }
+        let n = send(len.min(*credit))?;
+        *credit -= n;
+        Ok(n)
+    }
+
+    /// Returns `true` if writes wait for the peer to demand data.
+    fn is_write_blocked(&self) -> bool {
+        self.write_zero && *self.credit.lock().unwrap_or_else(|err| err.into_inner()) == 0
+    }
+}
+
+/// Returns header of a message of `iov` with room for a single descriptor in `cmsg`.
+fn fd_message(iov: &mut libc::iovec, cmsg: &mut Vec<u64>) -> libc::msghdr {
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    cmsg.resize(space.div_ceil(mem::size_of::<u64>()), 0);
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    msg
+}
+
+/// Either a connected socket, or a listening socket shared by pipe instances, or both
+/// (connected server keeps its listener to be able to accept a new client after disconnection).
+#[derive(Debug)]
+pub struct Handle {
+    fd: Option<Fd>,
+    listener: Option<Arc<Listener>>,
+    /// Shared by duplicates of a connected socket.
+    control: Option<Arc<Control>>,
+    /// Rest of a partially read message, shared by duplicates of a connected socket.
+    remainder: Remainder,
+    /// Set for an instance that is not connected.
+    instance: Option<Instance>,
+}
+
+/// Rest of a message that didn't fit into the buffer of a previous read.
+type Remainder = Arc<Mutex<Vec<u8>>>;
+
+impl Handle {
+    pub fn raw(&self) -> RawHandle {
+        match (&self.fd, &self.listener) {
+            (Some(fd), _) => fd.0,
+            (None, Some(listener)) => listener.fd.0,
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Op {
+    Idle,
+    Connect,
+    Read {
+        buf: *mut u8,
+        len: usize,
+    },
+    Write {
+        buf: *const u8,
+        len: usize,
+        done: usize,
+    },
+    /// Stream sockets only, see `read_file_vectored`.
+    ReadVectored {
+        iov: Vec<libc::iovec>,
+    },
+    /// Buffers that are not sent yet, `done` bytes of `len` are sent.
+    WriteVectored {
+        iov: Vec<libc::iovec>,
+        len: usize,
+        done: usize,
+    },
+}
+
+/// Client accepted by `Op::Connect`. It's behind a mutex, so waits that only borrow the
+/// operation can accept clients (see `Overlapped::accept`).
+#[derive(Debug, Default)]
+struct Accepted {
+    fd: Option<Fd>,
+    /// Control socket passed by the client.
+    control: Option<Fd>,
+    /// Whether the client is done with the handshake (passed its control socket or closed).
+    handshaken: bool,
+    /// Error of an accept made by a wait.
+    error: Option<io::Error>,
+}
+
+#[derive(Debug)]
+pub struct Overlapped {
+    op: Op,
+    signaled: bool,
+    result: Option<io::Result<u32>>,
+    accepted: Mutex<Accepted>,
+    /// Listener of a pipe instance that waits for a client, set by `connect_named_pipe`.
+    listener: Option<Arc<Listener>>,
+    /// Whether accepted clients pass a control socket.
+    handshake: bool,
+    control: Option<Arc<Control>>,
+    /// Whether the socket preserves message boundaries. Queried on the first read.
+    seqpacket: Option<bool>,
+    remainder: Remainder,
+    /// Duplicate of the listening socket registered in a mio registry.
+    #[cfg(feature = "mio")]
+    mio: Option<Fd>,
+}
+
+unsafe impl Send for Overlapped {}
+unsafe impl Sync for Overlapped {}
+
+impl Overlapped {
+    pub fn new(handle: &Handle) -> io::Result<Overlapped> {
+        Ok(Overlapped {
+            op: Op::Idle,
+            signaled: false,
+            result: None,
+            accepted: Mutex::new(Accepted::default()),
+            listener: None,
+            handshake: false,
+            control: handle.control.clone(),
+            seqpacket: None,
+            remainder: handle.remainder.clone(),
+            #[cfg(feature = "mio")]
+            mio: None,
+        })
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.op = Op::Idle;
+        self.signaled = false;
+        self.result = None;
+        *self
+            .accepted
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner()) = Accepted::default();
+        self.seqpacket = None;
+        self.lock_remainder().clear();
+        Ok(())
+    }
+
+    fn start(&mut self, op: Op) {
+        self.op = op;
+        self.signaled = false;
+        self.result = None;
+    }
+
+    /// Returns `true` if operation is ready to make progress without waiting for the socket.
+    fn is_ready(&self) -> bool {
+        match self.op {
+            Op::Connect => self.signaled || self.is_accepted(),
+            Op::Read { .. } => self.signaled || !self.lock_remainder().is_empty(),
+            _ => self.signaled,
+        }
+    }
+
+    /// Returns `true` if `Op::Connect` accepted a client (or failed) and can be completed
+    /// without waiting.
+    fn is_accepted(&self) -> bool {
+        let accepted = self.lock_accepted();
+        accepted.error.is_some()
+            || (accepted.fd.is_some() && (accepted.handshaken || !self.handshake))
+    }
+
+    /// Returns `true` if operation is ready after `fd` returned by `pollfd` is polled. Instances
+    /// of a pipe share the listening socket, so `Op::Connect` is ready only once it accepts a
+    /// client itself. Errors of the accept are kept for `progress`.
+    fn poll_ready(&self, fd: &libc::pollfd) -> bool {
+        match self.op {
+            Op::Connect if fd.revents != 0 => match self.accept() {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    self.lock_accepted().error = Some(err);
+                    true
+                }
+            },
+            _ => fd.revents != 0,
+        }
+    }
+
+    fn lock_accepted(&self) -> MutexGuard<'_, Accepted> {
+        self.accepted.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn lock_remainder(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.remainder.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Accepts a client and receives its control socket without blocking. Returns `Ok(true)` once
+    /// it's done.
+    fn accept(&self) -> io::Result<bool> {
+        let mut accepted = self.lock_accepted();
+        if let Some(err) = accepted.error.take() {
+            return Err(err);
+        }
+        if accepted.fd.is_none() {
+            let listener = match self.listener {
+                Some(ref listener) => listener,
+                None => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            };
+            match listener.accept() {
+                Ok(fd) => accepted.fd = Some(fd),
+                // Another instance got the client or it's gone before it's accepted.
+                Err(ref err)
+                    if would_block(err) || err.raw_os_error() == Some(libc::ECONNABORTED) =>
+                {
+                    return Ok(false)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        if !self.handshake || accepted.handshaken {
+            return Ok(true);
+        }
+        match Control::accept(accepted.fd.as_ref().unwrap()) {
+            Ok(control) => {
+                accepted.control = control;
+                accepted.handshaken = true;
+                Ok(true)
+            }
+            Err(ref err) if would_block(err) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns descriptor and events to wait for to make progress.
+    fn pollfd(&self, handle: RawHandle) -> libc::pollfd {
+        match self.op {
+            Op::Idle => pollfd(handle, 0),
+            Op::Connect => match self.lock_accepted().fd {
+                // Waiting for the control socket.
+                Some(ref fd) => pollfd(fd.0, libc::POLLIN),
+                None => pollfd(handle, libc::POLLIN),
+            },
+            Op::Read { .. } | Op::ReadVectored { .. } => pollfd(handle, libc::POLLIN),
+            Op::Write { .. } | Op::WriteVectored { .. } => match self.control {
+                Some(ref control) if control.is_write_blocked() => {
+                    pollfd(control.fd.0, libc::POLLIN)
+                }
+                _ => pollfd(handle, libc::POLLOUT),
+            },
+        }
+    }
+
+    /// Lets the writer of a pipe without buffer know that a read waits for data.
+    fn demand(&self) {
+        let len = match self.op {
+            Op::Read { len, .. } => len,
+            Op::ReadVectored { ref iov } => iov.iter().map(|iov| iov.iov_len).sum(),
+            _ => return,
+        };
+        if let Some(ref control) = self.control {
+            if control.read_zero {
+                control.demand(len);
+            }
+        }
+    }
+
+    /// Accounts `n` bytes read from the socket.
+    fn received(&self, n: usize) {
+        if let Some(ref control) = self.control {
+            control.received(n);
+        }
+    }
+
+    /// Tries to make progress without blocking. Returns `true` once operation is completed.
+    fn progress(&mut self, handle: RawHandle) -> bool {
+        let result = match self.op {
+            Op::Idle => return self.signaled,
+            Op::Connect => match self.accept() {
+                Ok(true) => Ok(0),
+                Ok(false) => return false,
+                Err(err) => Err(err),
+            },
+            Op::Read { buf, len } => match self.seqpacket {
+                Some(true) => unsafe { self.read_message(handle, buf, len) },
+                Some(false) => {
+                    let result = unsafe { libc::recv(handle, buf as *mut libc::c_void, len, 0) };
+                    match cvt_size(result) {
+                        Ok(0) if len > 0 => Err(broken_pipe()),
+                        Ok(n) => {
+                            self.received(n);
+                            Ok(n as u32)
+                        }
+                        Err(ref err) if err.raw_os_error() == Some(libc::ECONNRESET) => {
+                            Err(broken_pipe())
+                        }
+                        result => result.map(|n| n as u32),
+                    }
+                }
+                None => match is_seqpacket(handle) {
+                    Ok(seqpacket) => {
+                        self.seqpacket = Some(seqpacket);
+                        return self.progress(handle);
+                    }
+                    Err(err) => Err(err),
+                },
+            },
+            Op::Write {
+                buf,
+                len,
+                ref mut done,
+            } => {
+                let result = Control::send(self.control.as_deref(), len - *done, |len| unsafe {
+                    cvt_size(libc::send(
+                        handle,
+                        buf.add(*done) as *const libc::c_void,
+                        len,
+                        libc::MSG_NOSIGNAL,
+                    ))
+                });
+                match result {
+                    Ok(n) => {
+                        *done += n;
+                        if *done < len {
+                            return false;
+                        }
+                        Ok(len as u32)
+                    }
+                    Err(ref err) if err.raw_os_error() == Some(libc::ECONNRESET) => {
+                        Err(broken_pipe())
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            Op::ReadVectored { ref iov } => {
+                let len = iov.iter().map(|iov| iov.iov_len).sum::<usize>();
+                let result = unsafe { libc::readv(handle, iov.as_ptr(), iov.len() as libc::c_int) };
+                match cvt_size(result) {
+                    Ok(0) if len > 0 => Err(broken_pipe()),
+                    Ok(n) => {
+                        self.received(n);
+                        Ok(n as u32)
+                    }
+                    Err(ref err) if err.raw_os_error() == Some(libc::ECONNRESET) => {
+                        Err(broken_pipe())
+                    }
+                    result => result.map(|n| n as u32),
+                }
+            }
+            Op::WriteVectored {
+                ref mut iov,
+                len,
+                ref mut done,
+            } => {
+                // `writev` can't suppress `SIGPIPE`, so `sendmsg` is used.
+                let result = Control::send(self.control.as_deref(), len - *done, |len| {
+                    let mut iov = limit(iov, len);
+                    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+                    msg.msg_iov = iov.as_mut_ptr();
+                    msg.msg_iovlen = iov.len() as _;
+                    cvt_size(unsafe { libc::sendmsg(handle, &msg, libc::MSG_NOSIGNAL) })
+                });
+                match result {
+                    Ok(n) => {
+                        *done += n;
+                        if *done < len {
+                            advance(iov, n);
+                            return false;
+                        }
+                        Ok(len as u32)
+                    }
+                    Err(ref err) if err.raw_os_error() == Some(libc::ECONNRESET) => {
+                        Err(broken_pipe())
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+        };
+
+        match result {
+            Err(ref err) if would_block(err) => {
+                self.demand();
+                false
+            }
+            result => {
+                self.op = Op::Idle;
+                self.signaled = true;
+                self.result = Some(result);
+                true
+            }
+        }
+    }
+}
+
+impl Overlapped {
+    /// Reads a message from a `SOCK_SEQPACKET` socket. If it doesn't fit into the buffer, the
+    /// rest is kept for the following reads and `more_data` error is returned.
+    ///
+    /// Note that empty messages are indistinguishable from the end of stream here.
+    unsafe fn read_message(
+        &mut self,
+        handle: RawHandle,
+        buf: *mut u8,
+        len: usize,
+    ) -> io::Result<u32> {
+        let remainder = self.remainder.clone();
+        let mut remainder = remainder.lock().unwrap_or_else(|err| err.into_inner());
+        if remainder.is_empty() {
+            let peek = libc::recv(handle, ptr::null_mut(), 0, libc::MSG_PEEK | libc::MSG_TRUNC);
+            let size = match cvt_size(peek) {
+                Ok(0) => return Err(broken_pipe()),
+                Err(ref err) if err.raw_os_error() == Some(libc::ECONNRESET) => {
+                    return Err(broken_pipe())
+                }
+                result => result?,
+            };
+            if size <= len {
+                let result = libc::recv(handle, buf as *mut libc::c_void, len, 0);
+                let n = cvt_size(result)?;
+                self.received(n);
+                return Ok(n as u32);
+            }
+            let mut message = vec![0; size];
+            let result = libc::recv(handle, message.as_mut_ptr() as *mut libc::c_void, size, 0);
+            message.truncate(cvt_size(result)?);
+            self.received(message.len());
+            *remainder = message;
+        }
+
+        let n = len.min(remainder.len());
+        ptr::copy_nonoverlapping(remainder.as_ptr(), buf, n);
+        remainder.drain(..n);
+        if remainder.is_empty() {
+            Ok(n as u32)
+        } else {
+            Err(more_data())
+        }
+    }
+}
+
+/// Removes first `n` bytes from `iov`.
+fn advance(iov: &mut Vec<libc::iovec>, mut n: usize) {
+    let sent = iov.iter().take_while(|iov| {
+        let sent = iov.iov_len <= n;
+        if sent {
+            n -= iov.iov_len;
+        }
+        sent
+    });
+    let count = sent.count();
+    iov.drain(..count);
+    if let Some(iov) = iov.first_mut() {
+        iov.iov_base = unsafe { (iov.iov_base as *mut u8).add(n) } as *mut libc::c_void;
+        iov.iov_len -= n;
+    }
+}
+
+/// Returns first `n` bytes of `iov`.
+fn limit(iov: &[libc::iovec], mut n: usize) -> Vec<libc::iovec> {
+    let mut limited = Vec::with_capacity(iov.len());
+    for iov in iov.iter() {
+        if n == 0 {
+            break;
+        }
+        let len = iov.iov_len.min(n);
+        limited.push(libc::iovec {
+            iov_base: iov.iov_base,
+            iov_len: len,
+        });
+        n -= len;
+    }
+    limited
+}
+
+/// Converts `iov` into a list of `iovec`, skipping empty buffers.
+fn iovecs<I>(iov: I) -> Vec<libc::iovec>
+where
+    I: Iterator<Item = (*mut u8, usize)>,
+{
+    iov.filter(|&(_, len)| len > 0)
+        .map(|(base, len)| libc::iovec {
+            iov_base: base as *mut libc::c_void,
+            iov_len: len,
+        })
+        .collect()
+}
+
+/// Converts `Duration` into `poll` timeout.
+fn poll_timeout(timeout: Option<Duration>) -> libc::c_int {
+    match timeout {
+        Some(timeout) => {
+            // Round up to not to spin on sub-millisecond remainders.
+            let millis = timeout.as_nanos().div_ceil(1_000_000);
+            millis.min(libc::c_int::MAX as u128) as libc::c_int
+        }
+        None => -1,
+    }
+}
+
+fn deadline(timeout: u32) -> Option<Instant> {
+    if timeout == INFINITE {
+        None
+    } else {
+        Some(Instant::now() + Duration::from_millis(timeout as u64))
+    }
+}
+
+/// Returns `None` for infinite deadline.
+fn remaining(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+fn poll(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> io::Result<usize> {
+    let result = unsafe {
+        libc::poll(
+            fds.as_mut_ptr(),
+            fds.len() as libc::nfds_t,
+            poll_timeout(timeout),
+        )
+    };
+    match cvt(result) {
+        Ok(n) => Ok(n as usize),
+        Err(ref err) if err.kind() == io::ErrorKind::Interrupted => Ok(0),
+        Err(err) => Err(err),
+    }
+}
+
+fn pollfd(fd: RawFd, events: libc::c_short) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    }
+}
+
+pub fn create_named_pipe(options: &PipeOptions, first: bool) -> io::Result<Handle> {
+    let path = socket_path(&options.name)?;
+    loop {
+        let mut listeners = LISTENERS.lock().unwrap_or_else(|err| err.into_inner());
+
+        if let Some(weak) = listeners.get(&path) {
+            // Listener isn't upgraded just to be dropped: if it's the last reference,
+            // `Listener::drop` would lock `LISTENERS` again.
+            if first && weak.strong_count() > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "pipe instance already exists",
+                ));
+            }
+            if let Some(listener) = weak.upgrade() {
+                return Ok(Handle {
+                    fd: None,
+                    instance: Some(Instance::new(&listener)),
+                    listener: Some(listener),
+                    control: None,
+                    remainder: Remainder::default(),
+                });
+            }
+            // Previous listener is being dropped right now and still holds the lock.
+            drop(listeners);
+            thread::yield_now();
+            continue;
+        }
+
+        let listener = Arc::new(Listener::bind(options, path.clone())?);
+        listeners.insert(path, Arc::downgrade(&listener));
+        return Ok(Handle {
+            fd: None,
+            instance: Some(Instance::new(&listener)),
+            listener: Some(listener),
+            control: None,
+            remainder: Remainder::default(),
+        });
+    }
+}
+
+/// Returns `Ok(true)` if connection is pending or `Ok(false)` if pipe is connected.
+pub fn connect_named_pipe(handle: &Handle, ovl: &mut Overlapped) -> io::Result<bool> {
+    ovl.listener = handle.listener.clone();
+    ovl.handshake = ovl
+        .listener
+        .as_ref()
+        .is_some_and(|listener| listener.has_control());
+    ovl.start(Op::Connect);
+    if ovl.progress(handle.raw()) {
+        ovl.result.take().unwrap().map(|_| false)
+    } else {
+        Ok(true)
+    }
+}
+
+/// Returns handle of a connected pipe instance once `connect_named_pipe` is completed.
+pub fn finish_connect(handle: Handle, ovl: &mut Overlapped) -> io::Result<Handle> {
+    // Connected server is registered by its own socket.
+    #[cfg(feature = "mio")]
+    {
+        ovl.mio = None;
+    }
+    let accepted = mem::take(
+        ovl.accepted
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner()),
+    );
+    match (accepted.fd, handle.listener) {
+        (Some(fd), Some(listener)) => {
+            listener.setup(&fd)?;
+            let control = accepted.control.map(|control| {
+                Arc::new(Control::new(
+                    control,
+                    listener.in_buffer == 0,
+                    listener.out_buffer == 0,
+                    listener.message,
+                ))
+            });
+            ovl.control = control.clone();
+            ovl.remainder = Remainder::default();
+            Ok(Handle {
+                fd: Some(fd),
+                listener: Some(listener),
+                control,
+                remainder: ovl.remainder.clone(),
+                instance: None,
+            })
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "pipe is not connected",
+        )),
+    }
+}
+
+/// Returns handle that is ready to be passed to `connect_named_pipe` after disconnection.
+pub fn listening_handle(handle: Handle) -> Handle {
+    Handle {
+        fd: None,
+        instance: handle.listener.as_ref().map(Instance::new),
+        listener: handle.listener.clone(),
+        control: None,
+        remainder: Remainder::default(),
+    }
+}
+
+pub fn duplicate_handle(handle: &Handle) -> io::Result<Handle> {
+    let fd = match handle.fd {
+        Some(ref fd) => Some(Fd::duplicate(fd.0)?),
+        None => None,
+    };
+    Ok(Handle {
+        fd,
+        listener: handle.listener.clone(),
+        control: handle.control.clone(),
+        remainder: handle.remainder.clone(),
+        instance: handle
+            .instance
+            .as_ref()
+            .map(|instance| Instance::new(&instance.0)),
+    })
+}
+
+pub fn flush_file_buffers(_handle: &Handle) -> io::Result<()> {
+    // Data is written directly into the peer's receive queue so there is nothing to flush.
+    Ok(())
+}
+
+pub fn disconnect_named_pipe(handle: &Handle) -> io::Result<()> {
+    if let Some(ref control) = handle.control {
+        control.fd.shutdown(libc::SHUT_RDWR)?;
+    }
+    match handle.fd {
+        Some(ref fd) => fd.shutdown(libc::SHUT_RDWR),
+        None => Ok(()),
+    }
+}
+
+fn connect(path: &Path, ty: libc::c_int) -> io::Result<Fd> {
+    let (addr, len) = sockaddr(path)?;
+    let fd = Fd::socket(ty)?;
+    unsafe {
+        cvt(libc::connect(
+            fd.0,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len,
+        ))?;
+    }
+    Ok(fd)
+}
+
+/// Opens client end of a pipe waiting until `deadline` (`None` stands for infinite waiting).
+pub fn open_pipe(name: &OsStr, deadline: Option<Instant>) -> io::Result<Handle> {
+    let path = socket_path(name)?;
+    loop {
+        // Pipe mode is chosen by the server, so try both socket types.
+        let result = match connect(&path, libc::SOCK_STREAM) {
+            Err(ref err) if err.raw_os_error() == Some(libc::EPROTOTYPE) => {
+                connect(&path, libc::SOCK_SEQPACKET).map(|fd| (fd, true))
+            }
+            result => result.map(|fd| (fd, false)),
+        };
+        match result {
+            Ok((fd, message)) => {
+                let control = match Lock::read_buffers(&path) {
+                    Some((in_buffer, out_buffer)) if in_buffer == 0 || out_buffer == 0 => {
+                        Some(Arc::new(Control::connect(
+                            &fd,
+                            out_buffer == 0,
+                            in_buffer == 0,
+                            message,
+                        )?))
+                    }
+                    _ => None,
+                };
+                return Ok(Handle {
+                    fd: Some(fd),
+                    listener: None,
+                    control,
+                    remainder: Remainder::default(),
+                    instance: None,
+                });
+            }
+            // Backlog is full, so all instances are busy.
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => match remaining(deadline) {
+                Some(remaining) if remaining == Duration::from_millis(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ResourceBusy,
+                        "all pipe instances are busy",
+                    ))
+                }
+                remaining => thread::sleep(
+                    remaining
+                        .unwrap_or(Duration::from_millis(1))
+                        .min(Duration::from_millis(1)),
+                ),
+            },
+            // Socket of a crashed server is left behind, but the pipe doesn't exist.
+            Err(ref err) if err.raw_os_error() == Some(libc::ECONNREFUSED) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "the system cannot find the pipe specified",
+                ))
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Starts read emulation. Returns `Ok(Some(bytes_read))` if operation was completed
+/// synchronously and `Ok(None)` if it is pending.
+///
+/// # Unsafety
+/// `buf` must stay valid until operation is completed or cancelled.
+pub unsafe fn read_file(
+    handle: RawHandle,
+    buf: *mut u8,
+    len: u32,
+    ovl: &mut Overlapped,
+) -> io::Result<Option<u32>> {
+    ovl.start(Op::Read {
+        buf,
+        len: len as usize,
+    });
+    if ovl.progress(handle) {
+        ovl.result.take().unwrap().map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Starts write emulation. Returns `Ok(Some(bytes_written))` if operation was completed
+/// synchronously and `Ok(None)` if it is pending.
+///
+/// # Unsafety
+/// `buf` must stay valid until operation is completed or cancelled.
+pub unsafe fn write_file(
+    handle: RawHandle,
+    buf: *const u8,
+    len: u32,
+    ovl: &mut Overlapped,
+) -> io::Result<Option<u32>> {
+    ovl.start(Op::Write {
+        buf,
+        len: len as usize,
+        done: 0,
+    });
+    if ovl.progress(handle) {
+        ovl.result.take().unwrap().map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn peek_named_pipe(handle: &Handle, ovl: &Overlapped, buf: &mut [u8]) -> io::Result<PeekInfo> {
+    let fd = handle.raw();
+    let mut available: libc::c_int = 0;
+    cvt(unsafe { libc::ioctl(fd, libc::FIONREAD, &mut available) })?;
+    let remainder = ovl.lock_remainder();
+    let mut info = PeekInfo {
+        bytes_read: 0,
+        bytes_available: (remainder.len() + available as usize) as u32,
+        message_bytes_left: 0,
+    };
+
+    // Rest of a partially read message goes first.
+    if !remainder.is_empty() {
+        let n = buf.len().min(remainder.len());
+        buf[..n].copy_from_slice(&remainder[..n]);
+        info.bytes_read = n as u32;
+        info.message_bytes_left = (remainder.len() - n) as u32;
+        return Ok(info);
+    }
+
+    let seqpacket = match ovl.seqpacket {
+        Some(seqpacket) => seqpacket,
+        None => is_seqpacket(fd)?,
+    };
+    // Stream is peeked at least a byte to tell the end of stream from an empty pipe, `MSG_TRUNC`
+    // makes `recv` return the whole length of a message.
+    let mut byte = 0u8;
+    let (ptr, len, flags) = match (seqpacket, buf.len()) {
+        (true, len) => (buf.as_mut_ptr(), len, libc::MSG_PEEK | libc::MSG_TRUNC),
+        (false, 0) => (&mut byte as *mut u8, 1, libc::MSG_PEEK),
+        (false, len) => (buf.as_mut_ptr(), len, libc::MSG_PEEK),
+    };
+    let result = unsafe { libc::recv(fd, ptr as *mut libc::c_void, len, flags) };
+    match cvt_size(result) {
+        Ok(0) => Err(broken_pipe()),
+        Ok(n) => {
+            info.bytes_read = n.min(buf.len()) as u32;
+            if seqpacket {
+                info.message_bytes_left = (n - info.bytes_read as usize) as u32;
+            }
+            Ok(info)
+        }
+        Err(ref err) if err.raw_os_error() == Some(libc::ECONNRESET) => Err(broken_pipe()),
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(info),
+        Err(err) => Err(err),
+    }
+}
+
+/// Starts vectored read emulation with `readv`. Returns `None` for `SOCK_SEQPACKET` sockets, then
+/// message should be read with `read_file`, otherwise the same as `read_file`.
+///
+/// # Unsafety
+/// `bufs` must stay valid until operation is completed or cancelled.
+pub unsafe fn read_file_vectored(
+    handle: RawHandle,
+    bufs: &mut [IoSliceMut<'_>],
+    ovl: &mut Overlapped,
+) -> Option<io::Result<Option<u32>>> {
+    let seqpacket = match ovl.seqpacket {
+        Some(seqpacket) => seqpacket,
+        None => match is_seqpacket(handle) {
+            Ok(seqpacket) => *ovl.seqpacket.insert(seqpacket),
+            Err(err) => return Some(Err(err)),
+        },
+    };
+    if seqpacket {
+        return None;
+    }
+    let iov = iovecs(bufs.iter_mut().map(|buf| (buf.as_mut_ptr(), buf.len())));
+    ovl.start(Op::ReadVectored { iov });
+    if ovl.progress(handle) {
+        Some(ovl.result.take().unwrap().map(Some))
+    } else {
+        Some(Ok(None))
+    }
+}
+
+/// Starts vectored write emulation with `sendmsg`, so `SOCK_SEQPACKET` sockets get a single
+/// message. Otherwise the same as `write_file`.
+///
+/// # Unsafety
+/// `bufs` must stay valid until operation is completed or cancelled.
+pub unsafe fn write_file_vectored(
+    handle: RawHandle,
+    bufs: &[IoSlice<'_>],
+    ovl: &mut Overlapped,
+) -> Option<io::Result<Option<u32>>> {
+    let iov = iovecs(bufs.iter().map(|buf| (buf.as_ptr() as *mut u8, buf.len())));
+    let len = iov.iter().map(|iov| iov.iov_len).sum::<usize>();
+    ovl.start(Op::WriteVectored { iov, len, done: 0 });
+    if ovl.progress(handle) {
+        Some(ovl.result.take().unwrap().map(Some))
+    } else {
+        Some(Ok(None))
+    }
+}
+
+/// Waits for completion of an operation and returns number of bytes transferred.
+pub fn get_overlapped_result(handle: RawHandle, ovl: &mut Overlapped) -> io::Result<u32> {
+    loop {
+        if let Some(result) = ovl.result.take() {
+            return result;
+        }
+        if let Op::Idle = ovl.op {
+            return Ok(0);
+        }
+        if !ovl.progress(handle) {
+            poll(&mut [ovl.pollfd(handle)], None)?;
+        }
+    }
+}
+
+/// Cancels pending operation. Returns number of bytes transferred before the cancellation.
+pub fn cancel_io(_handle: RawHandle, ovl: &mut Overlapped) -> io::Result<u32> {
+    // Nothing is in flight between calls so it's enough to forget the operation.
+    let count = match ovl.op {
+        Op::Write { done, .. } | Op::WriteVectored { done, .. } => done as u32,
+        _ => match ovl.result {
+            Some(Ok(count)) => count,
+            _ => 0,
+        },
+    };
+    ovl.op = Op::Idle;
+    ovl.result = None;
+    Ok(count)
+}
+
+/// Returns `Ok(true)` if operation was completed and `Ok(false)` on timeout or if any of
+/// `shutdown` is triggered.
+pub fn wait_for_single(
+    handle: RawHandle,
+    ovl: &mut Overlapped,
+    timeout: u32,
+    shutdown: &[&Shutdown],
+) -> io::Result<bool> {
+    let deadline = deadline(timeout);
+    loop {
+        if ovl.signaled || ovl.progress(handle) {
+            return Ok(true);
+        }
+        match remaining(deadline) {
+            Some(remaining) if remaining == Duration::from_millis(0) => return Ok(false),
+            remaining => {
+                let mut fds = vec![ovl.pollfd(handle)];
+                fds.extend(shutdown.iter().map(|shutdown| shutdown.pollfd()));
+                poll(&mut fds, remaining)?;
+                if fds[1..].iter().any(|fd| fd.revents != 0) {
+                    return Ok(ovl.signaled || ovl.progress(handle));
+                }
+            }
+        }
+    }
+}
+
+/// Waits for readiness of operations. Completed operations are always ready. Returns `Ok(None)`
+/// on timeout or if any of `shutdown` is triggered.
+pub fn wait_for_multiple(
+    list: &[(RawHandle, &Overlapped)],
+    all: bool,
+    timeout: u32,
+    shutdown: &[&Shutdown],
+) -> io::Result<Option<usize>> {
+    if list.is_empty() {
+        return Ok(None);
+    }
+
+    let deadline = deadline(timeout);
+    let mut ready = list
+        .iter()
+        .map(|(_, ovl)| ovl.is_ready())
+        .collect::<Vec<_>>();
+    loop {
+        if all && ready.iter().all(|x| *x) {
+            return Ok(Some(0));
+        }
+        if !all {
+            if let Some(i) = ready.iter().position(|x| *x) {
+                return Ok(Some(i));
+            }
+        }
+
+        let indexes = (0..list.len()).filter(|i| !ready[*i]).collect::<Vec<_>>();
+        let mut fds = indexes
+            .iter()
+            .map(|i| list[*i].1.pollfd(list[*i].0))
+            .chain(shutdown.iter().map(|shutdown| shutdown.pollfd()))
+            .collect::<Vec<_>>();
+        let remaining = remaining(deadline);
+        if poll(&mut fds, remaining)? == 0 && remaining == Some(Duration::from_millis(0)) {
+            return Ok(None);
+        }
+        if fds[indexes.len()..].iter().any(|fd| fd.revents != 0) {
+            return Ok(None);
+        }
+        for (i, fd) in indexes.iter().zip(fds.iter()) {
+            ready[*i] = list[*i].1.poll_ready(fd);
+        }
+    }
+}
+
+/// Waits for readiness of any operation and returns indexes of all ready operations (empty on
+/// timeout or if any of `shutdown` is triggered). Unlike `wait_for_multiple` number of
+/// operations isn't limited.
+pub fn wait_for_ready(
+    list: &[(RawHandle, &Overlapped)],
+    timeout: u32,
+    shutdown: &[&Shutdown],
+) -> io::Result<Vec<usize>> {
+    if list.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let deadline = deadline(timeout);
+    loop {
+        let completed = list
+            .iter()
+            .map(|(_, ovl)| ovl.is_ready())
+            .collect::<Vec<_>>();
+        // Descriptors may change as operations make progress (e.g. accepted client).
+        let mut fds = list
+            .iter()
+            .map(|(handle, ovl)| ovl.pollfd(*handle))
+            .chain(shutdown.iter().map(|shutdown| shutdown.pollfd()))
+            .collect::<Vec<_>>();
+        // Operations that are ready already are reported along with the ones ready right now.
+        let remaining = if completed.iter().any(|x| *x) {
+            Some(Duration::from_millis(0))
+        } else {
+            remaining(deadline)
+        };
+        poll(&mut fds, remaining)?;
+        let ready = (0..list.len())
+            .filter(|i| completed[*i] || list[*i].1.poll_ready(&fds[*i]))
+            .collect::<Vec<_>>();
+        if !ready.is_empty()
+            || fds[list.len()..].iter().any(|fd| fd.revents != 0)
+            || remaining == Some(Duration::from_millis(0))
+        {
+            return Ok(ready);
+        }
+    }
+}
+
+/// Wakes waits once triggered: the read end of a socket pair becomes readable forever.
+#[derive(Debug)]
+pub struct Shutdown {
+    read: UnixStream,
+    write: UnixStream,
+}
+
+impl Shutdown {
+    pub fn new() -> io::Result<Shutdown> {
+        let (read, write) = UnixStream::pair()?;
+        write.set_nonblocking(true)?;
+        Ok(Shutdown { read, write })
+    }
+
+    pub fn trigger(&self) -> io::Result<()> {
+        match (&self.write).write(&[1]) {
+            Err(ref err) if would_block(err) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn pollfd(&self) -> libc::pollfd {
+        pollfd(self.read.as_raw_fd(), libc::POLLIN)
+    }
+}
+
+fn epoll_create() -> io::Result<Fd> {
+    let epoll = unsafe { cvt(libc::epoll_create1(libc::EPOLL_CLOEXEC))? };
+    Ok(Fd(epoll))
+}
+
+/// Registers a duplicate of the descriptor that operation of `ovl` waits for, the duplicate is
+/// returned.
+///
+/// Descriptor is duplicated because instances of a pipe share the listening socket and epoll
+/// doesn't allow to register a descriptor twice.
+fn epoll_add(
+    epoll: &Fd,
+    handle: RawHandle,
+    ovl: &Overlapped,
+    key: u64,
+    flags: libc::c_int,
+) -> io::Result<Fd> {
+    let target = ovl.pollfd(handle);
+    let fd = Fd::duplicate(target.fd)?;
+    let mut event = libc::epoll_event {
+        events: (epoll_events(target.events) | flags) as u32,
+        u64: key,
+    };
+    unsafe {
+        cvt(libc::epoll_ctl(
+            epoll.0,
+            libc::EPOLL_CTL_ADD,
+            fd.0,
+            &mut event,
+        ))?;
+    }
+    Ok(fd)
+}
+
+/// Converts `poll` events into epoll ones.
+fn epoll_events(events: libc::c_short) -> libc::c_int {
+    let mut result = 0;
+    if events & libc::POLLIN != 0 {
+        result |= libc::EPOLLIN;
+    }
+    if events & libc::POLLOUT != 0 {
+        result |= libc::EPOLLOUT;
+    }
+    result
+}
+
+fn epoll_del(epoll: &Fd, fd: &Fd) -> io::Result<()> {
+    unsafe {
+        cvt(libc::epoll_ctl(
+            epoll.0,
+            libc::EPOLL_CTL_DEL,
+            fd.0,
+            ptr::null_mut(),
+        ))?;
+    }
+    Ok(())
+}
+
+/// Returns keys of ready descriptors (empty on timeout or if interrupted).
+fn epoll_wait(epoll: &Fd, max_events: usize, timeout: Option<Duration>) -> io::Result<Vec<u64>> {
+    let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; max_events.max(1)];
+    let result = unsafe {
+        libc::epoll_wait(
+            epoll.0,
+            events.as_mut_ptr(),
+            events.len() as libc::c_int,
+            poll_timeout(timeout),
+        )
+    };
+    match cvt(result) {
+        Ok(n) => Ok(events[..n as usize].iter().map(|event| event.u64).collect()),
+        Err(ref err) if err.kind() == io::ErrorKind::Interrupted => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Watches pending operations with epoll. Every descriptor is added once and stays in the epoll
+/// until it's closed. It's added with `EPOLLONESHOT` and armed again by every `register`.
+/// Descriptors are identified by their numbers, so notifications may be spurious if a number is
+/// reused.
+#[derive(Debug)]
+pub struct Poller {
+    epoll: Fd,
+    /// Descriptor waited by every registered operation.
+    registered: HashMap<usize, RawFd>,
+    /// Keys and events of operations waiting for every descriptor.
+    fds: HashMap<RawFd, Vec<(usize, libc::c_short)>>,
+}
+
+impl Poller {
+    pub fn new() -> io::Result<Poller> {
+        Ok(Poller {
+            epoll: epoll_create()?,
+            registered: HashMap::new(),
+            fds: HashMap::new(),
+        })
+    }
+
+    pub fn register(&mut self, handle: RawHandle, ovl: &Overlapped, key: usize) -> io::Result<()> {
+        // Descriptor may change as operation makes progress (e.g. accepted client).
+        self.deregister(key)?;
+        let target = ovl.pollfd(handle);
+        self.registered.insert(key, target.fd);
+        let waiting = self.fds.entry(target.fd).or_default();
+        waiting.push((key, target.events));
+        let events = waiting
+            .iter()
+            .fold(0, |events, (_, x)| events | epoll_events(*x));
+        let mut event = libc::epoll_event {
+            events: (events | libc::EPOLLONESHOT) as u32,
+            u64: target.fd as u64,
+        };
+        unsafe {
+            let result = libc::epoll_ctl(self.epoll.0, libc::EPOLL_CTL_MOD, target.fd, &mut event);
+            if result == -1 && io::Error::last_os_error().raw_os_error() == Some(libc::ENOENT) {
+                cvt(libc::epoll_ctl(
+                    self.epoll.0,
+                    libc::EPOLL_CTL_ADD,
+                    target.fd,
+                    &mut event,
+                ))?;
+            } else {
+                cvt(result)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops reporting the operation. Its descriptor stays in the epoll for other operations.
+    pub fn deregister(&mut self, key: usize) -> io::Result<()> {
+        if let Some(fd) = self.registered.remove(&key) {
+            if let Some(waiting) = self.fds.get_mut(&fd) {
+                waiting.retain(|(x, _)| *x != key);
+                if waiting.is_empty() {
+                    self.fds.remove(&fd);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns keys of operations that may be ready (empty on timeout). Operations that are not
+    /// finished must be registered again.
+    pub fn wait(&mut self, timeout: u32) -> io::Result<Vec<usize>> {
+        let fds = epoll_wait(&self.epoll, self.fds.len(), remaining(deadline(timeout)))?;
+        Ok(fds
+            .into_iter()
+            .filter_map(|fd| self.fds.get(&(fd as RawFd)))
+            .flat_map(|waiting| waiting.iter().map(|(key, _)| *key))
+            .collect())
+    }
+}
+
+/// Background thread that wakes tasks once their operations are ready. If epoll fails, it wakes
+/// all the tasks and stops, so they fail to register again.
+#[derive(Debug)]
+struct Reactor {
+    epoll: Fd,
+    next_key: AtomicU64,
+    wakers: Mutex<HashMap<u64, Waker>>,
+    /// Error code that stopped the thread (zero while it's running). Set with `wakers` locked.
+    error: AtomicI32,
+}
+
+static REACTOR: Mutex<Option<Arc<Reactor>>> = Mutex::new(None);
+
+impl Reactor {
+    /// Returns the reactor starting it if necessary.
+    fn get() -> io::Result<Arc<Reactor>> {
+        let mut reactor = REACTOR.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(ref reactor) = *reactor {
+            return Ok(reactor.clone());
+        }
+
+        let new_reactor = Arc::new(Reactor {
+            epoll: epoll_create()?,
+            next_key: AtomicU64::new(0),
+            wakers: Mutex::new(HashMap::new()),
+            error: AtomicI32::new(0),
+        });
+        let thread_reactor = new_reactor.clone();
+        thread::Builder::new()
+            .name("named_pipe reactor".into())
+            .spawn(move || thread_reactor.run())?;
+        *reactor = Some(new_reactor.clone());
+        Ok(new_reactor)
+    }
+
+    fn run(&self) {
+        loop {
+            // Interruption is reported as an empty list of keys.
+            let result = epoll_wait(&self.epoll, 64, None);
+            let wakers = {
+                let mut wakers = self.wakers.lock().unwrap_or_else(|err| err.into_inner());
+                match result {
+                    Ok(ref keys) => keys
+                        .iter()
+                        .filter_map(|key| wakers.remove(key))
+                        .collect::<Vec<_>>(),
+                    Err(ref err) => {
+                        let code = err.raw_os_error().unwrap_or(libc::EIO);
+                        self.error.store(code, Ordering::Relaxed);
+                        wakers.drain().map(|(_, waker)| waker).collect()
+                    }
+                }
+            };
+            for waker in wakers {
+                waker.wake();
+            }
+            if result.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Registration of a waker, see `register_waker`.
+#[derive(Debug)]
+pub struct Registration {
+    reactor: Arc<Reactor>,
+    key: u64,
+    fd: Fd,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.reactor
+            .wakers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&self.key);
+        let _ = epoll_del(&self.reactor.epoll, &self.fd);
+    }
+}
+
+/// Wakes `waker` once, when operation is ready.
+pub fn register_waker(
+    handle: RawHandle,
+    ovl: &Overlapped,
+    waker: Waker,
+) -> io::Result<Registration> {
+    let reactor = Reactor::get()?;
+    let key = reactor.next_key.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut wakers = reactor.wakers.lock().unwrap_or_else(|err| err.into_inner());
+        match reactor.error.load(Ordering::Relaxed) {
+            0 => wakers.insert(key, waker),
+            code => return Err(io::Error::from_raw_os_error(code)),
+        };
+    }
+    match epoll_add(&reactor.epoll, handle, ovl, key, libc::EPOLLONESHOT) {
+        Ok(fd) => Ok(Registration { reactor, key, fd }),
+        Err(err) => {
+            reactor
+                .wakers
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .remove(&key);
+            Err(err)
+        }
+    }
+}
+
+/// Returns descriptor of `handle` that is registered in a mio registry.
+#[cfg(feature = "mio")]
+fn mio_fd(handle: &Handle, ovl: &Overlapped) -> RawFd {
+    ovl.mio.as_ref().map_or(handle.raw(), |fd| fd.0)
+}
+
+/// Registers socket of `handle` in a mio registry.
+#[cfg(feature = "mio")]
+pub fn mio_register(
+    handle: &Handle,
+    ovl: &mut Overlapped,
+    registry: &mio::Registry,
+    token: mio::Token,
+    interests: mio::Interest,
+) -> io::Result<()> {
+    if ovl.handshake || handle.control.is_some() {
+        return Err(zero_buffers_unsupported());
+    }
+    // Instances of a pipe share the listening socket, so each of them registers a duplicate.
+    if handle.fd.is_none() && ovl.mio.is_none() {
+        ovl.mio = Some(Fd::duplicate(handle.raw())?);
+    }
+    registry.register(&mut SourceFd(&mio_fd(handle, ovl)), token, interests)
+}
+
+#[cfg(feature = "mio")]
+pub fn mio_reregister(
+    handle: &Handle,
+    ovl: &mut Overlapped,
+    registry: &mio::Registry,
+    token: mio::Token,
+    interests: mio::Interest,
+) -> io::Result<()> {
+    registry.reregister(&mut SourceFd(&mio_fd(handle, ovl)), token, interests)
+}
+
+#[cfg(feature = "mio")]
+pub fn mio_deregister(
+    handle: &Handle,
+    ovl: &mut Overlapped,
+    registry: &mio::Registry,
+) -> io::Result<()> {
+    registry.deregister(&mut SourceFd(&mio_fd(handle, ovl)))?;
+    ovl.mio = None;
+    Ok(())
+}
+
+pub fn is_more_data(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+pub fn is_disconnected(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(code) => code == libc::EPIPE || code == libc::ENOTCONN || code == libc::ECONNRESET,
+        None => false,
+    }
+}
+
+pub fn is_busy(_err: &io::Error) -> bool {
+    // Busy pipe is reported with `ErrorKind::ResourceBusy`, see `open_pipe`.
+    false
+}
+
+pub fn is_cancelled(_err: &io::Error) -> bool {
+    // Nothing is in flight between calls, so the OS never aborts operations.
+    false
+}
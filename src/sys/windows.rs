@@ -0,0 +1,941 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Windows backend. Thin wrappers around overlapped IO of named pipes.
+
+use winapi::{
+    ctypes::*,
+    shared::{minwindef::*, ntdef::HANDLE, winerror::*},
+    um::{
+        errhandlingapi::*, fileapi::*, handleapi::*, ioapiset::*, minwinbase::*, namedpipeapi::*,
+        processthreadsapi::*, synchapi::*, threadpoollegacyapiset::*, winbase::*, winnt::*,
+    },
+};
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "mio")]
+use std::task::Wake;
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+use super::{remaining_ms, PeekInfo};
+use crate::{OpenMode, PipeMode, PipeName, PipeOptions};
+
+pub use winapi::um::winbase::INFINITE;
+
+pub type RawHandle = HANDLE;
+
+pub const NULL_HANDLE: RawHandle = ptr::null_mut();
+
+pub const MAXIMUM_WAIT_OBJECTS: usize = winapi::um::winnt::MAXIMUM_WAIT_OBJECTS as usize;
+
+#[derive(Debug)]
+pub struct Handle {
+    value: HANDLE,
+    /// Id of the `Poller` the handle is associated with (zero if none), shared by duplicates.
+    poller: Arc<AtomicUsize>,
+}
+
+impl Handle {
+    fn new(value: HANDLE) -> Handle {
+        Handle {
+            value,
+            poller: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn raw(&self) -> RawHandle {
+        self.value
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let _ = unsafe { CloseHandle(self.value) };
+    }
+}
+
+unsafe impl Sync for Handle {}
+unsafe impl Send for Handle {}
+
+#[derive(Debug)]
+struct Event {
+    handle: Handle,
+}
+
+impl Event {
+    fn new() -> io::Result<Event> {
+        let handle = unsafe { CreateEventW(ptr::null_mut(), 1, 0, ptr::null()) };
+        if !handle.is_null() {
+            Ok(Event {
+                handle: Handle::new(handle),
+            })
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        let result = unsafe { ResetEvent(self.handle.value) };
+        if result != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn set(&self) -> io::Result<()> {
+        let result = unsafe { SetEvent(self.handle.value) };
+        if result != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+pub struct Overlapped {
+    /// Must be dropped before the event.
+    #[cfg(feature = "mio")]
+    mio: Option<MioSource>,
+    ovl: Box<OVERLAPPED>,
+    event: Event,
+    /// `Handle::poller` of the handle.
+    poller: Arc<AtomicUsize>,
+}
+
+impl fmt::Debug for Overlapped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("Overlapped");
+        f.field("ovl", &"OVERLAPPED").field("event", &self.event);
+        #[cfg(feature = "mio")]
+        f.field("mio", &self.mio);
+        f.finish()
+    }
+}
+
+unsafe impl Send for Overlapped {}
+unsafe impl Sync for Overlapped {}
+
+impl Overlapped {
+    pub fn new(handle: &Handle) -> io::Result<Overlapped> {
+        let event = Event::new()?;
+        let mut ovl: Box<OVERLAPPED> = Box::new(unsafe { mem::zeroed() });
+        ovl.hEvent = event.handle.value;
+        Ok(Overlapped {
+            #[cfg(feature = "mio")]
+            mio: None,
+            ovl,
+            event,
+            poller: handle.poller.clone(),
+        })
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.event.reset()?;
+        *self.ovl = unsafe { mem::zeroed() };
+        self.ovl.hEvent = self.event.handle.value;
+        Ok(())
+    }
+
+    fn get_mut(&mut self) -> &mut OVERLAPPED {
+        &mut self.ovl
+    }
+
+    /// Wakes the mio registry (if any) once the started operation is finished.
+    #[cfg(feature = "mio")]
+    fn arm(&mut self) -> io::Result<()> {
+        let waker = match self.mio {
+            Some(ref mut source) => {
+                source.registration = None;
+                source.waker.clone()
+            }
+            None => return Ok(()),
+        };
+        let registration = register_waker(ptr::null_mut(), self, Waker::from(waker))?;
+        if let Some(ref mut source) = self.mio {
+            source.registration = Some(registration);
+        }
+        Ok(())
+    }
+
+    /// Arms started operation. It's already pending, so the failure is not reported and the
+    /// registry just won't be woken.
+    #[cfg(feature = "mio")]
+    fn arm_pending(&mut self) {
+        let _ = self.arm();
+    }
+}
+
+/// Validates pipe name and converts it into a null-terminated wide string.
+fn wide_name(name: &OsStr) -> io::Result<Vec<u16>> {
+    PipeName::new(name)?;
+    let mut full_name: OsString = name.into();
+    full_name.push("\x00");
+    Ok(full_name.encode_wide().collect::<Vec<u16>>())
+}
+
+fn open_mode_flags(mode: &OpenMode) -> u32 {
+    match *mode {
+        OpenMode::Read => PIPE_ACCESS_INBOUND,
+        OpenMode::Write => PIPE_ACCESS_OUTBOUND,
+        OpenMode::Duplex => PIPE_ACCESS_DUPLEX,
+    }
+}
+
+fn pipe_mode_flags(pipe_mode: PipeMode) -> DWORD {
+    match pipe_mode {
+        PipeMode::Byte => PIPE_TYPE_BYTE | PIPE_READMODE_BYTE,
+        PipeMode::Message => PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE,
+    }
+}
+
+pub fn create_named_pipe(options: &PipeOptions, first: bool) -> io::Result<Handle> {
+    let name = wide_name(&options.name)?;
+    let handle = unsafe {
+        CreateNamedPipeW(
+            name.as_ptr(),
+            open_mode_flags(&options.open_mode)
+                | FILE_FLAG_OVERLAPPED
+                | if first {
+                    FILE_FLAG_FIRST_PIPE_INSTANCE
+                } else {
+                    0
+                },
+            pipe_mode_flags(options.pipe_mode) | PIPE_WAIT,
+            PIPE_UNLIMITED_INSTANCES,
+            options.out_buffer,
+            options.in_buffer,
+            0,
+            ptr::null_mut(),
+        )
+    };
+
+    if handle != INVALID_HANDLE_VALUE {
+        Ok(Handle::new(handle))
+    } else {
+        let err = io::Error::last_os_error();
+        if first && err.raw_os_error() == Some(ERROR_ACCESS_DENIED as i32) {
+            // That's how `FILE_FLAG_FIRST_PIPE_INSTANCE` reports an existing pipe.
+            Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "pipe instance already exists",
+            ))
+        } else {
+            Err(err)
+        }
+    }
+}
+
+/// Returns `Ok(true)` if connection is pending or `Ok(false)` if pipe is connected.
+pub fn connect_named_pipe(handle: &Handle, ovl: &mut Overlapped) -> io::Result<bool> {
+    let result = unsafe { ConnectNamedPipe(handle.value, ovl.get_mut()) };
+    if result == TRUE {
+        // Overlapped ConnectNamedPipe should return FALSE
+        Err(io::Error::last_os_error())
+    } else {
+        let err = io::Error::last_os_error();
+        let mut pending = false;
+        match err.raw_os_error().unwrap() as u32 {
+            ERROR_IO_PENDING => pending = true,
+            ERROR_PIPE_CONNECTED => ovl.event.set()?,
+            _ => return Err(err),
+        }
+        #[cfg(feature = "mio")]
+        ovl.arm_pending();
+        Ok(pending)
+    }
+}
+
+/// Returns handle of a connected pipe instance once `connect_named_pipe` is completed.
+pub fn finish_connect(handle: Handle, _ovl: &mut Overlapped) -> io::Result<Handle> {
+    Ok(handle)
+}
+
+/// Returns handle that is ready to be passed to `connect_named_pipe` after disconnection.
+pub fn listening_handle(handle: Handle) -> Handle {
+    handle
+}
+
+pub fn duplicate_handle(handle: &Handle) -> io::Result<Handle> {
+    let mut value = ptr::null_mut();
+    let result = unsafe {
+        let process = GetCurrentProcess();
+        DuplicateHandle(
+            process,
+            handle.value,
+            process,
+            &mut value,
+            0,
+            FALSE,
+            DUPLICATE_SAME_ACCESS,
+        )
+    };
+    if result == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(Handle {
+            value,
+            poller: handle.poller.clone(),
+        })
+    }
+}
+
+pub fn flush_file_buffers(handle: &Handle) -> io::Result<()> {
+    let result = unsafe { FlushFileBuffers(handle.value) };
+    if result != 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+pub fn disconnect_named_pipe(handle: &Handle) -> io::Result<()> {
+    let result = unsafe { DisconnectNamedPipe(handle.value) };
+    if result != 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Opens a pipe falling back to access modes with less rights. Fails with `ERROR_PIPE_BUSY` if
+/// all instances are busy, see `open_pipe`.
+fn create_file(name: &[u16], mode: DWORD) -> io::Result<Handle> {
+    let handle = unsafe {
+        CreateFileW(
+            name.as_ptr(),
+            mode,
+            0,
+            ptr::null_mut(),
+            OPEN_EXISTING,
+            FILE_FLAG_OVERLAPPED,
+            ptr::null_mut(),
+        )
+    };
+
+    if handle != INVALID_HANDLE_VALUE {
+        return Ok(Handle::new(handle));
+    }
+
+    match unsafe { GetLastError() } {
+        ERROR_ACCESS_DENIED if mode == (GENERIC_READ | GENERIC_WRITE) => {
+            create_file(name, GENERIC_READ | FILE_WRITE_ATTRIBUTES)
+        }
+        ERROR_ACCESS_DENIED if mode == (GENERIC_READ | FILE_WRITE_ATTRIBUTES) => {
+            create_file(name, GENERIC_WRITE | FILE_READ_ATTRIBUTES)
+        }
+        code => Err(io::Error::from_raw_os_error(code as i32)),
+    }
+}
+
+/// Opens client end of a pipe waiting while all instances are busy until `deadline` (`None`
+/// stands for infinite waiting).
+pub fn open_pipe(name: &OsStr, deadline: Option<Instant>) -> io::Result<Handle> {
+    let full_name = wide_name(name)?;
+    loop {
+        match create_file(&full_name, GENERIC_READ | GENERIC_WRITE) {
+            Ok(handle) => {
+                let result = unsafe {
+                    // Client reads in the mode of the pipe chosen by the server.
+                    let mut flags = 0;
+                    if GetNamedPipeInfo(
+                        handle.value,
+                        &mut flags,
+                        ptr::null_mut(),
+                        ptr::null_mut(),
+                        ptr::null_mut(),
+                    ) == 0
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                    let mut mode = if flags & PIPE_TYPE_MESSAGE != 0 {
+                        PIPE_READMODE_MESSAGE | PIPE_WAIT
+                    } else {
+                        PIPE_READMODE_BYTE | PIPE_WAIT
+                    };
+                    SetNamedPipeHandleState(
+                        handle.value,
+                        &mut mode,
+                        ptr::null_mut(),
+                        ptr::null_mut(),
+                    )
+                };
+
+                if result != 0 {
+                    return Ok(handle);
+                } else {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Err(ref err) if is_busy(err) => {
+                // Other client may take the freed instance first, so wait again until the deadline.
+                let timeout = match deadline {
+                    Some(deadline) => match remaining_ms(deadline) {
+                        0 => return Err(io::Error::from_raw_os_error(ERROR_PIPE_BUSY as i32)),
+                        timeout => timeout,
+                    },
+                    // `NMPWAIT_WAIT_FOREVER`
+                    None => INFINITE,
+                };
+                if unsafe { WaitNamedPipeW(full_name.as_ptr(), timeout) } == 0 {
+                    let err = io::Error::last_os_error();
+                    if err.raw_os_error() != Some(ERROR_SEM_TIMEOUT as i32) {
+                        return Err(err);
+                    }
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Starts overlapped read. Returns `Ok(Some(bytes_read))` if operation was completed
+/// synchronously and `Ok(None)` if it is pending.
+///
+/// # Unsafety
+/// `buf` must stay valid until operation is completed or cancelled.
+pub unsafe fn read_file(
+    handle: RawHandle,
+    buf: *mut u8,
+    len: u32,
+    ovl: &mut Overlapped,
+) -> io::Result<Option<u32>> {
+    let mut bytes_read = 0;
+    let result = ReadFile(
+        handle,
+        buf as *mut c_void,
+        len,
+        &mut bytes_read,
+        ovl.get_mut(),
+    );
+
+    if result != 0 {
+        Ok(Some(bytes_read))
+    } else {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(ERROR_IO_PENDING as i32) {
+            #[cfg(feature = "mio")]
+            ovl.arm_pending();
+            Ok(None)
+        } else {
+            Err(err)
+        }
+    }
+}
+
+/// Starts overlapped write. Returns `Ok(Some(bytes_written))` if operation was completed
+/// synchronously and `Ok(None)` if it is pending.
+///
+/// # Unsafety
+/// `buf` must stay valid until operation is completed or cancelled.
+pub unsafe fn write_file(
+    handle: RawHandle,
+    buf: *const u8,
+    len: u32,
+    ovl: &mut Overlapped,
+) -> io::Result<Option<u32>> {
+    let mut bytes_written = 0;
+    let result = WriteFile(
+        handle,
+        buf as *mut c_void,
+        len,
+        &mut bytes_written,
+        ovl.get_mut(),
+    );
+
+    if result != 0 {
+        Ok(Some(bytes_written))
+    } else {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(ERROR_IO_PENDING as i32) {
+            #[cfg(feature = "mio")]
+            ovl.arm_pending();
+            Ok(None)
+        } else {
+            Err(err)
+        }
+    }
+}
+
+pub fn peek_named_pipe(handle: &Handle, _ovl: &Overlapped, buf: &mut [u8]) -> io::Result<PeekInfo> {
+    let mut info = PeekInfo::default();
+    let (ptr, len) = match buf.len() {
+        0 => (ptr::null_mut(), 0),
+        len => (buf.as_mut_ptr() as *mut c_void, len.min(0xFFFFFFFF) as u32),
+    };
+    let result = unsafe {
+        PeekNamedPipe(
+            handle.value,
+            ptr,
+            len,
+            &mut info.bytes_read,
+            &mut info.bytes_available,
+            &mut info.message_bytes_left,
+        )
+    };
+    if result == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info)
+}
+
+/// Pipes have no vectored reads (`ReadFileScatter` only works with unbuffered files), so it
+/// always returns `None` and the caller reads into a single buffer with `read_file`.
+pub unsafe fn read_file_vectored(
+    _handle: RawHandle,
+    _bufs: &mut [IoSliceMut<'_>],
+    _ovl: &mut Overlapped,
+) -> Option<io::Result<Option<u32>>> {
+    None
+}
+
+/// Pipes have no vectored writes (`WriteFileGather` only works with unbuffered files), so it
+/// always returns `None` and the caller gathers buffers for `write_file`.
+pub unsafe fn write_file_vectored(
+    _handle: RawHandle,
+    _bufs: &[IoSlice<'_>],
+    _ovl: &mut Overlapped,
+) -> Option<io::Result<Option<u32>>> {
+    None
+}
+
+/// Waits for completion of overlapped operation and returns number of bytes transferred.
+pub fn get_overlapped_result(handle: RawHandle, ovl: &mut Overlapped) -> io::Result<u32> {
+    let mut count = 0;
+    let result = unsafe { GetOverlappedResult(handle, ovl.get_mut(), &mut count, TRUE) };
+    if result != 0 {
+        Ok(count)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Cancels pending operation and waits until it is finished. Returns number of bytes
+/// transferred before the cancellation.
+pub fn cancel_io(handle: RawHandle, ovl: &mut Overlapped) -> io::Result<u32> {
+    let result = unsafe { CancelIoEx(handle, ovl.get_mut()) };
+    let cancel_error = match result {
+        FALSE => match io::Error::last_os_error() {
+            // Operation is already completed.
+            ref err if err.raw_os_error() == Some(ERROR_NOT_FOUND as i32) => None,
+            err => Some(err),
+        },
+        _ => None,
+    };
+
+    // Buffer of the operation must stay valid until the OS is done with it, whether or not
+    // cancellation succeeded.
+    let mut count = 0;
+    unsafe { GetOverlappedResult(handle, ovl.get_mut(), &mut count, TRUE) };
+    match cancel_error {
+        Some(err) => Err(err),
+        None => Ok(count),
+    }
+}
+
+/// Returns `Ok(true)` if operation was completed and `Ok(false)` on timeout or if any of
+/// `shutdown` is triggered.
+pub fn wait_for_single(
+    _handle: RawHandle,
+    ovl: &mut Overlapped,
+    timeout: u32,
+    shutdown: &[&Shutdown],
+) -> io::Result<bool> {
+    // Event of the operation goes first, so it wins if both are signaled.
+    let mut events = vec![ovl.event.handle.value];
+    events.extend(shutdown.iter().map(|shutdown| shutdown.event.handle.value));
+    assert!(events.len() <= MAXIMUM_WAIT_OBJECTS);
+    let result =
+        unsafe { WaitForMultipleObjects(events.len() as u32, events.as_ptr(), FALSE, timeout) };
+
+    match result {
+        WAIT_OBJECT_0 => Ok(true),
+        WAIT_TIMEOUT => Ok(false),
+        WAIT_FAILED => Err(io::Error::last_os_error()),
+        i if i < WAIT_OBJECT_0 + events.len() as u32 => Ok(false),
+        _ => unreachable!(),
+    }
+}
+
+/// Waits for operations to finish. Returns `Ok(None)` on timeout or if any of `shutdown` is
+/// triggered. Number of operations isn't limited: if `all` is set, operations are waited one by
+/// one (their events stay signaled), otherwise see `wait_for_events`.
+pub fn wait_for_multiple(
+    list: &[(RawHandle, &Overlapped)],
+    all: bool,
+    timeout: u32,
+    shutdown: &[&Shutdown],
+) -> io::Result<Option<usize>> {
+    if list.is_empty() {
+        return Ok(None);
+    }
+
+    let shutdown = shutdown
+        .iter()
+        .map(|shutdown| shutdown.event.handle.value)
+        .collect::<Vec<_>>();
+    if !all {
+        let events = list
+            .iter()
+            .map(|(_, ovl)| ovl.event.handle.value)
+            .chain(shutdown.iter().cloned())
+            .map(|event| event as usize)
+            .collect::<Vec<_>>();
+        let mut ready = wait_for_events(&events, timeout)?;
+        ready.retain(|i| *i < list.len());
+        return Ok(ready.first().cloned());
+    }
+
+    let deadline = match timeout {
+        INFINITE => None,
+        timeout => Instant::now().checked_add(Duration::from_millis(timeout as u64)),
+    };
+    for (_, ovl) in list.iter() {
+        // Event of the operation goes first, so it wins if both are signaled.
+        let mut events = vec![ovl.event.handle.value];
+        events.extend(shutdown.iter().cloned());
+        assert!(events.len() <= MAXIMUM_WAIT_OBJECTS);
+        let timeout = deadline.map_or(INFINITE, remaining_ms);
+        let result =
+            unsafe { WaitForMultipleObjects(events.len() as u32, events.as_ptr(), FALSE, timeout) };
+        match result {
+            WAIT_OBJECT_0 => (),
+            WAIT_TIMEOUT => return Ok(None),
+            WAIT_FAILED => return Err(io::Error::last_os_error()),
+            i if i < WAIT_OBJECT_0 + events.len() as u32 => return Ok(None),
+            _ => unreachable!(),
+        }
+    }
+    Ok(Some(0))
+}
+
+/// Waits for any operation to finish and returns indexes of all finished operations (empty on
+/// timeout or if any of `shutdown` is triggered). Number of operations isn't limited: if there
+/// are more than `MAXIMUM_WAIT_OBJECTS` of them, then groups of operations are waited by helper
+/// threads.
+pub fn wait_for_ready(
+    list: &[(RawHandle, &Overlapped)],
+    timeout: u32,
+    shutdown: &[&Shutdown],
+) -> io::Result<Vec<usize>> {
+    // Raw handles are not `Send`.
+    let events = list
+        .iter()
+        .map(|(_, ovl)| ovl.event.handle.value as usize)
+        .chain(
+            shutdown
+                .iter()
+                .map(|shutdown| shutdown.event.handle.value as usize),
+        )
+        .collect::<Vec<_>>();
+    let mut ready = wait_for_events(&events, timeout)?;
+    ready.retain(|i| *i < list.len());
+    Ok(ready)
+}
+
+fn wait_for_events(events: &[usize], timeout: u32) -> io::Result<Vec<usize>> {
+    let ready = signaled(events)?;
+    if !ready.is_empty() || events.is_empty() || timeout == 0 {
+        return Ok(ready);
+    }
+
+    if events.len() <= MAXIMUM_WAIT_OBJECTS {
+        wait_for_any(events, timeout)?;
+    } else {
+        // Every group also waits on `wake` that is set once any of the groups is finished.
+        let wake = Event::new()?;
+        std::thread::scope(|scope| {
+            let threads = events
+                .chunks(MAXIMUM_WAIT_OBJECTS - 1)
+                .map(|group| {
+                    let wake = &wake;
+                    scope.spawn(move || {
+                        let mut group = group.to_vec();
+                        group.push(wake.handle.value as usize);
+                        let result = wait_for_any(&group, timeout);
+                        wake.set().and(result)
+                    })
+                })
+                .collect::<Vec<_>>();
+            threads.into_iter().try_for_each(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|err| std::panic::resume_unwind(err))
+            })
+        })?;
+    }
+    signaled(events)
+}
+
+/// Wakes waits once triggered by setting a manual-reset event.
+#[derive(Debug)]
+pub struct Shutdown {
+    event: Event,
+}
+
+impl Shutdown {
+    pub fn new() -> io::Result<Shutdown> {
+        Ok(Shutdown {
+            event: Event::new()?,
+        })
+    }
+
+    pub fn trigger(&self) -> io::Result<()> {
+        self.event.set()
+    }
+}
+
+/// Watches pending operations with an I/O completion port. Handle (with its duplicates) is
+/// associated with the port on its first registration and stays associated until it's closed,
+/// so registering it with another `Poller` fails. The port gets a packet for every operation on
+/// associated handles, packets of operations that are not registered are ignored.
+#[derive(Debug)]
+pub struct Poller {
+    port: Handle,
+    /// Unique id, handles of closed ports may be reused.
+    id: usize,
+    /// Key of every registered operation by its `OVERLAPPED`.
+    registered: HashMap<usize, usize>,
+}
+
+static NEXT_POLLER_ID: AtomicUsize = AtomicUsize::new(1);
+
+impl Poller {
+    pub fn new() -> io::Result<Poller> {
+        let port = unsafe { CreateIoCompletionPort(INVALID_HANDLE_VALUE, ptr::null_mut(), 0, 0) };
+        if port.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Poller {
+            port: Handle::new(port),
+            id: NEXT_POLLER_ID.fetch_add(1, Ordering::Relaxed),
+            registered: HashMap::new(),
+        })
+    }
+
+    pub fn register(&mut self, handle: RawHandle, ovl: &Overlapped, key: usize) -> io::Result<()> {
+        match ovl
+            .poller
+            .compare_exchange(0, self.id, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => {
+                let port = unsafe { CreateIoCompletionPort(handle, self.port.value, 0, 0) };
+                if port.is_null() {
+                    ovl.poller.store(0, Ordering::Release);
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Err(id) if id == self.id => (),
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "pipe is associated with another queue",
+                ))
+            }
+        }
+        self.registered
+            .insert(&*ovl.ovl as *const OVERLAPPED as usize, key);
+        Ok(())
+    }
+
+    pub fn deregister(&mut self, key: usize) -> io::Result<()> {
+        self.registered.retain(|_, x| *x != key);
+        Ok(())
+    }
+
+    /// Returns keys of operations that may be finished (empty on timeout).
+    pub fn wait(&mut self, timeout: u32) -> io::Result<Vec<usize>> {
+        let mut entries: Vec<OVERLAPPED_ENTRY> =
+            vec![unsafe { mem::zeroed() }; self.registered.len().clamp(1, 64)];
+        let mut count = 0;
+        let result = unsafe {
+            GetQueuedCompletionStatusEx(
+                self.port.value,
+                entries.as_mut_ptr(),
+                entries.len() as u32,
+                &mut count,
+                timeout,
+                FALSE,
+            )
+        };
+        if result == 0 {
+            return match unsafe { GetLastError() } {
+                WAIT_TIMEOUT => Ok(Vec::new()),
+                code => Err(io::Error::from_raw_os_error(code as i32)),
+            };
+        }
+        Ok(entries[..count as usize]
+            .iter()
+            .filter_map(|entry| self.registered.get(&(entry.lpOverlapped as usize)))
+            .cloned()
+            .collect())
+    }
+}
+
+fn wait_for_any(events: &[usize], timeout: u32) -> io::Result<()> {
+    let events = events.iter().map(|x| *x as HANDLE).collect::<Vec<_>>();
+    let result =
+        unsafe { WaitForMultipleObjects(events.len() as u32, events.as_ptr(), FALSE, timeout) };
+    match result {
+        WAIT_FAILED => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns indexes of signaled events.
+fn signaled(events: &[usize]) -> io::Result<Vec<usize>> {
+    let mut ready = Vec::new();
+    for (i, event) in events.iter().enumerate() {
+        match unsafe { WaitForSingleObject(*event as HANDLE, 0) } {
+            WAIT_OBJECT_0 => ready.push(i),
+            WAIT_TIMEOUT => (),
+            WAIT_FAILED => return Err(io::Error::last_os_error()),
+            _ => unreachable!(),
+        }
+    }
+    Ok(ready)
+}
+
+/// Registration of a waker, see `register_waker`.
+#[derive(Debug)]
+pub struct Registration {
+    wait: HANDLE,
+    waker: *mut Waker,
+}
+
+unsafe impl Sync for Registration {}
+unsafe impl Send for Registration {}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // Waits for the running callback, so it's safe to free the waker.
+        let _ = unsafe { UnregisterWaitEx(self.wait, INVALID_HANDLE_VALUE) };
+        drop(unsafe { Box::from_raw(self.waker) });
+    }
+}
+
+unsafe extern "system" fn wake_callback(waker: PVOID, _timed_out: BOOLEAN) {
+    (*(waker as *const Waker)).wake_by_ref();
+}
+
+/// Wakes `waker` once, when operation is finished. Event of the operation must outlive the
+/// registration.
+pub fn register_waker(
+    _handle: RawHandle,
+    ovl: &Overlapped,
+    waker: Waker,
+) -> io::Result<Registration> {
+    let waker = Box::into_raw(Box::new(waker));
+    let mut wait = ptr::null_mut();
+    let result = unsafe {
+        RegisterWaitForSingleObject(
+            &mut wait,
+            ovl.event.handle.value,
+            Some(wake_callback),
+            waker as PVOID,
+            INFINITE,
+            WT_EXECUTEONLYONCE,
+        )
+    };
+    if result != 0 {
+        Ok(Registration { wait, waker })
+    } else {
+        drop(unsafe { Box::from_raw(waker) });
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Emulated readiness of a pipe registered in a mio registry: the registry is woken once
+/// a started operation is finished.
+#[cfg(feature = "mio")]
+#[derive(Debug)]
+struct MioSource {
+    registration: Option<Registration>,
+    waker: Arc<MioWaker>,
+}
+
+#[cfg(feature = "mio")]
+#[derive(Debug)]
+struct MioWaker(mio::Waker);
+
+#[cfg(feature = "mio")]
+impl Wake for MioWaker {
+    fn wake(self: Arc<Self>) {
+        let _ = self.0.wake();
+    }
+}
+
+/// Registers pipe in a mio registry. Operation that is already pending will wake the registry
+/// as well as all the following ones.
+#[cfg(feature = "mio")]
+pub fn mio_register(
+    _handle: &Handle,
+    ovl: &mut Overlapped,
+    registry: &mio::Registry,
+    token: mio::Token,
+    _interests: mio::Interest,
+) -> io::Result<()> {
+    ovl.mio = Some(MioSource {
+        registration: None,
+        waker: Arc::new(MioWaker(mio::Waker::new(registry, token)?)),
+    });
+    ovl.arm()
+}
+
+#[cfg(feature = "mio")]
+pub fn mio_reregister(
+    handle: &Handle,
+    ovl: &mut Overlapped,
+    registry: &mio::Registry,
+    token: mio::Token,
+    interests: mio::Interest,
+) -> io::Result<()> {
+    mio_register(handle, ovl, registry, token, interests)
+}
+
+#[cfg(feature = "mio")]
+pub fn mio_deregister(
+    _handle: &Handle,
+    ovl: &mut Overlapped,
+    _registry: &mio::Registry,
+) -> io::Result<()> {
+    ovl.mio = None;
+    Ok(())
+}
+
+pub fn is_more_data(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(ERROR_MORE_DATA as i32)
+}
+
+pub fn is_disconnected(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(code) => {
+            code == ERROR_BROKEN_PIPE as i32
+                || code == ERROR_PIPE_NOT_CONNECTED as i32
+                || code == ERROR_NO_DATA as i32
+        }
+        None => false,
+    }
+}
+
+pub fn is_busy(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(ERROR_PIPE_BUSY as i32)
+}
+
+pub fn is_cancelled(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(ERROR_OPERATION_ABORTED as i32)
+}
@@ -0,0 +1,325 @@
+// Copyright (c) 2015-2016 Anatoly Ikorsky
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Pipes for the [tokio](https://tokio.rs) runtime (requires `tokio` feature).
+//!
+//! [`PipeListener`](struct.PipeListener.html) accepts clients as
+//! [`AsyncPipeServer`](struct.AsyncPipeServer.html)s and
+//! [`AsyncPipeClient`](struct.AsyncPipeClient.html) connects to a pipe. Both implement
+//! `AsyncRead` and `AsyncWrite`. On Windows they wrap tokio's named pipes, on Unix they wrap
+//! `UnixStream` connected to the socket of the pipe (see [`PipeName`](../struct.PipeName.html)),
+//! so they interoperate with `PipeServer` and `PipeClient`.
+//!
+//! ```no_run
+//! # use named_pipe::PipeOptions;
+//! # use named_pipe::tokio::PipeListener;
+//! # use tokio::io::AsyncWriteExt;
+//! # async fn serve() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut listener = PipeListener::new(&PipeOptions::new(r"\\.\pipe\tokio"))?;
+//! loop {
+//!     let mut server = listener.accept().await?;
+//!     tokio::spawn(async move { server.write_all(b"hello").await });
+//! }
+//! # }
+//! ```
+//!
+//! Message-mode pipes and pipes with zero-sized buffers are not supported on Unix.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use ::tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[cfg(windows)]
+use ::tokio::net::windows::named_pipe::{
+    ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions,
+};
+#[cfg(unix)]
+use ::tokio::net::{UnixListener, UnixStream};
+
+use crate::{memory, pipe_error, sys, Operation, PipeError, PipeOptions};
+
+#[cfg(unix)]
+type ServerInner = UnixStream;
+#[cfg(windows)]
+type ServerInner = NamedPipeServer;
+
+#[cfg(unix)]
+type ClientInner = UnixStream;
+#[cfg(windows)]
+type ClientInner = NamedPipeClient;
+
+fn check_name(name: &Arc<OsString>, operation: Operation) -> Result<(), PipeError> {
+    if memory::is_memory_name(name) {
+        Err(PipeError::new(
+            sys::memory_unsupported(),
+            Some(name.clone()),
+            operation,
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Accepts clients of a pipe.
+#[derive(Debug)]
+pub struct PipeListener {
+    name: Arc<OsString>,
+    #[cfg(unix)]
+    listener: UnixListener,
+    /// Keeps the socket registered as the listener of the pipe.
+    #[cfg(unix)]
+    handle: sys::Handle,
+    #[cfg(windows)]
+    options: PipeOptions,
+    /// Instance that waits for the next client.
+    #[cfg(windows)]
+    next: NamedPipeServer,
+}
+
+impl PipeListener {
+    /// Creates pipe with `options`. Must be called within tokio runtime.
+    #[cfg(unix)]
+    pub fn new(options: &PipeOptions) -> Result<PipeListener, PipeError> {
+        let name = options.name.clone();
+        check_name(&name, Operation::Create)?;
+        let error = pipe_error(&name, Operation::Create);
+        let (handle, listener) = sys::bind_std_listener(options).map_err(&error)?;
+        let listener = UnixListener::from_std(listener).map_err(&error)?;
+        Ok(PipeListener {
+            name,
+            listener,
+            handle,
+        })
+    }
+
+    /// Creates pipe with `options`. Must be called within tokio runtime.
+    #[cfg(windows)]
+    pub fn new(options: &PipeOptions) -> Result<PipeListener, PipeError> {
+        let name = options.name.clone();
+        check_name(&name, Operation::Create)?;
+        let next = create_instance(options, options.first)
+            .map_err(pipe_error(&name, Operation::Create))?;
+        Ok(PipeListener {
+            name,
+            options: options.clone(),
+            next,
+        })
+    }
+
+    /// Waits for a client.
+    #[cfg(unix)]
+    pub async fn accept(&mut self) -> Result<AsyncPipeServer, PipeError> {
+        use std::os::unix::io::AsRawFd;
+
+        let error = pipe_error(&self.name, Operation::Accept);
+        let (stream, _) = self.listener.accept().await.map_err(&error)?;
+        sys::setup_accepted(&self.handle, stream.as_raw_fd()).map_err(&error)?;
+        Ok(AsyncPipeServer {
+            name: self.name.clone(),
+            inner: stream,
+        })
+    }
+
+    /// Waits for a client.
+    #[cfg(windows)]
+    pub async fn accept(&mut self) -> Result<AsyncPipeServer, PipeError> {
+        let error = pipe_error(&self.name, Operation::Accept);
+        self.next.connect().await.map_err(&error)?;
+        // Keep an instance listening, so clients don't get `ERROR_PIPE_BUSY`.
+        let next = create_instance(&self.options, false)
+            .map_err(pipe_error(&self.name, Operation::Create))?;
+        let inner = std::mem::replace(&mut self.next, next);
+        Ok(AsyncPipeServer {
+            name: self.name.clone(),
+            inner,
+        })
+    }
+
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+}
+
+#[cfg(windows)]
+fn create_instance(options: &PipeOptions, first: bool) -> io::Result<NamedPipeServer> {
+    use crate::{OpenMode, PipeMode};
+    use ::tokio::net::windows::named_pipe::PipeMode as TokioPipeMode;
+
+    ServerOptions::new()
+        .first_pipe_instance(first)
+        .access_inbound(options.open_mode != OpenMode::Write)
+        .access_outbound(options.open_mode != OpenMode::Read)
+        .pipe_mode(match options.pipe_mode {
+            PipeMode::Byte => TokioPipeMode::Byte,
+            PipeMode::Message => TokioPipeMode::Message,
+        })
+        .in_buffer_size(options.in_buffer)
+        .out_buffer_size(options.out_buffer)
+        .create(&*options.name)
+}
+
+/// Server end of a pipe connected to a client. See [`PipeListener`](struct.PipeListener.html).
+#[derive(Debug)]
+pub struct AsyncPipeServer {
+    name: Arc<OsString>,
+    inner: ServerInner,
+}
+
+impl AsyncPipeServer {
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+}
+
+/// Client end of a pipe.
+#[derive(Debug)]
+pub struct AsyncPipeClient {
+    name: Arc<OsString>,
+    inner: ClientInner,
+}
+
+impl AsyncPipeClient {
+    /// Connects to a pipe waiting while all its instances are busy.
+    pub async fn connect<T: AsRef<OsStr>>(name: T) -> Result<AsyncPipeClient, PipeError> {
+        let name = Arc::new(name.as_ref().to_os_string());
+        check_name(&name, Operation::Connect)?;
+        let inner = connect(&name)
+            .await
+            .map_err(pipe_error(&name, Operation::Connect))?;
+        Ok(AsyncPipeClient { name, inner })
+    }
+
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+}
+
+#[cfg(unix)]
+async fn connect(name: &OsStr) -> io::Result<ClientInner> {
+    UnixStream::connect(sys::async_socket_path(name)?).await
+}
+
+#[cfg(windows)]
+async fn connect(name: &OsStr) -> io::Result<ClientInner> {
+    use std::time::Duration;
+
+    loop {
+        match ClientOptions::new().open(name) {
+            Err(ref err) if sys::is_busy(err) => (),
+            result => return result,
+        }
+        ::tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+macro_rules! impl_async_io {
+    ($ty:ident) => {
+        impl AsyncRead for $ty {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.inner).poll_read(cx, buf)
+            }
+        }
+
+        impl AsyncWrite for $ty {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.inner).poll_write(cx, buf)
+            }
+
+            fn poll_write_vectored(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                bufs: &[io::IoSlice<'_>],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+            }
+
+            fn is_write_vectored(&self) -> bool {
+                self.inner.is_write_vectored()
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.inner).poll_flush(cx)
+            }
+
+            fn poll_shutdown(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.inner).poll_shutdown(cx)
+            }
+        }
+    };
+}
+
+impl_async_io!(AsyncPipeServer);
+impl_async_io!(AsyncPipeClient);
+
+#[::tokio::test]
+async fn test_tokio_pipe() {
+    use ::tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let name = r"\\.\pipe\test_tokio_pipe";
+    let mut listener = PipeListener::new(&PipeOptions::new(name)).unwrap();
+    let client = ::tokio::spawn(async move {
+        let mut client = AsyncPipeClient::connect(name).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    });
+
+    let mut server = listener.accept().await.unwrap();
+    let mut buf = [0; 4];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    server.write_all(b"pong").await.unwrap();
+    client.await.unwrap();
+
+    // Listener keeps accepting.
+    let client = ::tokio::spawn(async move { AsyncPipeClient::connect(name).await.unwrap() });
+    let mut server = listener.accept().await.unwrap();
+    drop(client.await.unwrap());
+    assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+}
+
+#[::tokio::test]
+async fn test_tokio_sync_interop() {
+    use crate::PipeClient;
+    use ::tokio::io::AsyncReadExt;
+    use std::io::Write;
+
+    let name = r"\\.\pipe\test_tokio_sync_interop";
+    let mut listener = PipeListener::new(&PipeOptions::new(name)).unwrap();
+    let client = std::thread::spawn(move || {
+        let mut client = PipeClient::connect(name).unwrap();
+        client.write_all(b"ping").unwrap();
+    });
+    let mut server = listener.accept().await.unwrap();
+    let mut buf = Vec::new();
+    server.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"ping");
+    client.join().unwrap();
+}
+
+#[::tokio::test]
+async fn test_tokio_memory_pipe() {
+    let err = PipeListener::new(&PipeOptions::new("mem://test_tokio_memory_pipe")).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+}